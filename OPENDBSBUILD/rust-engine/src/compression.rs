@@ -1,4 +1,3 @@
-#![allow(dead_code)]
 use crate::error::{OpenDBSError, Result};
 
 /// Compress data using Snappy
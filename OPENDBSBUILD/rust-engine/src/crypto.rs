@@ -0,0 +1,513 @@
+//! Authenticated encryption of stored data with AES-256-GCM.
+//!
+//! A sealed payload is `[0xE1][key id: u32][nonce: 12 bytes][ciphertext + tag]`.
+//! The key id is derived from the key itself, so data written before a key
+//! rotation stays readable as long as the old key is still configured.
+//!
+//! Separately, racks can declare document fields as encrypted with a key
+//! the engine never stores. Such a field holds `"$enc:<mode>:<hex>"` on disk,
+//! in the WAL and in the rack's index, and is only decrypted for callers
+//! that present the key.
+
+use crate::durability;
+use crate::error::{OpenDBSError, Result};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// First byte of a sealed payload. JSON never starts with it.
+pub const SEALED_MAGIC: u8 = 0xE1;
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const SEALED_HEADER_LEN: usize = 1 + KEY_ID_LEN + NONCE_LEN;
+
+/// Prefix of a document field value encrypted with a field key
+pub const ENCRYPTED_FIELD_PREFIX: &str = "$enc:";
+
+/// JSON options enabling encryption at rest, under `encryption` in the
+/// engine options
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EncryptionOptions {
+    /// Active key as 64 hex characters (32 bytes)
+    pub key: Option<String>,
+    /// Keys replaced by a rotation that data may still be encrypted with
+    pub previous_keys: Vec<String>,
+    /// File holding hex keys one per line, active key first. Created from
+    /// `key` if missing, and updated when keys are rotated. Once it exists,
+    /// `key` and `previous_keys` must be keys it holds.
+    pub key_file: Option<String>,
+}
+
+struct Key {
+    id: u32,
+    hex: String,
+    cipher: Aes256Gcm,
+}
+
+/// The keys data can be encrypted with; the first one encrypts new data
+pub struct Keyring {
+    keys: RwLock<Vec<Key>>,
+    key_file: Option<PathBuf>,
+    /// Background re-encryptions still running
+    rotations: AtomicUsize,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<String> = self.keys.read().iter().map(|key| format!("{:08x}", key.id)).collect();
+        f.debug_struct("Keyring").field("keys", &ids).finish()
+    }
+}
+
+impl Keyring {
+    /// Build the keyring described by the options; `None` if encryption is
+    /// off. An existing key file wins over keys in the options, which must
+    /// all be in the file; a new key is brought in with `rotate`. A
+    /// `read_only` engine never writes the key file.
+    pub fn from_options(options: &EncryptionOptions, read_only: bool) -> Result<Option<Self>> {
+        let key_file = options.key_file.as_ref().map(PathBuf::from);
+        let mut hex_keys: Vec<String> = options.key.iter().chain(&options.previous_keys).cloned().collect();
+
+        if let Some(path) = &key_file {
+            if path.exists() {
+                let file_keys: Vec<String> = fs::read_to_string(path)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                let file_ids = file_keys
+                    .iter()
+                    .map(|hex| parse_key(hex).map(|key| key.id))
+                    .collect::<Result<Vec<_>>>()?;
+                for hex in &hex_keys {
+                    let key = parse_key(hex)?;
+                    if !file_ids.contains(&key.id) {
+                        return Err(OpenDBSError::Encryption(format!(
+                            "Key {:08x} is not in key file {}; add new keys with a key rotation",
+                            key.id,
+                            path.display()
+                        )));
+                    }
+                }
+                hex_keys = file_keys;
+            } else if hex_keys.is_empty() {
+                return Err(OpenDBSError::Encryption(format!(
+                    "Key file {} does not exist",
+                    path.display()
+                )));
+            }
+        }
+        if hex_keys.is_empty() {
+            return Ok(None);
+        }
+
+        let keys = hex_keys.iter().map(|hex| parse_key(hex)).collect::<Result<Vec<_>>>()?;
+        let keyring = Self {
+            keys: RwLock::new(keys),
+            key_file,
+            rotations: AtomicUsize::new(0),
+        };
+        if !read_only && keyring.key_file.as_ref().is_some_and(|path| !path.exists()) {
+            keyring.save()?;
+        }
+        Ok(Some(keyring))
+    }
+
+    /// Encrypt `plaintext` with the active key, binding it to `aad`
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let keys = self.keys.read();
+        let key = &keys[0];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| OpenDBSError::Encryption("Failed to encrypt data".into()))?;
+
+        let mut sealed = Vec::with_capacity(SEALED_HEADER_LEN + ciphertext.len());
+        sealed.push(SEALED_MAGIC);
+        sealed.extend_from_slice(&key.id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt and authenticate a payload produced by `seal` with the same `aad`
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEALED_HEADER_LEN + TAG_LEN || sealed[0] != SEALED_MAGIC {
+            return Err(OpenDBSError::Encryption("Encrypted data is truncated".into()));
+        }
+        let id = u32::from_le_bytes(sealed[1..1 + KEY_ID_LEN].try_into().unwrap());
+        let nonce = Nonce::from_slice(&sealed[1 + KEY_ID_LEN..SEALED_HEADER_LEN]);
+
+        let keys = self.keys.read();
+        let key = keys.iter().find(|key| key.id == id).ok_or_else(|| {
+            OpenDBSError::Encryption(format!(
+                "Data was encrypted with key {:08x}, which is not configured",
+                id
+            ))
+        })?;
+        key.cipher
+            .decrypt(nonce, Payload { msg: &sealed[SEALED_HEADER_LEN..], aad })
+            .map_err(|_| OpenDBSError::Encryption("Authentication failed; the data was tampered with".into()))
+    }
+
+    /// Make `hex` the active key, keeping the others for reading
+    pub fn rotate(&self, hex: &str) -> Result<()> {
+        let key = parse_key(hex)?;
+        {
+            let mut keys = self.keys.write();
+            keys.retain(|existing| existing.id != key.id);
+            keys.insert(0, key);
+        }
+        self.save()
+    }
+
+    /// Id of the key new data is encrypted with
+    pub fn active_key_id(&self) -> String {
+        format!("{:08x}", self.keys.read()[0].id)
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.read().len()
+    }
+
+    pub fn begin_rotation(&self) {
+        self.rotations.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn end_rotation(&self) {
+        self.rotations.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Number of background re-encryptions still running
+    pub fn rotations_running(&self) -> usize {
+        self.rotations.load(Ordering::SeqCst)
+    }
+
+    /// Write the keys to the key file, if one is configured
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.key_file else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for key in self.keys.read().iter() {
+            contents.push_str(&key.hex);
+            contents.push('\n');
+        }
+        durability::write_atomic(path, contents.as_bytes())?;
+        restrict_permissions(path)
+    }
+}
+
+/// How an encrypted document field is sealed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldEncryption {
+    /// Equal values give equal ciphertext, so the field can still be
+    /// looked up by equality through the index
+    Deterministic,
+    /// Every write uses a fresh nonce; the field is left out of the index
+    Randomized,
+}
+
+impl FieldEncryption {
+    fn tag(self) -> char {
+        match self {
+            FieldEncryption::Deterministic => 'd',
+            FieldEncryption::Randomized => 'r',
+        }
+    }
+}
+
+/// Encrypts and decrypts declared document fields with a caller's key
+pub struct FieldCipher {
+    cipher: Aes256Gcm,
+    /// Derives the nonce of deterministic values from their plaintext
+    nonce_key: [u8; 32],
+    key_check: String,
+}
+
+impl FieldCipher {
+    /// Cipher for a field key given as 64 hex characters
+    pub fn new(hex: &str) -> Result<Self> {
+        let key = hex::decode(hex.trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| OpenDBSError::Encryption("Field keys must be 64 hex characters (32 bytes)".into()))?;
+
+        Ok(Self {
+            cipher: Aes256Gcm::new_from_slice(&hmac(&key, &[b"opendbs-field-encryption"]))
+                .map_err(|_| OpenDBSError::Encryption("Invalid key length".into()))?,
+            nonce_key: hmac(&key, &[b"opendbs-field-nonce"]),
+            key_check: hex::encode(&hmac(&key, &[b"opendbs-field-key-check"])[..8]),
+        })
+    }
+
+    /// Fingerprint kept in rack metadata so that a wrong key is refused
+    /// instead of producing ciphertext nobody can read
+    pub fn key_check(&self) -> &str {
+        &self.key_check
+    }
+
+    /// Encrypt the declared top-level fields of `data`. Every value is
+    /// encrypted, even one that looks encrypted already, so a caller
+    /// cannot store plaintext by giving it the ciphertext prefix.
+    pub fn seal_fields(&self, data: &mut Value, fields: &BTreeMap<String, FieldEncryption>) -> Result<()> {
+        let Value::Object(map) = data else {
+            return Ok(());
+        };
+        for (field, mode) in fields {
+            if let Some(value) = map.get_mut(field) {
+                *value = self.seal_value(field, value, *mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypt the declared top-level fields of `data`. Other fields are
+    /// left alone, even if their values look encrypted.
+    pub fn open_fields(&self, data: &mut Value, fields: &BTreeMap<String, FieldEncryption>) -> Result<()> {
+        let Value::Object(map) = data else {
+            return Ok(());
+        };
+        for field in fields.keys() {
+            if let Some(value) = map.get_mut(field) {
+                if is_encrypted_value(value) {
+                    *value = self.open_value(field, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The stored form of `value` in `field`
+    pub fn seal_value(&self, field: &str, value: &Value, mode: FieldEncryption) -> Result<Value> {
+        let plaintext = serde_json::to_vec(value)?;
+        let nonce = match mode {
+            FieldEncryption::Deterministic => {
+                let digest = hmac(&self.nonce_key, &[field.as_bytes(), &[0], &plaintext]);
+                *Nonce::from_slice(&digest[..NONCE_LEN])
+            }
+            FieldEncryption::Randomized => Aes256Gcm::generate_nonce(&mut OsRng),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: field.as_bytes() })
+            .map_err(|_| OpenDBSError::Encryption("Failed to encrypt data".into()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(Value::String(format!(
+            "{}{}:{}",
+            ENCRYPTED_FIELD_PREFIX,
+            mode.tag(),
+            hex::encode(sealed)
+        )))
+    }
+
+    fn open_value(&self, field: &str, value: &Value) -> Result<Value> {
+        let invalid = || OpenDBSError::Encryption(format!("Field {} holds invalid encrypted data", field));
+        let encoded = value
+            .as_str()
+            .and_then(|s| s.strip_prefix(ENCRYPTED_FIELD_PREFIX))
+            .and_then(|s| s.get(2..))
+            .ok_or_else(invalid)?;
+        let sealed = hex::decode(encoded).map_err(|_| invalid())?;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(invalid());
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&sealed[..NONCE_LEN]),
+                Payload { msg: &sealed[NONCE_LEN..], aad: field.as_bytes() },
+            )
+            .map_err(|_| {
+                OpenDBSError::Encryption(format!("Field {} failed authentication; wrong key or tampered data", field))
+            })?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+/// Whether a field value was produced by `FieldCipher::seal_value`
+pub fn is_encrypted_value(value: &Value) -> bool {
+    value.as_str().is_some_and(|s| s.starts_with(ENCRYPTED_FIELD_PREFIX))
+}
+
+/// Name of a declared field that holds plaintext in `data`, if any
+pub fn plaintext_field<'a>(data: &Value, fields: &'a BTreeMap<String, FieldEncryption>) -> Option<&'a str> {
+    fields
+        .keys()
+        .find(|field| data.get(field.as_str()).is_some_and(|value| !is_encrypted_value(value)))
+        .map(String::as_str)
+}
+
+/// HMAC-SHA256 of the concatenated `parts`
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Whether a payload was produced by `Keyring::seal`
+pub fn is_sealed(payload: &[u8]) -> bool {
+    payload.first() == Some(&SEALED_MAGIC)
+}
+
+fn parse_key(hex: &str) -> Result<Key> {
+    let hex = hex.trim().to_ascii_lowercase();
+    let bytes = hex::decode(&hex)
+        .ok()
+        .filter(|bytes| bytes.len() == 32)
+        .ok_or_else(|| OpenDBSError::Encryption("Keys must be 64 hex characters (32 bytes)".into()))?;
+
+    let digest = Sha256::new_with_prefix(b"opendbs-key-id").chain_update(&bytes).finalize();
+    Ok(Key {
+        id: u32::from_le_bytes(digest[..KEY_ID_LEN].try_into().unwrap()),
+        cipher: Aes256Gcm::new_from_slice(&bytes)
+            .map_err(|_| OpenDBSError::Encryption("Invalid key length".into()))?,
+        hex,
+    })
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn keyring(key: &str) -> Keyring {
+        let options = EncryptionOptions {
+            key: Some(key.to_string()),
+            ..EncryptionOptions::default()
+        };
+        Keyring::from_options(&options, false).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_seal_detects_tampering_and_wrong_keys() {
+        let keyring = keyring(KEY_A);
+        let sealed = keyring.seal(b"secret", b"doc-1").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(keyring.open(&sealed, b"doc-1").unwrap(), b"secret");
+
+        // Bound to its context
+        assert!(matches!(keyring.open(&sealed, b"doc-2"), Err(OpenDBSError::Encryption(_))));
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(keyring.open(&tampered, b"doc-1"), Err(OpenDBSError::Encryption(_))));
+
+        let other = self::keyring(KEY_B);
+        assert!(matches!(other.open(&sealed, b"doc-1"), Err(OpenDBSError::Encryption(_))));
+
+        // After a rotation old data stays readable and new data uses the new key
+        other.rotate(KEY_A).unwrap();
+        assert_eq!(other.open(&sealed, b"doc-1").unwrap(), b"secret");
+        assert_eq!(other.active_key_id(), keyring.active_key_id());
+        assert_eq!(other.key_count(), 2);
+    }
+
+    #[test]
+    fn test_field_encryption_modes() {
+        let cipher = FieldCipher::new(KEY_A).unwrap();
+        let fields = BTreeMap::from([
+            ("ssn".to_string(), FieldEncryption::Deterministic),
+            ("password_hash".to_string(), FieldEncryption::Randomized),
+        ]);
+        let plain = serde_json::json!({ "name": "Alice", "ssn": "123-45-6789", "password_hash": "x" });
+
+        let mut first = plain.clone();
+        cipher.seal_fields(&mut first, &fields).unwrap();
+        let mut second = plain.clone();
+        cipher.seal_fields(&mut second, &fields).unwrap();
+        assert_eq!(first["name"], "Alice");
+        assert_eq!(first["ssn"], second["ssn"]);
+        assert_ne!(first["password_hash"], second["password_hash"]);
+        assert!(plaintext_field(&first, &fields).is_none());
+        assert_eq!(plaintext_field(&plain, &fields), Some("password_hash"));
+
+        let mut opened = first.clone();
+        cipher.open_fields(&mut opened, &fields).unwrap();
+        assert_eq!(opened, plain);
+
+        // A value that looks encrypted is encrypted all the same
+        let mut forged = serde_json::json!({ "ssn": "$enc:d:00" });
+        cipher.seal_fields(&mut forged, &fields).unwrap();
+        cipher.open_fields(&mut forged, &fields).unwrap();
+        assert_eq!(forged["ssn"], "$enc:d:00");
+
+        // Undeclared fields are never decrypted
+        let mut note = serde_json::json!({ "note": "$enc:hello", "copy": first["ssn"].clone() });
+        cipher.open_fields(&mut note, &fields).unwrap();
+        assert_eq!(note["note"], "$enc:hello");
+
+        // Ciphertext is bound to its field and key
+        let mut moved = serde_json::json!({ "password_hash": first["ssn"].clone() });
+        assert!(cipher.open_fields(&mut moved, &fields).is_err());
+        let other = FieldCipher::new(KEY_B).unwrap();
+        assert_ne!(other.key_check(), cipher.key_check());
+        assert!(matches!(other.open_fields(&mut first, &fields), Err(OpenDBSError::Encryption(_))));
+    }
+
+    #[test]
+    fn test_key_file_is_created_and_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        let options = EncryptionOptions {
+            key: Some(KEY_A.to_string()),
+            key_file: Some(path.to_str().unwrap().to_string()),
+            ..EncryptionOptions::default()
+        };
+        let keyring = Keyring::from_options(&options, false).unwrap().unwrap();
+        keyring.rotate(KEY_B).unwrap();
+
+        let reopened = Keyring::from_options(&options, false).unwrap().unwrap();
+        assert_eq!(reopened.key_count(), 2);
+        assert_eq!(reopened.active_key_id(), keyring.active_key_id());
+
+        // A key the file does not have is refused rather than ignored
+        let options = EncryptionOptions {
+            key: Some("cc".repeat(32)),
+            ..options
+        };
+        assert!(matches!(Keyring::from_options(&options, false), Err(OpenDBSError::Encryption(_))));
+
+        // Read-only engines do not create the key file
+        let missing = EncryptionOptions {
+            key_file: Some(dir.path().join("other").to_str().unwrap().to_string()),
+            ..options
+        };
+        assert!(Keyring::from_options(&missing, true).unwrap().is_some());
+        assert!(!dir.path().join("other").exists());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{OpenDBSError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// When acknowledged writes are forced to stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// fsync the write-ahead log before every write is acknowledged
+    #[default]
+    Always,
+    /// fsync from a background thread every N milliseconds; a crash can lose
+    /// writes acknowledged within the last interval
+    BatchedMs(u64),
+    /// Never fsync and leave flushing to the operating system
+    Never,
+}
+
+impl Durability {
+    /// Whether checkpoints and whole-file writes should fsync at all
+    pub fn syncs(&self) -> bool {
+        *self != Durability::Never
+    }
+}
+
+/// A file that only becomes visible under its final name once complete.
+///
+/// Data is written to `<path>.tmp`, fsynced, renamed over `path` and the
+/// parent directory is fsynced, so readers see either the old or the new
+/// contents but never a torn file.
+pub struct AtomicFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        let tmp_path = tmp_path(path);
+        let file = File::create(&tmp_path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Flush, fsync and move the file into place
+    pub fn commit(self) -> Result<()> {
+        let file = self
+            .writer
+            .into_inner()
+            .map_err(|e| OpenDBSError::IoError(e.into_error()))?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&self.tmp_path, &self.path)?;
+        if let Some(parent) = self.path.parent() {
+            sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Atomically replace the contents of `path`
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(contents)?;
+    file.commit()
+}
+
+/// Temporary name used while `path` is being written
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Flush directory entries (file creation, rename, removal) to disk
+pub fn sync_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+
+        write_atomic(&path, b"{\"a\":1}").unwrap();
+        write_atomic(&path, b"{\"a\":2}").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"{\"a\":2}");
+        assert!(!tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn test_durability_from_json() {
+        let batched: Durability = serde_json::from_str(r#"{"batched_ms":50}"#).unwrap();
+        assert_eq!(batched, Durability::BatchedMs(50));
+
+        let never: Durability = serde_json::from_str(r#""never""#).unwrap();
+        assert!(!never.syncs());
+    }
+}
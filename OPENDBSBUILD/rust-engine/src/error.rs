@@ -15,15 +15,42 @@ pub enum OpenDBSError {
     #[error("Invalid query: {0}")]
     InvalidQuery(String),
 
+    #[error("Invalid document: {0}")]
+    InvalidDocument(String),
+
+    /// A document with the requested `_id` already exists
+    #[error("Document already exists: {0}")]
+    DuplicateId(String),
+
+    /// A unique index already holds the value, for the document `existing_id`
+    #[error("Unique index on {field} already has {value} (document {existing_id})")]
+    UniqueViolation {
+        field: String,
+        value: String,
+        existing_id: String,
+    },
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
 
+    #[error("Corrupt data: {0}")]
+    Corruption(String),
+
     #[error("Compression error: {0}")]
     CompressionError(String),
 
+    /// Encrypted data could not be authenticated: a wrong or missing key,
+    /// or tampering
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    /// Another engine, possibly in another process, has the data directory open
+    #[error("Data directory is locked: {0}")]
+    Locked(String),
+
     #[error("Permission denied: {0}")]
     PermissionDenied(String),
 
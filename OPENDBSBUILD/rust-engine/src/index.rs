@@ -1,64 +1,241 @@
+use crate::crypto::{self, Keyring};
+use crate::durability;
+use crate::error::{OpenDBSError, Result};
+use crate::meta::IndexKind;
+use crate::query::RangeOp;
 use dashmap::DashMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+
+/// File holding a rack's saved index entries inside its directory
+pub const INDEX_FILE: &str = "rack.index";
+
+/// Additional data authenticated with an encrypted index file
+const INDEX_AAD: &[u8] = b"opendbs-rack-index";
+
+/// Leading byte of an ordered key, which sorts values of different types apart
+const TAG_BOOL: u8 = 0x10;
+const TAG_NUMBER: u8 = 0x20;
+const TAG_DATE: u8 = 0x30;
+const TAG_STRING: u8 = 0x40;
+
+/// Compound key component of a field that is missing or not a scalar
+const TAG_NONE: u8 = 0x00;
+
+/// Byte that sorts after the first byte of every key component
+const AFTER_COMPONENT: u8 = 0xFF;
+
+/// Ordered index entries: encoded value -> ids
+type OrderedEntries = BTreeMap<Vec<u8>, HashSet<String>>;
+
+/// Lower and upper bound of a scan over ordered keys
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
 
 /// Inverted Index structure
 /// Maps: Field Name -> Value (String representation) -> Set of Document IDs
 #[derive(Debug)]
 pub struct Index {
     indices: DashMap<String, DashMap<String, HashSet<String>>>,
+    /// Entries of ordered indexes, keyed by `ordered_key`
+    ordered: DashMap<String, OrderedEntries>,
+    /// Declared fields; no other field is indexed
+    fields: RwLock<HashMap<String, IndexKind>>,
+    /// Compound indexes: name -> fields in key order. Their entries are
+    /// kept in `ordered` under the name.
+    compound: RwLock<HashMap<String, Vec<String>>>,
+}
+
+/// Index entries as saved to `rack.index`, with the segment files they
+/// were built from
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedIndex {
+    /// Number and length of every segment file
+    segments: Vec<(u64, u64)>,
+    fields: Vec<(String, IndexKind)>,
+    compound: Vec<(String, Vec<String>)>,
+    entries: HashMap<String, HashMap<String, HashSet<String>>>,
+    ordered: HashMap<String, OrderedEntries>,
 }
 
 #[allow(dead_code)]
 impl Index {
     pub fn new() -> Self {
+        Self::with_fields(std::iter::empty())
+    }
+
+    /// An index over `fields` only
+    pub fn with_fields<I>(fields: I) -> Self
+    where
+        I: IntoIterator<Item = (String, IndexKind)>,
+    {
         Self {
             indices: DashMap::new(),
+            ordered: DashMap::new(),
+            fields: RwLock::new(fields.into_iter().collect()),
+            compound: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// This index, also holding the compound indexes `compound`
+    pub fn with_compound<I>(self, compound: I) -> Self
+    where
+        I: IntoIterator<Item = (String, Vec<String>)>,
+    {
+        self.compound.write().extend(compound);
+        self
+    }
+
+    /// Declared fields with their kind, sorted by field
+    pub fn fields(&self) -> Vec<(String, IndexKind)> {
+        let mut fields: Vec<_> = self.fields.read().iter().map(|(field, kind)| (field.clone(), *kind)).collect();
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        fields
+    }
+
+    /// Compound indexes with their fields, sorted by name
+    pub fn compound(&self) -> Vec<(String, Vec<String>)> {
+        let mut compound: Vec<_> = self.compound.read().iter().map(|(name, fields)| (name.clone(), fields.clone())).collect();
+        compound.sort();
+        compound
+    }
+
+    /// Kind of the index on `field`, if there is one
+    pub fn kind(&self, field: &str) -> Option<IndexKind> {
+        self.fields.read().get(field).copied()
+    }
+
+    /// Start indexing `field`; documents already stored must be added
+    /// with `index_field`. Returns false if it was indexed already.
+    pub fn add_field(&self, field: &str, kind: IndexKind) -> bool {
+        let mut fields = self.fields.write();
+        if fields.contains_key(field) {
+            return false;
+        }
+        fields.insert(field.to_string(), kind);
+        true
+    }
+
+    /// Start maintaining the compound index `name` over `fields`, like
+    /// `add_field`. Returns false if the name is taken already.
+    pub fn add_compound(&self, name: &str, fields: Vec<String>) -> bool {
+        let mut compound = self.compound.write();
+        if compound.contains_key(name) || self.kind(name).is_some() {
+            return false;
         }
+        compound.insert(name.to_string(), fields);
+        true
+    }
+
+    /// Stop indexing `field`, or the compound index of that name, and drop
+    /// its entries
+    pub fn remove_field(&self, field: &str) -> bool {
+        self.indices.remove(field);
+        self.ordered.remove(field);
+        let compound = self.compound.write().remove(field).is_some();
+        self.fields.write().remove(field).is_some() || compound
     }
 
     /// Index a document
     pub fn index_document(&self, doc_id: &str, data: &Value) {
-        if let Value::Object(map) = data {
-            for (key, value) in map {
-                // We only index scalar values for now (String, Number, Bool)
-                if value.is_string() || value.is_number() || value.is_boolean() {
-                    let value_str = match value {
-                        Value::String(s) => s.clone(),
-                        _ => value.to_string(),
-                    };
+        for (key, kind) in self.fields.read().iter() {
+            self.insert(key, *kind, doc_id, data);
+        }
+        for (name, fields) in self.compound.read().iter() {
+            self.insert_compound(name, fields, doc_id, data);
+        }
+    }
 
+    /// Index one field, or one compound index, of a document
+    pub fn index_field(&self, key: &str, doc_id: &str, data: &Value) {
+        if let Some(kind) = self.kind(key) {
+            self.insert(key, kind, doc_id, data);
+        } else if let Some(fields) = self.compound.read().get(key) {
+            self.insert_compound(key, fields, doc_id, data);
+        }
+    }
+
+    fn insert_compound(&self, name: &str, fields: &[String], doc_id: &str, data: &Value) {
+        self.ordered
+            .entry(name.to_string())
+            .or_default()
+            .entry(compound_key(fields, data))
+            .or_default()
+            .insert(doc_id.to_string());
+    }
+
+    fn insert(&self, key: &str, kind: IndexKind, doc_id: &str, data: &Value) {
+        let Some(value) = data.get(key) else {
+            return;
+        };
+        match kind {
+            // We only index scalar values for now (String, Number, Bool)
+            IndexKind::Hash => {
+                if let Some(value_str) = index_key(value) {
                     let field_index = self
                         .indices
-                        .entry(key.clone())
-                        .or_insert_with(DashMap::new);
-                    
+                        .entry(key.to_string())
+                        .or_default();
+
                     let mut doc_set = field_index
                         .entry(value_str)
-                        .or_insert_with(HashSet::new);
-                    
+                        .or_default();
+
                     doc_set.insert(doc_id.to_string());
                 }
             }
+            IndexKind::Ordered => {
+                if let Some(value_key) = ordered_key(value) {
+                    self.ordered
+                        .entry(key.to_string())
+                        .or_default()
+                        .entry(value_key)
+                        .or_default()
+                        .insert(doc_id.to_string());
+                }
+            }
         }
     }
 
     /// Remove a document from index
     pub fn remove_document(&self, doc_id: &str, data: &Value) {
-        if let Value::Object(map) = data {
-            for (key, value) in map {
-                if value.is_string() || value.is_number() || value.is_boolean() {
-                    let value_str = match value {
-                        Value::String(s) => s.clone(),
-                        _ => value.to_string(),
-                    };
-
-                    if let Some(field_index) = self.indices.get(key) {
-                        if let Some(mut doc_set) = field_index.get_mut(&value_str) {
-                            doc_set.remove(doc_id);
+        for (key, kind) in self.fields.read().iter() {
+            let Some(value) = data.get(key) else {
+                continue;
+            };
+            match kind {
+                IndexKind::Hash => {
+                    if let Some(value_str) = index_key(value) {
+                        if let Some(field_index) = self.indices.get(key) {
+                            if let Some(mut doc_set) = field_index.get_mut(&value_str) {
+                                doc_set.remove(doc_id);
+                            }
                         }
                     }
                 }
+                IndexKind::Ordered => {
+                    if let Some(value_key) = ordered_key(value) {
+                        self.remove_ordered(key, &value_key, doc_id);
+                    }
+                }
+            }
+        }
+        for (name, fields) in self.compound.read().iter() {
+            self.remove_ordered(name, &compound_key(fields, data), doc_id);
+        }
+    }
+
+    fn remove_ordered(&self, key: &str, value_key: &[u8], doc_id: &str) {
+        if let Some(mut entries) = self.ordered.get_mut(key) {
+            if let Some(doc_set) = entries.get_mut(value_key) {
+                doc_set.remove(doc_id);
+                if doc_set.is_empty() {
+                    entries.remove(value_key);
+                }
             }
         }
     }
@@ -73,8 +250,832 @@ impl Index {
         None
     }
 
+    /// Ids of every document whose `field` may equal `value`, or None if
+    /// the index cannot tell. Strings and other scalars with the same text
+    /// share a hash entry, so candidates still have to be matched.
+    pub fn candidates(&self, field: &str, value: &Value) -> Option<HashSet<String>> {
+        match self.kind(field)? {
+            IndexKind::Hash => {
+                let value_str = index_key(value)?;
+                let mut ids = self.search(field, &value_str).unwrap_or_default();
+                for spelling in number_spellings(value) {
+                    ids.extend(self.search(field, &spelling).unwrap_or_default());
+                }
+                Some(ids)
+            }
+            IndexKind::Ordered => {
+                let value_key = ordered_key(value)?;
+                let entries = self.ordered.get(field);
+                Some(entries.and_then(|entries| entries.get(&value_key).cloned()).unwrap_or_default())
+            }
+        }
+    }
+
+    /// Ids of every document whose `field` satisfies all of the range
+    /// operators, or None if `field` has no ordered index
+    pub fn range(&self, field: &str, ops: &[(RangeOp, &Value)]) -> Option<HashSet<String>> {
+        if self.kind(field)? != IndexKind::Ordered {
+            return None;
+        }
+        let Some(entries) = self.ordered.get(field) else {
+            return Some(HashSet::new());
+        };
+        Some(
+            range_bounds(ops)
+                .into_iter()
+                .flat_map(|bounds| entries.range(bounds).flat_map(|(_, ids)| ids.iter().cloned()))
+                .collect(),
+        )
+    }
+
+    /// Ids of every document that may satisfy the equality and range
+    /// predicates, one set per compound index whose leading fields all
+    /// have an equality predicate. A range predicate on the field after
+    /// them narrows the set further.
+    pub fn compound_candidates(
+        &self,
+        predicates: &[(&str, &Value)],
+        ranges: &[(&str, Vec<(RangeOp, &Value)>)],
+    ) -> Vec<HashSet<String>> {
+        let mut sets = Vec::new();
+        for (name, fields) in self.compound.read().iter() {
+            let mut prefix = Vec::new();
+            let mut covered = 0;
+            for field in fields {
+                let Some(value) = predicates.iter().find(|(f, _)| f == field).map(|(_, value)| *value) else {
+                    break;
+                };
+                let Some(value_key) = ordered_key(value) else {
+                    break;
+                };
+                prefix.extend_from_slice(&value_key);
+                covered += 1;
+            }
+            if covered == 0 {
+                continue;
+            }
+
+            let ops: Vec<_> = fields
+                .get(covered)
+                .map(|next| {
+                    ranges
+                        .iter()
+                        .filter(|(field, _)| field == next)
+                        .flat_map(|(_, ops)| ops.iter().copied())
+                        .collect()
+                })
+                .unwrap_or_default();
+            let ids = self
+                .ordered
+                .get(name)
+                .map(|entries| {
+                    compound_bounds(&prefix, &ops)
+                        .into_iter()
+                        .flat_map(|bounds| entries.range(bounds).flat_map(|(_, ids)| ids.iter().cloned()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            sets.push(ids);
+        }
+        sets
+    }
+
+    /// Ids of the documents with an indexed value of `field` that satisfies
+    /// `ops`, in value order and by id among equal values. None if `field`
+    /// has no ordered index.
+    pub fn scan(&self, field: &str, ops: &[(RangeOp, &Value)], descending: bool) -> Option<Vec<String>> {
+        if self.kind(field)? != IndexKind::Ordered {
+            return None;
+        }
+        let mut ranges = match ops {
+            [] => vec![(Bound::Unbounded, Bound::Unbounded)],
+            ops => range_bounds(ops),
+        };
+
+        let Some(entries) = self.ordered.get(field) else {
+            return Some(Vec::new());
+        };
+        let sorted = |(_, ids): (&Vec<u8>, &HashSet<String>)| {
+            let mut ids: Vec<String> = ids.iter().cloned().collect();
+            ids.sort();
+            ids
+        };
+        if descending {
+            ranges.reverse();
+        }
+        let mut ids = Vec::new();
+        for bounds in ranges {
+            let range = entries.range(bounds);
+            match descending {
+                false => ids.extend(range.flat_map(sorted)),
+                true => ids.extend(range.rev().flat_map(sorted)),
+            }
+        }
+        Some(ids)
+    }
+
+    /// Snapshot of all non-empty entries, for consistency checks. Keys of
+    /// ordered indexes are given in hex.
+    pub fn entries(&self) -> HashMap<String, HashMap<String, HashSet<String>>> {
+        let mut entries = self.hash_entries();
+        for field in self.ordered.iter() {
+            let values: HashMap<_, _> = field
+                .value()
+                .iter()
+                .filter(|(_, ids)| !ids.is_empty())
+                .map(|(key, ids)| (hex::encode(key), ids.clone()))
+                .collect();
+            if !values.is_empty() {
+                entries.insert(field.key().clone(), values);
+            }
+        }
+        entries
+    }
+
+    fn hash_entries(&self) -> HashMap<String, HashMap<String, HashSet<String>>> {
+        self.indices
+            .iter()
+            .map(|field| {
+                let values = field
+                    .value()
+                    .iter()
+                    .filter(|docs| !docs.value().is_empty())
+                    .map(|docs| (docs.key().clone(), docs.value().clone()))
+                    .collect::<HashMap<_, _>>();
+                (field.key().clone(), values)
+            })
+            .filter(|(_, values)| !values.is_empty())
+            .collect()
+    }
+
+    /// Encode the entries for `save`, recording the `segments` they
+    /// reflect. Writers must be held off while this runs.
+    pub fn encode(&self, segments: Vec<(u64, u64)>) -> Result<Vec<u8>> {
+        let saved = SavedIndex {
+            segments,
+            fields: self.fields(),
+            compound: self.compound(),
+            entries: self.hash_entries(),
+            ordered: self
+                .ordered
+                .iter()
+                .map(|field| (field.key().clone(), field.value().clone()))
+                .collect(),
+        };
+        bincode::serialize(&saved).map_err(|e| OpenDBSError::Internal(e.to_string()))
+    }
+
+    /// Atomically write entries produced by `encode` to `rack.index`
+    pub fn save(rack_path: &Path, encoded: &[u8], cipher: Option<&Keyring>) -> Result<()> {
+        let payload = match cipher {
+            Some(cipher) => cipher.seal(encoded, INDEX_AAD)?,
+            None => encoded.to_vec(),
+        };
+        let mut contents = crc32fast::hash(&payload).to_le_bytes().to_vec();
+        contents.extend_from_slice(&payload);
+        durability::write_atomic(&rack_path.join(INDEX_FILE), &contents)
+    }
+
+    /// Fill the index from `rack.index` if it was saved for exactly
+    /// `segments` and the declared fields. Returns false if it cannot be
+    /// used and the index has to be rebuilt from the documents.
+    pub fn load(&self, rack_path: &Path, segments: &[(u64, u64)], cipher: Option<&Keyring>) -> Result<bool> {
+        let path = rack_path.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let contents = fs::read(&path)?;
+        let Some((crc, payload)) = contents.split_first_chunk::<4>() else {
+            return Ok(false);
+        };
+        if u32::from_le_bytes(*crc) != crc32fast::hash(payload) || crypto::is_sealed(payload) != cipher.is_some() {
+            return Ok(false);
+        }
+        let payload = match cipher {
+            Some(cipher) => cipher.open(payload, INDEX_AAD)?,
+            None => payload.to_vec(),
+        };
+        let Ok(saved) = bincode::deserialize::<SavedIndex>(&payload) else {
+            return Ok(false);
+        };
+        if saved.segments != segments || saved.fields != self.fields() || saved.compound != self.compound() {
+            return Ok(false);
+        }
+
+        self.clear();
+        for (field, values) in saved.entries {
+            let field_index = self.indices.entry(field).or_default();
+            for (value, ids) in values {
+                field_index.insert(value, ids);
+            }
+        }
+        for (field, entries) in saved.ordered {
+            self.ordered.insert(field, entries);
+        }
+        Ok(true)
+    }
+
     /// Clear index
     pub fn clear(&self) {
         self.indices.clear();
+        self.ordered.clear();
+    }
+}
+
+/// Entry under which a scalar value is indexed
+fn index_key(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Other texts of a number that equals `value`: an integer written as a
+/// float or the other way round, and both signs of zero
+fn number_spellings(value: &Value) -> Vec<String> {
+    let Value::Number(n) = value else {
+        return Vec::new();
+    };
+    let Some(rounded) = n.as_f64() else {
+        return Vec::new();
+    };
+    let other = match n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from)) {
+        Some(exact) if rounded as i128 == exact => Some(Value::from(rounded)),
+        Some(_) => None,
+        None if rounded.fract() != 0.0 => None,
+        None if (0.0..18_446_744_073_709_551_616.0).contains(&rounded) => Some(Value::from(rounded as u64)),
+        None if (-9_223_372_036_854_775_808.0..0.0).contains(&rounded) => Some(Value::from(rounded as i64)),
+        None => None,
+    };
+
+    let mut spellings: Vec<String> = other.iter().map(Value::to_string).collect();
+    if rounded == 0.0 {
+        spellings.extend(["0".to_string(), "0.0".to_string(), "-0.0".to_string()]);
+    }
+    spellings
+}
+
+/// Byte key of a scalar value that sorts like the value. Values of
+/// different types sort apart: booleans, then numbers, then dates, then
+/// strings. Strings holding an RFC 3339 date-time (or a bare `YYYY-MM-DD`)
+/// count as dates and sort by the instant they denote.
+pub fn ordered_key(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Bool(b) => Some(vec![TAG_BOOL, *b as u8]),
+        Value::Number(n) => {
+            // -0.0 and 0.0 are the same number
+            let rounded = n.as_f64()? + 0.0;
+            let bits = rounded.to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+            // Integers beyond 2^53 share their nearest float with their
+            // neighbours; what the float is off by keeps them apart
+            let exact = n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from));
+            let offset = exact.map_or(0, |exact| (exact - rounded as i128) as i32);
+            let mut key = vec![TAG_NUMBER];
+            key.extend_from_slice(&bits.to_be_bytes());
+            key.extend_from_slice(&((offset as u32) ^ 1 << 31).to_be_bytes());
+            Some(key)
+        }
+        Value::String(s) => {
+            let mut key = match parse_date(s) {
+                Some(millis) => {
+                    let mut key = vec![TAG_DATE];
+                    key.extend_from_slice(&((millis as u64) ^ 1 << 63).to_be_bytes());
+                    key
+                }
+                None => vec![TAG_STRING],
+            };
+            push_string(&mut key, s);
+            Some(key)
+        }
+        _ => None,
+    }
+}
+
+/// Append `s` escaped and terminated, so that no string key is a prefix of
+/// another and keys sort like the strings
+fn push_string(key: &mut Vec<u8>, s: &str) {
+    for &byte in s.as_bytes() {
+        key.push(byte);
+        if byte == 0 {
+            key.push(0xFF);
+        }
+    }
+    key.extend_from_slice(&[0, 1]);
+}
+
+/// Key of a document in a compound index over `fields`: the ordered keys
+/// of the values, concatenated. As no ordered key is a prefix of another,
+/// keys sort by the first field, then the second and so on. Fields that
+/// are missing or not scalars still get a component, so that a scan by
+/// leading fields finds every document.
+fn compound_key(fields: &[String], data: &Value) -> Vec<u8> {
+    let mut key = Vec::new();
+    for field in fields {
+        match data.get(field).and_then(ordered_key) {
+            Some(value_key) => key.extend_from_slice(&value_key),
+            None => key.push(TAG_NONE),
+        }
+    }
+    key
+}
+
+/// Ranges of the compound keys starting with `prefix` whose next
+/// component may satisfy `ops`
+fn compound_bounds(prefix: &[u8], ops: &[(RangeOp, &Value)]) -> Vec<KeyRange> {
+    let with = |tail: &[u8]| [prefix, tail].concat();
+    if ops.is_empty() {
+        return vec![(Bound::Included(prefix.to_vec()), Bound::Excluded(with(&[AFTER_COMPONENT])))];
+    }
+
+    // Keys continue after the bounded component, so an excluded lower or
+    // included upper bound has to skip past every continuation
+    range_bounds(ops)
+        .into_iter()
+        .map(|(lower, upper)| {
+            let lower = match lower {
+                Bound::Included(key) => Bound::Included(with(&key)),
+                Bound::Excluded(key) => Bound::Included(with(&[key.as_slice(), &[AFTER_COMPONENT]].concat())),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            let upper = match upper {
+                Bound::Included(key) => Bound::Excluded(with(&[key.as_slice(), &[AFTER_COMPONENT]].concat())),
+                Bound::Excluded(key) => Bound::Excluded(with(&key)),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            (lower, upper)
+        })
+        .collect()
+}
+
+/// Key ranges, in key order, holding every value that may satisfy all of
+/// the operators; none if no value can. All operands must be of one type,
+/// as values of different types never compare. Strings compare as
+/// strings, so a string operand bounds the plain strings and brings in all
+/// dates, whose keys follow the instant rather than the text.
+fn range_bounds(ops: &[(RangeOp, &Value)]) -> Vec<KeyRange> {
+    let operand_key = |value: &Value| match value {
+        Value::String(s) => {
+            let mut key = vec![TAG_STRING];
+            push_string(&mut key, s);
+            Some(key)
+        }
+        value => ordered_key(value),
+    };
+
+    let mut tag = None;
+    let mut lower: Option<(Vec<u8>, bool)> = None;
+    let mut upper: Option<(Vec<u8>, bool)> = None;
+    for &(op, value) in ops {
+        let Some(key) = operand_key(value) else {
+            return Vec::new();
+        };
+        if *tag.get_or_insert(key[0]) != key[0] {
+            return Vec::new();
+        }
+        // Keep the tighter of two bounds; an exclusive one wins a tie
+        match op {
+            RangeOp::Gt | RangeOp::Gte => {
+                let inclusive = op == RangeOp::Gte;
+                if lower.as_ref().is_none_or(|(bound, was)| key > *bound || (key == *bound && *was)) {
+                    lower = Some((key, inclusive));
+                }
+            }
+            RangeOp::Lt | RangeOp::Lte => {
+                let inclusive = op == RangeOp::Lte;
+                if upper.as_ref().is_none_or(|(bound, was)| key < *bound || (key == *bound && *was)) {
+                    upper = Some((key, inclusive));
+                }
+            }
+        }
+    }
+
+    let Some(tag) = tag else {
+        return Vec::new();
+    };
+    if let (Some((low, low_inclusive)), Some((high, high_inclusive))) = (&lower, &upper) {
+        if low > high || (low == high && !(*low_inclusive && *high_inclusive)) {
+            return Vec::new();
+        }
+    }
+    let lower = match lower {
+        Some((key, true)) => Bound::Included(key),
+        Some((key, false)) => Bound::Excluded(key),
+        None => Bound::Included(vec![tag]),
+    };
+    let upper = match upper {
+        Some((key, true)) => Bound::Included(key),
+        Some((key, false)) => Bound::Excluded(key),
+        None => Bound::Excluded(vec![tag + 1]),
+    };
+
+    let mut ranges = Vec::new();
+    if tag == TAG_STRING {
+        ranges.push((Bound::Included(vec![TAG_DATE]), Bound::Excluded(vec![TAG_DATE + 1])));
+    }
+    ranges.push((lower, upper));
+    ranges
+}
+
+/// Milliseconds since the Unix epoch of an RFC 3339 date-time such as
+/// `2024-05-01T12:00:00.250+02:00`, or of a bare date at midnight UTC
+fn parse_date(s: &str) -> Option<i64> {
+    fn number(digits: &[u8]) -> Option<i64> {
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        Some(digits.iter().fold(0, |n, digit| n * 10 + i64::from(digit - b'0')))
+    }
+
+    let b = s.as_bytes();
+    if b.len() < 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+    let (year, month, day) = (number(&b[0..4])?, number(&b[5..7])?, number(&b[8..10])?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if b.len() == 10 {
+        return Some(days * 86_400_000);
+    }
+
+    if b.len() < 20 || !matches!(b[10], b'T' | b't') || b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let (hour, minute, second) = (number(&b[11..13])?, number(&b[14..16])?, number(&b[17..19])?);
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &b[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix(b".") {
+        let len = fraction.iter().take_while(|d| d.is_ascii_digit()).count();
+        let digits: Vec<u8> = fraction[..len].iter().copied().chain([b'0'; 3]).take(3).collect();
+        millis = number(&fraction[..len]).and(number(&digits))?;
+        rest = &fraction[len..];
+    }
+    let offset_minutes = match rest {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let offset = number(&[*h1, *h2])? * 60 + number(&[*m1, *m2])?;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+
+    let minutes = (days * 24 + hour) * 60 + minute - offset_minutes;
+    Some(minutes * 60_000 + second * 1000 + millis)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::app_engine;
+    use crate::query::QueryEngine;
+    use crate::storage::{EngineOptions, StorageEngine};
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    fn index_names(engine: &StorageEngine, rack: &str) -> Vec<String> {
+        engine.list_indexes("app", rack).unwrap().into_iter().map(|index| index.name).collect()
+    }
+
+    #[test]
+    fn test_ordered_keys_sort_like_values() {
+        let sorted = [
+            json!(false),
+            json!(true),
+            json!(-1e10),
+            json!(-2),
+            json!(0),
+            json!(1.5),
+            json!(10),
+            json!("1969-12-31T23:59:59Z"),
+            json!("2024-05-01T09:00:00+02:00"),
+            json!("2024-05-01T07:00:00.5Z"),
+            json!("2024-05-02"),
+            json!(""),
+            json!("a"),
+            json!("a\u{0}"),
+            json!("ab"),
+            json!("b"),
+        ];
+        for pair in sorted.windows(2) {
+            assert!(ordered_key(&pair[0]) < ordered_key(&pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(ordered_key(&json!(-0.0)), ordered_key(&json!(0)));
+        assert_eq!(ordered_key(&json!(1.0)), ordered_key(&json!(1)));
+
+        // Integers past 2^53 keep their order, also against floats
+        let big = 1u64 << 53;
+        let sorted = [json!(big - 1), json!(big), json!(big + 1), json!(9.007199254740994e15), json!(big + 3)];
+        for pair in sorted.windows(2) {
+            assert!(ordered_key(&pair[0]) < ordered_key(&pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert!(ordered_key(&json!(i64::MIN)) < ordered_key(&json!(i64::MIN + 1)));
+        assert!(ordered_key(&json!(u64::MAX - 1)) < ordered_key(&json!(u64::MAX)));
+        assert_eq!(parse_date("1970-01-02T00:00:00Z"), Some(86_400_000));
+        assert_eq!(parse_date("2000-03-01"), Some(11_017 * 86_400_000));
+        assert!(parse_date("2024-13-01").is_none());
+        assert!(parse_date("2024-05-01 12:00").is_none());
+    }
+
+    #[test]
+    fn test_ordered_index_scans_ranges() {
+        let index = Index::with_fields([("age".to_string(), IndexKind::Ordered)]);
+        for (id, age) in [("1", json!(30)), ("2", json!(25)), ("3", json!(40)), ("4", json!("old"))] {
+            index.index_document(id, &json!({ "age": age }));
+        }
+
+        let (young, old) = (json!(25), json!(40));
+        let ids = index.scan("age", &[(RangeOp::Gte, &young), (RangeOp::Lt, &old)], false).unwrap();
+        assert_eq!(ids, vec!["2", "1"]);
+        assert_eq!(index.scan("age", &[], true).unwrap(), vec!["4", "3", "1", "2"]);
+        assert!(index.range("age", &[(RangeOp::Gt, &old), (RangeOp::Lt, &young)]).unwrap().is_empty());
+        assert!(index.range("age", &[(RangeOp::Gt, &json!(25)), (RangeOp::Gte, &young)]).unwrap().len() == 2);
+        assert!(index.range("age", &[(RangeOp::Gt, &json!("a"))]).unwrap().contains("4"));
+        // A string operand cannot bound dates, which sort by instant
+        index.index_document("5", &json!({ "age": "2024-05-01" }));
+        let ids = index.range("age", &[(RangeOp::Gt, &json!("2024")), (RangeOp::Lt, &json!("p"))]).unwrap();
+        assert_eq!(ids, HashSet::from(["4".to_string(), "5".to_string()]));
+        index.remove_document("5", &json!({ "age": "2024-05-01" }));
+
+        index.remove_document("3", &json!({ "age": 40 }));
+        assert!(index.candidates("age", &old).unwrap().is_empty());
+        assert!(index.range("name", &[(RangeOp::Gt, &old)]).is_none());
+    }
+
+    #[test]
+    fn test_ordered_index_ranges_keep_large_integers_apart() {
+        let index = Index::with_fields([("seq".to_string(), IndexKind::Ordered)]);
+        let big = 1u64 << 53;
+        for (id, seq) in [("1", big - 1), ("2", big), ("3", big + 1), ("4", big + 2)] {
+            index.index_document(id, &json!({ "seq": seq }));
+        }
+
+        let (low, high) = (json!(big), json!(big + 2));
+        let ids = index.scan("seq", &[(RangeOp::Gt, &low), (RangeOp::Lt, &high)], false).unwrap();
+        assert_eq!(ids, vec!["3"]);
+        let ids = index.scan("seq", &[(RangeOp::Gte, &low)], true).unwrap();
+        assert_eq!(ids, vec!["4", "3", "2"]);
+        assert_eq!(index.candidates("seq", &json!(big + 1)).unwrap(), HashSet::from(["3".to_string()]));
+    }
+
+    #[test]
+    fn test_compound_index_scans_prefixes() {
+        let fields = vec!["tenant".to_string(), "day".to_string()];
+        let index = Index::new().with_compound([("by_day".to_string(), fields)]);
+        let documents = [
+            ("1", json!({ "tenant": "a", "day": 1 })),
+            ("2", json!({ "tenant": "a", "day": 2 })),
+            ("3", json!({ "tenant": "a", "day": 3 })),
+            ("4", json!({ "tenant": "ab", "day": 2 })),
+            ("5", json!({ "tenant": "a" })),
+            ("6", json!({ "day": 2 })),
+        ];
+        for (id, data) in &documents {
+            index.index_document(id, data);
+        }
+
+        let sorted = |sets: Vec<HashSet<String>>| {
+            assert_eq!(sets.len(), 1);
+            let mut ids: Vec<String> = sets.into_iter().next().unwrap().into_iter().collect();
+            ids.sort();
+            ids
+        };
+        let (tenant, two) = (json!("a"), json!(2));
+        let prefix = [("tenant", &tenant)];
+        assert_eq!(sorted(index.compound_candidates(&prefix, &[])), vec!["1", "2", "3", "5"]);
+        assert_eq!(sorted(index.compound_candidates(&[("tenant", &tenant), ("day", &two)], &[])), vec!["2"]);
+        let ranges = |ops| vec![("day", ops)];
+        assert_eq!(
+            sorted(index.compound_candidates(&prefix, &ranges(vec![(RangeOp::Gt, &two)]))),
+            vec!["3"]
+        );
+        assert_eq!(
+            sorted(index.compound_candidates(&prefix, &ranges(vec![(RangeOp::Lte, &two)]))),
+            vec!["1", "2"]
+        );
+        // Only a leading field makes the index usable
+        assert!(index.compound_candidates(&[("day", &two)], &[]).is_empty());
+
+        index.remove_document("2", &documents[1].1);
+        assert!(sorted(index.compound_candidates(&[("tenant", &tenant), ("day", &two)], &[])).is_empty());
+        assert!(index.kind("by_day").is_none());
+    }
+
+    #[test]
+    fn test_declared_indexes_are_saved_and_reused() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        let rack_path = dir.path().join("app").join("users");
+        engine.create_rack("app", "users", None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice","city":"Oslo"}"#, None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Bob","city":"Oslo"}"#, None).unwrap();
+
+        // Only declared fields are indexed
+        let rack = engine.open_rack("app", "users").unwrap();
+        assert!(rack.index.entries().is_empty());
+        drop(rack);
+        assert!(engine.create_index("app", "users", "city", None).unwrap());
+        assert!(!engine.create_index("app", "users", "city", None).unwrap());
+        assert!(engine.create_index("app", "users", "name", Some(r#"{"bogus":true}"#)).is_err());
+        assert!(engine.create_index("app", "users", "name", Some("{}")).unwrap());
+        assert_eq!(index_names(&engine, "users"), vec!["city", "name"]);
+        let rack = engine.open_rack("app", "users").unwrap();
+        assert_eq!(rack.index.search("city", "Oslo").unwrap().len(), 2);
+        drop(rack);
+
+        assert!(engine.drop_index("app", "users", "name").unwrap());
+        assert!(!engine.drop_index("app", "users", "name").unwrap());
+        engine.checkpoint().unwrap();
+        assert!(rack_path.join(INDEX_FILE).exists());
+        drop(engine);
+
+        // The saved index is used as long as the segments are unchanged
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(index_names(&engine, "users"), vec!["city"]);
+        let rack = engine.open_rack("app", "users").unwrap();
+        assert!(!rack.index_dirty.load(Ordering::SeqCst));
+        assert_eq!(rack.index.search("city", "Oslo").unwrap().len(), 2);
+        assert!(rack.index.search("name", "Alice").is_none());
+        drop(rack);
+        drop(engine);
+
+        // A write the saved index misses forces a rebuild
+        let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        engine.insert("app", "users", r#"{"name":"Carol","city":"Oslo"}"#, None).unwrap();
+        drop(engine);
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "users", r#"{"city":"Oslo"}"#, None, None).unwrap().len(), 3);
+        assert!(engine.verify(None, false).unwrap().issues.is_empty());
+
+        engine.compact("app", "users").unwrap();
+        assert!(!rack_path.join(INDEX_FILE).exists());
+    }
+
+    #[test]
+    fn test_unique_indexes_reject_duplicates() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine.create_rack("app", "users", None).unwrap();
+        let alice = engine.insert("app", "users", r#"{"username":"alice","n":1}"#, None).unwrap();
+        let bob = engine.insert("app", "users", r#"{"username":"bob","n":1.0}"#, None).unwrap();
+        engine.insert("app", "users", r#"{"name":"anonymous"}"#, None).unwrap();
+
+        // Existing duplicates keep the index from being created
+        let unique = Some(r#"{"unique":true}"#);
+        assert!(matches!(
+            engine.create_index("app", "users", "n", unique),
+            Err(OpenDBSError::UniqueViolation { .. })
+        ));
+        assert!(engine.list_indexes("app", "users").unwrap().is_empty());
+        assert!(engine.create_index("app", "users", "username", unique).unwrap());
+        let indexes = engine.list_indexes("app", "users").unwrap();
+        assert!(indexes[0].unique && indexes[0].kind == IndexKind::Hash);
+
+        match engine.insert("app", "users", r#"{"username":"alice"}"#, None) {
+            Err(OpenDBSError::UniqueViolation { field, value, existing_id }) => {
+                assert_eq!(field, "username");
+                assert_eq!(value, r#""alice""#);
+                assert_eq!(existing_id, alice);
+            }
+            other => panic!("expected a unique violation, got {:?}", other),
+        }
+        let bob_update = r#"{"username":"alice","n":2}"#;
+        assert!(engine.update("app", "users", &bob, bob_update, None).is_err());
+        assert!(engine.update("app", "users", &alice, r#"{"username":"alice","n":2}"#, None).unwrap());
+        // Documents without the field are not constrained
+        engine.insert("app", "users", r#"{"name":"anonymous"}"#, None).unwrap();
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 4);
+
+        // Equal numbers clash however they are written
+        for (field, options) in [("code", unique), ("rank", Some(r#"{"type":"ordered","unique":true}"#))] {
+            assert!(engine.create_index("app", "users", field, options).unwrap());
+            engine.insert("app", "users", &format!(r#"{{"{}":2}}"#, field), None).unwrap();
+            let twin = format!(r#"{{"{}":2.0}}"#, field);
+            assert!(matches!(
+                engine.insert("app", "users", &twin, None),
+                Err(OpenDBSError::UniqueViolation { .. })
+            ));
+        }
+        drop(engine);
+
+        let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert!(engine.insert("app", "users", r#"{"username":"bob"}"#, None).is_err());
+        // A unique field whose index is missing is still checked, by a scan
+        let rack = engine.open_rack("app", "users").unwrap();
+        rack.index.remove_field("username");
+        assert!(matches!(
+            rack.check_unique(&serde_json::json!({ "username": "bob" }), None),
+            Err(OpenDBSError::UniqueViolation { .. })
+        ));
+        rack.index.add_field("username", IndexKind::Hash);
+        rack.rebuild_index().unwrap();
+        drop(rack);
+        assert!(engine.update("app", "users", &bob, r#"{"username":"robert"}"#, None).unwrap());
+        engine.insert("app", "users", r#"{"username":"bob"}"#, None).unwrap();
+
+        let path = dir.path().join("users.ndjson");
+        fs::write(&path, "{\"username\":\"carol\"}\n{\"username\":\"robert\"}\n").unwrap();
+        let report = engine
+            .import_rack("app", "users", path.to_str().unwrap(), "ndjson", None)
+            .unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.errors[0].line, 2);
+    }
+
+    #[test]
+    fn test_compound_indexes_answer_prefix_queries() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        // Declared indexes are checked like those created later
+        for options in [
+            r#"{"indexes":["c"],"index_options":{"c":{"fields":["a","b"],"unique":true}}}"#,
+            r#"{"indexes":["c"],"index_options":{"c":{"fields":["a"]}}}"#,
+            r#"{"indexes":["_id"]}"#,
+            r#"{"indexes":["a","a"]}"#,
+            r#"{"index_options":{"a":{"unique":true}}}"#,
+        ] {
+            assert!(matches!(
+                engine.create_rack("app", "bad", Some(options)),
+                Err(OpenDBSError::InvalidQuery(_))
+            ));
+        }
+        let key = "33".repeat(32);
+        let encrypted = format!(
+            r#"{{"encrypted_fields":{{"a":"deterministic"}},"field_key":"{}","indexes":["c"],"index_options":{{"c":{{"fields":["a","b"]}}}}}}"#,
+            key
+        );
+        assert!(matches!(
+            engine.create_rack("app", "bad", Some(&encrypted)),
+            Err(OpenDBSError::Encryption(_))
+        ));
+        assert!(!dir.path().join("app").join("bad").exists());
+
+        engine.create_rack("app", "tickets", None).unwrap();
+        for n in 0..40 {
+            let status = if n % 4 == 0 { "open" } else { "closed" };
+            let ticket = serde_json::json!({ "tenant_id": n % 2, "status": status, "n": n });
+            engine.insert("app", "tickets", &ticket.to_string(), None).unwrap();
+        }
+
+        let by_status = Some(r#"{"fields":["tenant_id","status","n"]}"#);
+        assert!(engine.create_index("app", "tickets", "tenant_id", Some(r#"{"fields":["status"]}"#)).is_err());
+        assert!(engine.create_index("app", "tickets", "by_status", Some(r#"{"fields":["n","n"]}"#)).is_err());
+        assert!(engine.create_index("app", "tickets", "by_status", by_status).unwrap());
+        let indexes = engine.list_indexes("app", "tickets").unwrap();
+        assert_eq!(
+            serde_json::to_value(&indexes).unwrap(),
+            serde_json::json!([{ "name": "by_status", "type": "ordered", "unique": false, "fields": ["tenant_id", "status", "n"] }])
+        );
+
+        let query = serde_json::json!({ "tenant_id": 0, "status": "open", "n": { "$gte": 8, "$lt": 32 } });
+        let rack = engine.open_rack("app", "tickets").unwrap();
+        let predicates = QueryEngine::new().equality_predicates(&query);
+        let ranges = QueryEngine::new().range_predicates(&query);
+        assert_eq!(rack.candidates(&predicates, &ranges, None).unwrap().unwrap().len(), 6);
+        let prefix: Vec<_> = predicates.iter().copied().filter(|(field, _)| *field == "tenant_id").collect();
+        assert_eq!(rack.candidates(&prefix, &[], None).unwrap().unwrap().len(), 20);
+        drop(rack);
+
+        assert_eq!(engine.find("app", "tickets", &query.to_string(), None, None).unwrap().len(), 6);
+        let open = r#"{"tenant_id":0,"status":"open"}"#;
+        assert_eq!(engine.find("app", "tickets", open, None, None).unwrap().len(), 10);
+        // A query that skips the leading field scans the rack
+        assert_eq!(engine.find("app", "tickets", r#"{"status":"open"}"#, None, None).unwrap().len(), 10);
+
+        // A document without the middle field is still found by the prefix
+        engine.insert("app", "tickets", r#"{"tenant_id":1,"n":99}"#, None).unwrap();
+        assert_eq!(engine.find("app", "tickets", r#"{"tenant_id":1}"#, None, None).unwrap().len(), 21);
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        let rack = engine.open_rack("app", "tickets").unwrap();
+        assert!(!rack.index_dirty.load(Ordering::SeqCst));
+        drop(rack);
+        assert_eq!(engine.find("app", "tickets", &query.to_string(), None, None).unwrap().len(), 6);
+        assert!(engine.verify(None, false).unwrap().issues.is_empty());
+        assert!(engine.drop_index("app", "tickets", "by_status").unwrap());
+        assert_eq!(engine.find("app", "tickets", open, None, None).unwrap().len(), 10);
     }
 }
@@ -6,9 +6,26 @@ mod storage;
 mod index;
 mod query;
 mod compression;
+mod crypto;
 mod error;
+mod durability;
+mod wal;
+mod lock;
+mod quarantine;
+mod verify;
+mod segment;
+mod snapshot;
+mod mmap;
+mod oplog;
+mod meta;
+mod migration;
+mod transfer;
 
-use storage::StorageEngine;
+use error::OpenDBSError;
+use storage::{EngineOptions, StorageEngine};
+
+/// `code` of the JS error thrown when a unique index rejects a write
+const UNIQUE_VIOLATION: &str = "UNIQUE_VIOLATION";
 
 /// Main OpenDBS engine instance
 #[napi]
@@ -18,10 +35,23 @@ pub struct OpenDBSEngine {
 
 #[napi]
 impl OpenDBSEngine {
-    /// Create a new OpenDBS engine instance
+    /// Create a new OpenDBS engine instance, with optional JSON options
+    /// such as `{"durability": {"batched_ms": 50}, "memory_budget_bytes": 268435456}`.
+    /// `{"oplog": true}` keeps the operation log `restore_to` needs.
+    /// `{"encryption": {"key": "<64 hex chars>"}}` or `{"encryption": {"key_file": "..."}}`
+    /// encrypts documents and logs at rest. `{"read_only": true}` opens an
+    /// existing directory without writing to it, sharing it with other
+    /// read-only engines, and makes every mutation fail; otherwise opening
+    /// a directory another engine has open fails.
     #[napi(constructor)]
-    pub fn new(path: String) -> napi::Result<Self> {
-        let engine = StorageEngine::new(&path)
+    pub fn new(path: String, options: Option<String>) -> napi::Result<Self> {
+        let options: EngineOptions = match options {
+            Some(options) => serde_json::from_str(&options)
+                .map_err(|e| napi::Error::from_reason(e.to_string()))?,
+            None => EngineOptions::default(),
+        };
+
+        let engine = StorageEngine::new(&path, options)
             .map_err(|e| napi::Error::from_reason(e.to_string()))?;
         
         Ok(Self {
@@ -38,42 +68,124 @@ impl OpenDBSEngine {
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
-    /// Create a new rack (collection/table)
+    /// Create a new rack (collection/table), with optional JSON options
+    /// such as `{"type": "sql", "schema": {...}, "compression": true}`.
+    /// `{"encrypted_fields": {"ssn": "deterministic"}, "field_key": "<64 hex chars>"}`
+    /// stores fields encrypted; the key itself is not kept. `"id_strategy"`
+    /// is `counter` (default), `uuid_v4`, `uuid_v7`, `ulid`, or `caller` to
+    /// require an `_id` in every inserted document.
     #[napi]
-    pub fn create_rack(&self, database: String, rack: String) -> napi::Result<bool> {
+    pub fn create_rack(&self, database: String, rack: String, options: Option<String>) -> napi::Result<bool> {
         self.engine
             .write()
-            .create_rack(&database, &rack)
+            .create_rack(&database, &rack, options.as_deref())
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
-    /// Insert a document into a rack
+    /// Drop a database and all of its racks
     #[napi]
-    pub fn insert(&self, database: String, rack: String, data: String) -> napi::Result<String> {
+    pub fn drop_database(&self, name: String) -> napi::Result<bool> {
         self.engine
             .write()
-            .insert(&database, &rack, &data)
+            .drop_database(&name)
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
-    /// Find documents matching a query
+    /// Drop a rack and its documents
     #[napi]
-    pub fn find(&self, database: String, rack: String, query: String) -> napi::Result<Vec<String>> {
+    pub fn drop_rack(&self, database: String, rack: String) -> napi::Result<bool> {
         self.engine
-            .read()
-            .find(&database, &rack, &query)
+            .write()
+            .drop_rack(&database, &rack)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Remove every document of a rack
+    #[napi]
+    pub fn clear_rack(&self, database: String, rack: String) -> napi::Result<bool> {
+        self.engine
+            .write()
+            .clear_rack(&database, &rack)
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
-    /// Update a document
+    /// Rename a rack within its database
     #[napi]
-    pub fn update(&self, database: String, rack: String, id: String, data: String) -> napi::Result<bool> {
+    pub fn rename_rack(&self, database: String, rack: String, new_name: String) -> napi::Result<bool> {
         self.engine
             .write()
-            .update(&database, &rack, &id, &data)
+            .rename_rack(&database, &rack, &new_name)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Copy a rack, possibly into another database
+    #[napi]
+    pub fn duplicate_rack(
+        &self,
+        source_db: String,
+        source_rack: String,
+        target_db: String,
+        target_rack: String,
+    ) -> napi::Result<bool> {
+        self.engine
+            .write()
+            .duplicate_rack(&source_db, &source_rack, &target_db, &target_rack)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Insert a document into a rack. `field_key` encrypts the rack's
+    /// encrypted fields. A value a unique index already holds throws an
+    /// error with `code` `UNIQUE_VIOLATION`.
+    #[napi]
+    pub fn insert(
+        &self,
+        database: String,
+        rack: String,
+        data: String,
+        field_key: Option<String>,
+    ) -> napi::Result<String, String> {
+        self.engine
+            .write()
+            .insert(&database, &rack, &data, field_key.as_deref())
+            .map_err(write_error)
+    }
+
+    /// Find documents matching a query. Encrypted fields are only
+    /// decrypted when the rack's `field_key` is given. `options` is JSON
+    /// such as `{"sort": {"field": "age", "order": "desc"}, "skip": 10,
+    /// "limit": 10}`; sorting walks an ordered index when there is one.
+    #[napi]
+    pub fn find(
+        &self,
+        database: String,
+        rack: String,
+        query: String,
+        field_key: Option<String>,
+        options: Option<String>,
+    ) -> napi::Result<Vec<String>> {
+        self.engine
+            .read()
+            .find(&database, &rack, &query, field_key.as_deref(), options.as_deref())
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
+    /// Update a document. `field_key` encrypts the rack's encrypted fields.
+    /// Unique indexes are enforced as in `insert`.
+    #[napi]
+    pub fn update(
+        &self,
+        database: String,
+        rack: String,
+        id: String,
+        data: String,
+        field_key: Option<String>,
+    ) -> napi::Result<bool, String> {
+        self.engine
+            .write()
+            .update(&database, &rack, &id, &data, field_key.as_deref())
+            .map_err(write_error)
+    }
+
     /// Delete a document
     #[napi]
     pub fn delete(&self, database: String, rack: String, id: String) -> napi::Result<bool> {
@@ -99,6 +211,186 @@ impl OpenDBSEngine {
             .map_err(|e| napi::Error::from_reason(e.to_string()))
     }
 
+    /// Declare an index on a top-level field and index the rack's
+    /// documents. `options` of `{"type": "ordered"}` keep the entries
+    /// sorted for range queries and sorting; `{"unique": true}` rejects
+    /// writes of a value another document holds, and fails with `code`
+    /// `UNIQUE_VIOLATION` if the rack already has duplicates. With
+    /// `{"fields": ["tenant_id", "status"]}` it creates a compound index
+    /// named `field`, which `find` uses when a query has equality
+    /// predicates on its leading fields, plus optionally a range on the
+    /// next one. Returns false if the field is indexed already.
+    #[napi]
+    pub fn create_index(
+        &self,
+        database: String,
+        rack: String,
+        field: String,
+        options: Option<String>,
+    ) -> napi::Result<bool, String> {
+        self.engine
+            .write()
+            .create_index(&database, &rack, &field, options.as_deref())
+            .map_err(write_error)
+    }
+
+    /// Drop the index on a field. Returns false if there was none.
+    #[napi]
+    pub fn drop_index(&self, database: String, rack: String, field: String) -> napi::Result<bool> {
+        self.engine
+            .write()
+            .drop_index(&database, &rack, &field)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// List a rack's indexes as JSON: `[{"name", "type", "unique", "fields"}]`
+    #[napi]
+    pub fn list_indexes(&self, database: String, rack: String) -> napi::Result<String> {
+        self.engine
+            .read()
+            .list_indexes(&database, &rack)
+            .and_then(|indexes| Ok(serde_json::to_string(&indexes)?))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Export a rack to a file as `ndjson`, `csv` or `json`, returning the
+    /// number of documents written. Options: `{"keep_ids": true, "keep_timestamps": true}`
+    #[napi]
+    pub fn export_rack(
+        &self,
+        database: String,
+        rack: String,
+        path: String,
+        format: String,
+        options: Option<String>,
+    ) -> napi::Result<u32> {
+        self.engine
+            .read()
+            .export_rack(&database, &rack, &path, &format, options.as_deref())
+            .map(|count| count as u32)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Import documents from an `ndjson`, `csv` or `json` file and return a
+    /// JSON report with the imported count and per-line errors
+    #[napi]
+    pub fn import_rack(
+        &self,
+        database: String,
+        rack: String,
+        path: String,
+        format: String,
+        options: Option<String>,
+    ) -> napi::Result<String> {
+        self.engine
+            .write()
+            .import_rack(&database, &rack, &path, &format, options.as_deref())
+            .and_then(|report| Ok(serde_json::to_string(&report)?))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Compact a rack's files, reclaiming space left by updates and deletes
+    #[napi]
+    pub fn compact(&self, database: String, rack: String) -> napi::Result<String> {
+        self.engine
+            .read()
+            .compact(&database, &rack)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Make `new_key` (64 hex characters) the active encryption key and
+    /// re-encrypt every rack with it in the background
+    #[napi]
+    pub fn rotate_key(&self, new_key: String) -> napi::Result<()> {
+        self.engine
+            .read()
+            .rotate_key(&new_key)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Flush pending writes, save changed indexes and truncate the write-ahead log
+    #[napi]
+    pub fn checkpoint(&self) -> napi::Result<()> {
+        self.engine
+            .read()
+            .checkpoint()
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Write a consistent copy of `databases` (default: all) to `dest_dir`
+    /// and return its manifest as JSON
+    #[napi]
+    pub fn snapshot(&self, dest_dir: String, databases: Option<Vec<String>>) -> napi::Result<String> {
+        // Files are captured under the lock; checksumming them does not need it
+        let pending = self
+            .engine
+            .read()
+            .snapshot(&dest_dir, databases.as_deref())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        pending
+            .finish()
+            .and_then(|manifest| Ok(serde_json::to_string(&manifest)?))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Replace `database` (default: every database in the snapshot) with its
+    /// copy in the snapshot at `src_dir`, returning the restored names
+    #[napi]
+    pub fn restore(&self, src_dir: String, database: Option<String>) -> napi::Result<Vec<String>> {
+        self.engine
+            .write()
+            .restore(&src_dir, database.as_deref())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Rebuild `database` as it was at `timestamp_ms` into the new database
+    /// `target` from the nearest snapshot and the operation log. Returns
+    /// false if `target` already exists.
+    #[napi]
+    pub fn restore_to(&self, database: String, timestamp_ms: i64, target: String) -> napi::Result<bool> {
+        let timestamp_ms = u64::try_from(timestamp_ms)
+            .map_err(|_| napi::Error::from_reason("timestamp_ms must not be negative"))?;
+        self.engine
+            .write()
+            .restore_to(&database, timestamp_ms, &target)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Check storage consistency of one database (or all of them) and return
+    /// a JSON report. With `repair`, fixable issues are fixed in place.
+    #[napi]
+    pub fn verify(&self, database: Option<String>, repair: Option<bool>) -> napi::Result<String> {
+        let repair = repair.unwrap_or(false);
+        let report = if repair {
+            self.engine.write().verify(database.as_deref(), true)
+        } else {
+            self.engine.read().verify(database.as_deref(), false)
+        };
+
+        report
+            .and_then(|report| Ok(serde_json::to_string(&report)?))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Get a rack's type, schema, indexes, creation time and settings as JSON
+    #[napi]
+    pub fn get_rack_info(&self, database: String, rack: String) -> napi::Result<String> {
+        self.engine
+            .read()
+            .get_rack_info(&database, &rack)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// List files skipped while loading, and why, as JSON
+    #[napi]
+    pub fn get_load_report(&self) -> napi::Result<String> {
+        self.engine
+            .read()
+            .get_load_report()
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
     /// Get storage statistics
     #[napi]
     pub fn get_stats(&self) -> napi::Result<String> {
@@ -109,10 +401,18 @@ impl OpenDBSEngine {
     }
 }
 
+/// JS error for a failed write, with a `code` that tells constraint
+/// violations apart from other failures
+fn write_error(e: OpenDBSError) -> napi::Error<String> {
+    let code = match e {
+        OpenDBSError::UniqueViolation { .. } => UNIQUE_VIOLATION,
+        _ => napi::Status::GenericFailure.as_ref(),
+    };
+    napi::Error::new(code.to_string(), e.to_string())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn test_basic_operations() {
         // Tests will be added here
@@ -0,0 +1,147 @@
+//! Cross-process lock on the data directory.
+//!
+//! Every engine holds an advisory lock on `opendbs.lock` in its root for as
+//! long as it lives: writers take it exclusively, read-only engines take it
+//! shared. A second writer, or a writer next to readers, fails to open
+//! instead of loading its own copy of the data and overwriting the other's
+//! files. The lock is released by the OS when the process dies.
+//!
+//! Read-only engines create the lock file if it is missing, so that a writer
+//! cannot slip in next to them. Only when the directory itself cannot be
+//! written (a read-only mount, say) do they go without a lock, and then no
+//! writer can use the directory either.
+
+use crate::error::{OpenDBSError, Result};
+use fs4::fs_std::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// File inside the engine root that carries the lock
+pub const LOCK_FILE: &str = "opendbs.lock";
+
+/// The lock on a data directory, released when dropped
+#[derive(Debug)]
+pub struct DirLock {
+    _file: Option<File>,
+}
+
+impl DirLock {
+    /// Lock `root` exclusively, or shared if `shared` is set. Fails with
+    /// `OpenDBSError::Locked` instead of waiting if the lock is taken.
+    pub fn acquire(root: &Path, shared: bool) -> Result<Self> {
+        let path = root.join(LOCK_FILE);
+        let opened = match shared && path.exists() {
+            true => OpenOptions::new().read(true).open(&path),
+            false => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path),
+        };
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(e) if shared && is_read_only_error(&e) => {
+                tracing::warn!(
+                    "Cannot create {} ({}); opening {} read-only without a lock",
+                    path.display(),
+                    e,
+                    root.display()
+                );
+                return Ok(Self { _file: None });
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let locked = match shared {
+            true => FileExt::try_lock_shared(&file)?,
+            false => FileExt::try_lock_exclusive(&file)?,
+        };
+        if !locked {
+            // The writer holding the lock leaves its process id in the file
+            let holder = fs::read_to_string(&path)
+                .ok()
+                .map(|pid| pid.trim().to_string())
+                .filter(|pid| !pid.is_empty())
+                .map_or(String::new(), |pid| format!(" (process {})", pid));
+            let mode = if shared { "a writer" } else { "another engine" };
+            return Err(OpenDBSError::Locked(format!(
+                "{} is in use by {}{}",
+                root.display(),
+                mode,
+                holder
+            )));
+        }
+
+        if !shared {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", std::process::id())?;
+        }
+        Ok(Self { _file: Some(file) })
+    }
+}
+
+/// Whether creating a file failed because its directory cannot be written
+fn is_read_only_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ReadOnlyFilesystem | std::io::ErrorKind::PermissionDenied
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EngineOptions, StorageEngine};
+
+    #[test]
+    fn test_exclusive_and_shared_locks() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let writer = DirLock::acquire(dir.path(), false).unwrap();
+        let err = DirLock::acquire(dir.path(), false).unwrap_err();
+        assert!(matches!(err, OpenDBSError::Locked(_)));
+        assert!(err.to_string().contains(&std::process::id().to_string()));
+        assert!(DirLock::acquire(dir.path(), true).is_err());
+        drop(writer);
+
+        let reader = DirLock::acquire(dir.path(), true).unwrap();
+        let _other_reader = DirLock::acquire(dir.path(), true).unwrap();
+        assert!(matches!(DirLock::acquire(dir.path(), false), Err(OpenDBSError::Locked(_))));
+        drop(reader);
+    }
+
+    #[test]
+    fn test_data_directory_is_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let read_only = EngineOptions {
+            read_only: true,
+            ..EngineOptions::default()
+        };
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        let second = StorageEngine::new(root, EngineOptions::default());
+        assert!(matches!(second, Err(OpenDBSError::Locked(_))));
+        assert!(StorageEngine::new(root, read_only.clone()).is_err());
+        drop(engine);
+
+        let reader = StorageEngine::new(root, read_only.clone()).unwrap();
+        let _other_reader = StorageEngine::new(root, read_only).unwrap();
+        assert!(StorageEngine::new(root, EngineOptions::default()).is_err());
+        drop(reader);
+    }
+
+    #[test]
+    fn test_read_only_engine_locks_a_fresh_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let read_only = EngineOptions {
+            read_only: true,
+            ..EngineOptions::default()
+        };
+
+        let reader = StorageEngine::new(root, read_only).unwrap();
+        let writer = StorageEngine::new(root, EngineOptions::default());
+        assert!(matches!(writer, Err(OpenDBSError::Locked(_))));
+        drop(reader);
+        assert!(StorageEngine::new(root, EngineOptions::default()).is_ok());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::crypto::FieldEncryption;
+use crate::durability;
+use crate::error::{OpenDBSError, Result};
+use crate::storage::RackSettings;
+use crate::transfer::ID_FIELD;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+/// File holding a rack's metadata inside its directory
+pub const META_FILE: &str = "rack.meta";
+
+/// Settings file written by versions before `rack.meta` existed
+const LEGACY_SETTINGS_FILE: &str = "settings.json";
+
+/// Newest metadata format this build reads and writes
+pub const META_VERSION: u32 = 2;
+
+/// Format of racks that use nothing newer than plain indexes. Racks with
+/// id strategies, index options or encrypted fields are written as
+/// `META_VERSION`, so that builds which would ignore those settings refuse
+/// to open them.
+const BASE_META_VERSION: u32 = 1;
+
+/// How a rack is used by the server
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RackType {
+    #[default]
+    Nosql,
+    Sql,
+}
+
+/// How a rack assigns ids to inserted documents
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IdStrategy {
+    /// Decimal counter starting at 1
+    #[default]
+    Counter,
+    /// Random UUID
+    UuidV4,
+    /// Time-ordered UUID
+    UuidV7,
+    /// Time-ordered ULID
+    Ulid,
+    /// Every document must bring its own `_id`
+    Caller,
+}
+
+/// Everything known about a rack apart from its documents
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RackMeta {
+    pub version: u32,
+    #[serde(rename = "type", default)]
+    pub rack_type: RackType,
+    #[serde(default)]
+    pub id_strategy: IdStrategy,
+    /// Field schema as given by the server, `{ "<field>": { "type": ... } }`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
+    /// Fields declared as indexed
+    #[serde(default)]
+    pub indexes: Vec<String>,
+    /// Options of the indexes that do not use the defaults
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub index_options: BTreeMap<String, IndexOptions>,
+    /// Creation time in seconds since the Unix epoch
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub settings: RackSettings,
+    /// Top-level fields stored encrypted with a caller-held field key
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub encrypted_fields: BTreeMap<String, FieldEncryption>,
+    /// `FieldCipher::key_check` of the rack's field key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field_key_check: Option<String>,
+}
+
+/// JSON options accepted by `create_rack`: storage settings plus the rack's
+/// type, schema and indexed fields
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct RackOptions {
+    #[serde(rename = "type")]
+    pub rack_type: RackType,
+    pub id_strategy: IdStrategy,
+    pub schema: Option<Value>,
+    pub indexes: Vec<String>,
+    pub index_options: BTreeMap<String, IndexOptions>,
+    pub encrypted_fields: BTreeMap<String, FieldEncryption>,
+    /// Key for `encrypted_fields`; only a fingerprint of it is stored
+    pub field_key: Option<String>,
+    #[serde(flatten)]
+    pub settings: RackSettings,
+}
+
+/// How an index organizes its entries
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// Exact-match lookups by value
+    #[default]
+    Hash,
+    /// Entries sorted by value, for range queries and sorting
+    Ordered,
+}
+
+/// JSON options accepted by `create_index`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexOptions {
+    #[serde(rename = "type")]
+    pub kind: IndexKind,
+    /// No two documents may hold the same value; documents without a
+    /// scalar value for the field are not constrained
+    pub unique: bool,
+    /// Fields of a compound index, in key order. The index is then named
+    /// rather than being on a field of its name. Compound indexes are
+    /// always ordered.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+/// A declared index as reported by `list_indexes`
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct IndexInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: IndexKind,
+    pub unique: bool,
+    /// Indexed fields in key order; just the name for single-field indexes
+    pub fields: Vec<String>,
+}
+
+impl RackMeta {
+    /// Metadata of a rack created now
+    pub fn new(options: RackOptions) -> Self {
+        Self {
+            version: BASE_META_VERSION,
+            rack_type: options.rack_type,
+            id_strategy: options.id_strategy,
+            schema: options.schema,
+            indexes: options.indexes,
+            index_options: options.index_options,
+            created_at: now(),
+            settings: options.settings,
+            encrypted_fields: options.encrypted_fields,
+            field_key_check: None,
+        }
+    }
+
+    /// Declared single-field indexes the index can hold, with their kind.
+    /// Randomized encrypted fields are left out, as their ciphertext
+    /// differs on every write.
+    pub fn indexed_fields(&self) -> impl Iterator<Item = (String, IndexKind)> + '_ {
+        self.indexes
+            .iter()
+            .filter(|field| self.encrypted_fields.get(*field) != Some(&FieldEncryption::Randomized))
+            .map(|field| (field.clone(), self.index_options(field)))
+            .filter(|(_, options)| options.fields.is_empty())
+            .map(|(field, options)| (field, options.kind))
+    }
+
+    /// Declared compound indexes with their fields. Those over an encrypted
+    /// field are left out, as they would hold ciphertext out of order.
+    pub fn compound_indexes(&self) -> impl Iterator<Item = (String, Vec<String>)> + '_ {
+        self.index_options
+            .iter()
+            .filter(|(name, options)| !options.fields.is_empty() && self.indexes.contains(name))
+            .filter(|(_, options)| options.fields.iter().all(|field| !self.encrypted_fields.contains_key(field)))
+            .map(|(name, options)| (name.clone(), options.fields.clone()))
+    }
+
+    /// Options of the index on `field`
+    pub fn index_options(&self, field: &str) -> IndexOptions {
+        self.index_options.get(field).cloned().unwrap_or_default()
+    }
+
+    /// Every declared index with its options
+    pub fn index_infos(&self) -> Vec<IndexInfo> {
+        self.indexes
+            .iter()
+            .map(|name| {
+                let options = self.index_options(name);
+                IndexInfo {
+                    name: name.clone(),
+                    kind: options.kind,
+                    unique: options.unique,
+                    fields: match options.fields.is_empty() {
+                        true => vec![name.clone()],
+                        false => options.fields,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Refuse an index named `name` that this rack could not maintain
+    pub fn check_index(&self, name: &str, options: &IndexOptions) -> Result<()> {
+        let fields = match options.fields.as_slice() {
+            [] => vec![name.to_string()],
+            [_] => {
+                return Err(OpenDBSError::InvalidQuery(format!(
+                    "Compound index {} needs at least two fields",
+                    name
+                )))
+            }
+            fields => {
+                if options.unique {
+                    return Err(OpenDBSError::InvalidQuery(format!("Compound index {} cannot be unique", name)));
+                }
+                fields.to_vec()
+            }
+        };
+        if name.is_empty() || name == ID_FIELD {
+            return Err(OpenDBSError::InvalidQuery(format!("Cannot index field `{}`", name)));
+        }
+
+        for (position, field) in fields.iter().enumerate() {
+            if field.is_empty() || field == ID_FIELD || fields[..position].contains(field) {
+                return Err(OpenDBSError::InvalidQuery(format!("Cannot index field `{}`", field)));
+            }
+            match self.encrypted_fields.get(field) {
+                Some(FieldEncryption::Randomized) => {
+                    return Err(OpenDBSError::Encryption(format!(
+                        "Field {} is encrypted with a random nonce and cannot be indexed",
+                        field
+                    )))
+                }
+                Some(FieldEncryption::Deterministic) if !options.fields.is_empty() => {
+                    return Err(OpenDBSError::Encryption(format!(
+                        "Encrypted field {} cannot be part of a compound index",
+                        field
+                    )))
+                }
+                // Ciphertext does not sort like the values it hides
+                Some(FieldEncryption::Deterministic) if options.kind == IndexKind::Ordered => {
+                    return Err(OpenDBSError::Encryption(format!(
+                        "Encrypted field {} cannot have an ordered index",
+                        field
+                    )))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse declared indexes that `check_index` would, and options of
+    /// indexes that are not declared
+    pub fn check_indexes(&self) -> Result<()> {
+        for (position, name) in self.indexes.iter().enumerate() {
+            if self.indexes[..position].contains(name) {
+                return Err(OpenDBSError::InvalidQuery(format!("Index {} is declared twice", name)));
+            }
+            self.check_index(name, &self.index_options(name))?;
+        }
+        match self.index_options.keys().find(|name| !self.indexes.contains(name)) {
+            Some(name) => Err(OpenDBSError::InvalidQuery(format!("Options given for undeclared index {}", name))),
+            None => Ok(()),
+        }
+    }
+
+    /// Metadata for a copy of this rack, created now
+    pub fn duplicate(&self) -> Self {
+        Self {
+            created_at: now(),
+            ..self.clone()
+        }
+    }
+
+    /// Read the metadata of a rack directory. Racks written before
+    /// `rack.meta` existed get defaults plus their old `settings.json`.
+    pub fn load(rack_path: &Path) -> Result<Self> {
+        let meta_path = rack_path.join(META_FILE);
+        if meta_path.exists() {
+            let file = File::open(&meta_path)?;
+            let meta: Self = serde_json::from_reader(BufReader::new(file))?;
+            if meta.version > META_VERSION {
+                return Err(OpenDBSError::Corruption(format!(
+                    "{} uses unsupported metadata version {}",
+                    meta_path.display(),
+                    meta.version
+                )));
+            }
+            return Ok(meta);
+        }
+
+        let settings_path = rack_path.join(LEGACY_SETTINGS_FILE);
+        let settings = if settings_path.exists() {
+            let file = File::open(settings_path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            RackSettings::default()
+        };
+
+        // The directory's age is the best guess for when the rack was created
+        let created_at = fs::metadata(rack_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |age| age.as_secs());
+
+        Ok(Self {
+            created_at,
+            settings,
+            ..Self::new(RackOptions::default())
+        })
+    }
+
+    /// Whether the rack still uses the pre-`rack.meta` layout
+    pub fn needs_upgrade(rack_path: &Path) -> bool {
+        !rack_path.join(META_FILE).exists()
+    }
+
+    /// Oldest format that holds every setting of this rack
+    fn required_version(&self) -> u32 {
+        let uses_v2 = self.id_strategy != IdStrategy::Counter
+            || !self.index_options.is_empty()
+            || !self.encrypted_fields.is_empty()
+            || self.field_key_check.is_some();
+        match uses_v2 {
+            true => META_VERSION,
+            false => BASE_META_VERSION,
+        }
+    }
+
+    /// Atomically write `rack.meta`, replacing any legacy settings file
+    pub fn save(&self, rack_path: &Path) -> Result<()> {
+        let meta = Self {
+            version: self.required_version(),
+            ..self.clone()
+        };
+        let contents = serde_json::to_vec_pretty(&meta)?;
+        durability::write_atomic(&rack_path.join(META_FILE), &contents)?;
+
+        let settings_path = rack_path.join(LEGACY_SETTINGS_FILE);
+        if settings_path.exists() {
+            fs::remove_file(settings_path)?;
+            durability::sync_dir(rack_path)?;
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_settings_are_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(LEGACY_SETTINGS_FILE), r#"{"compression":true}"#).unwrap();
+
+        let meta = RackMeta::load(dir.path()).unwrap();
+        assert!(meta.settings.compression);
+        assert_eq!(meta.rack_type, RackType::Nosql);
+        assert!(RackMeta::needs_upgrade(dir.path()));
+
+        meta.save(dir.path()).unwrap();
+        assert!(!RackMeta::needs_upgrade(dir.path()));
+        assert!(!dir.path().join(LEGACY_SETTINGS_FILE).exists());
+
+        let options: RackOptions =
+            serde_json::from_str(r#"{"type":"sql","schema":{"age":{"type":"number"}},"mmap":true}"#).unwrap();
+        let meta = RackMeta::new(options);
+        meta.save(dir.path()).unwrap();
+
+        let loaded = RackMeta::load(dir.path()).unwrap();
+        assert_eq!(loaded.rack_type, RackType::Sql);
+        assert!(loaded.settings.mmap);
+        assert_eq!(loaded.schema.unwrap()["age"]["type"], "number");
+        assert_eq!(loaded.version, BASE_META_VERSION);
+
+        // Settings older builds would ignore raise the version
+        let options: RackOptions = serde_json::from_str(r#"{"id_strategy":"ulid"}"#).unwrap();
+        RackMeta::new(options).save(dir.path()).unwrap();
+        assert_eq!(RackMeta::load(dir.path()).unwrap().version, META_VERSION);
+    }
+}
@@ -0,0 +1,819 @@
+//! Import of rack files written by the Node engine (`dist/engine.js`).
+//!
+//! Each rack is a single `<rack>.odbs` file: a brotli-compressed msgpack
+//! object `{ signature, timestamp, encoding, hash, data }`, where `hash` is the
+//! SHA-256 of `JSON.stringify(data)`. The Node engine packs with msgpackr, so
+//! objects use its record extension and dates use the msgpack timestamp type.
+
+use crate::error::{OpenDBSError, Result};
+use crate::meta::{RackMeta, RackOptions, RackType};
+use crate::storage::Document;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Extension of rack files written by the Node engine
+pub const ODBS_EXTENSION: &str = "odbs";
+
+/// Suffix appended to a `.odbs` file once it has been imported
+pub const MIGRATED_SUFFIX: &str = ".migrated";
+
+/// Signature of the only `.odbs` format there is
+const SIGNATURE: &str = "OPENDBS_V1";
+
+/// msgpackr extension type of an inline record definition
+const EXT_RECORD: u8 = 0x72;
+
+/// msgpack extension type of timestamps
+const EXT_TIMESTAMP: i8 = -1;
+
+/// A rack read from a `.odbs` file
+#[derive(Debug)]
+pub struct OdbsRack {
+    pub meta: RackMeta,
+    pub documents: Vec<Document>,
+    /// Id the Node engine would have handed out next
+    pub next_id: u64,
+}
+
+/// `.odbs` files of a database directory, with the rack name each belongs to
+pub fn odbs_files(db_path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(db_path)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some(ODBS_EXTENSION) {
+            continue;
+        }
+        if let Some(rack) = path.file_stem().and_then(|s| s.to_str()) {
+            files.push((rack.to_string(), path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Read and verify a `.odbs` file
+pub fn read_odbs(path: &Path) -> Result<OdbsRack> {
+    let content = fs::read(path)?;
+
+    // Like the Node engine, fall back to plain JSON if it is not brotli + msgpack
+    let mut unpacked = Vec::new();
+    let file = match brotli::Decompressor::new(content.as_slice(), 4096).read_to_end(&mut unpacked) {
+        Ok(_) => Unpacker::new(&unpacked).unpack()?,
+        Err(_) => serde_json::from_slice::<JsValue>(&content).map_err(|_| {
+            OpenDBSError::Corruption(format!("{} is neither msgpack+brotli nor JSON", path.display()))
+        })?,
+    };
+
+    if let Some(signature) = file.get("signature") {
+        if !matches!(signature, JsValue::String(s) if s == SIGNATURE) {
+            return Err(OpenDBSError::Corruption(format!(
+                "{} has an unknown signature",
+                path.display()
+            )));
+        }
+    }
+
+    let data = file.get("data").cloned().unwrap_or(JsValue::Undefined);
+    if let Some(JsValue::String(expected)) = file.get("hash") {
+        let actual = hex::encode(Sha256::digest(data.to_json().as_bytes()));
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(OpenDBSError::Corruption(format!(
+                "Integrity check failed for {}",
+                path.display()
+            )));
+        }
+    }
+
+    convert(&data)
+}
+
+/// Map the Node engine's rack object onto documents and `RackMeta`
+fn convert(data: &JsValue) -> Result<OdbsRack> {
+    let rack_type = match data.get("type") {
+        Some(JsValue::String(s)) if s == "sql" => RackType::Sql,
+        _ => RackType::Nosql,
+    };
+    let schema = match data.get("schema") {
+        Some(schema @ JsValue::Object(_)) => Some(schema.to_value()?),
+        _ => None,
+    };
+    let indexes = match data.get("indexedFields") {
+        Some(JsValue::Array(fields)) => fields
+            .iter()
+            .filter_map(|field| match field {
+                JsValue::String(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut meta = RackMeta::new(RackOptions {
+        rack_type,
+        schema,
+        indexes,
+        ..RackOptions::default()
+    });
+    if let Some(created_at) = data.get("createdAt").and_then(JsValue::as_epoch_secs) {
+        meta.created_at = created_at;
+    }
+
+    let mut documents = Vec::new();
+    let mut max_id = 0;
+    if let Some(JsValue::Object(entries)) = data.get("documents") {
+        for (id, doc) in entries {
+            // Very old files stored the document body without a wrapper
+            let body = match doc.get("data") {
+                Some(body @ JsValue::Object(_)) => body,
+                _ => doc,
+            };
+            let created_at = doc
+                .get("createdAt")
+                .and_then(JsValue::as_epoch_secs)
+                .unwrap_or(meta.created_at);
+            let updated_at = doc
+                .get("updatedAt")
+                .and_then(JsValue::as_epoch_secs)
+                .unwrap_or(created_at);
+
+            if let Ok(id_num) = id.parse::<u64>() {
+                max_id = max_id.max(id_num);
+            }
+            documents.push(Document {
+                id: id.clone(),
+                data: body.to_value()?,
+                created_at,
+                updated_at,
+            });
+        }
+    }
+
+    let next_id = match data.get("nextId") {
+        Some(JsValue::Number(n)) if *n >= 1.0 => (*n as u64).max(max_id + 1),
+        _ => max_id + 1,
+    };
+
+    Ok(OdbsRack {
+        meta,
+        documents,
+        next_id,
+    })
+}
+
+/// A value as the Node engine held it in memory. Object key order and the
+/// difference between `undefined` and `null` are kept, so `JSON.stringify`
+/// output, and with it the integrity hash, can be reproduced exactly.
+#[derive(Debug, Clone, PartialEq)]
+enum JsValue {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Milliseconds since the Unix epoch
+    Date(f64),
+    Binary(Vec<u8>),
+    Array(Vec<JsValue>),
+    Object(Vec<(String, JsValue)>),
+}
+
+impl JsValue {
+    fn get(&self, key: &str) -> Option<&JsValue> {
+        match self {
+            JsValue::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Seconds since the epoch of a `Date` or an ISO 8601 string
+    fn as_epoch_secs(&self) -> Option<u64> {
+        let millis = match self {
+            JsValue::Date(millis) => *millis,
+            JsValue::String(s) => parse_iso_millis(s)?,
+            _ => return None,
+        };
+        (millis >= 0.0).then(|| (millis / 1000.0) as u64)
+    }
+
+    /// Convert to a `serde_json::Value` with `JSON.stringify` semantics
+    fn to_value(&self) -> Result<Value> {
+        Ok(serde_json::from_str(&self.to_json())?)
+    }
+
+    /// `JSON.stringify(value)`
+    fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match self {
+            JsValue::Undefined | JsValue::Null => out.push_str("null"),
+            JsValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            JsValue::Number(n) => out.push_str(&js_number(*n)),
+            JsValue::String(s) => write_json_string(s, out),
+            JsValue::Date(millis) => match iso_string(*millis) {
+                Some(iso) => write_json_string(&iso, out),
+                None => out.push_str("null"),
+            },
+            // Node's Buffer#toJSON
+            JsValue::Binary(bytes) => {
+                out.push_str("{\"type\":\"Buffer\",\"data\":[");
+                for (i, byte) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    let _ = write!(out, "{}", byte);
+                }
+                out.push_str("]}");
+            }
+            JsValue::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write_json(out);
+                }
+                out.push(']');
+            }
+            JsValue::Object(entries) => {
+                out.push('{');
+                let mut first = true;
+                for (key, value) in entries {
+                    if *value == JsValue::Undefined {
+                        continue;
+                    }
+                    if !first {
+                        out.push(',');
+                    }
+                    first = false;
+                    write_json_string(key, out);
+                    out.push(':');
+                    value.write_json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    // serde_json escapes exactly the characters JSON.stringify does
+    out.push_str(&serde_json::to_string(s).unwrap_or_default());
+}
+
+/// `Number.prototype.toString()` for a finite or non-finite f64 inside JSON
+fn js_number(n: f64) -> String {
+    if !n.is_finite() {
+        return "null".to_string();
+    }
+    if n == 0.0 {
+        return "0".to_string();
+    }
+    if n < 0.0 {
+        return format!("-{}", js_number(-n));
+    }
+
+    // Shortest round-trip digits and the decimal exponent, as in 1.2345e6
+    let scientific = format!("{:e}", n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    let point = exponent.parse::<i32>().unwrap_or(0) + 1;
+
+    if k <= point && point <= 21 {
+        format!("{}{}", digits, "0".repeat((point - k) as usize))
+    } else if 0 < point && point <= 21 {
+        let (int, frac) = digits.split_at(point as usize);
+        format!("{}.{}", int, frac)
+    } else if -6 < point && point <= 0 {
+        format!("0.{}{}", "0".repeat((-point) as usize), digits)
+    } else {
+        let exp = point - 1;
+        let sign = if exp < 0 { '-' } else { '+' };
+        let (first, rest) = digits.split_at(1);
+        if rest.is_empty() {
+            format!("{}e{}{}", first, sign, exp.abs())
+        } else {
+            format!("{}.{}e{}{}", first, rest, sign, exp.abs())
+        }
+    }
+}
+
+/// `Date.prototype.toISOString()`; `None` for dates JavaScript considers invalid
+fn iso_string(millis: f64) -> Option<String> {
+    if !millis.is_finite() || millis.abs() > 8.64e15 {
+        return None;
+    }
+
+    let millis = millis as i64;
+    let days = millis.div_euclid(86_400_000);
+    let ms_of_day = millis.rem_euclid(86_400_000);
+    let (year, month, day) = civil_from_days(days);
+
+    let year = if (0..=9999).contains(&year) {
+        format!("{:04}", year)
+    } else {
+        format!("{}{:06}", if year < 0 { '-' } else { '+' }, year.abs())
+    };
+
+    Some(format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000
+    ))
+}
+
+/// Milliseconds since the epoch of `YYYY-MM-DDTHH:MM:SS[.sss]Z`
+fn parse_iso_millis(s: &str) -> Option<f64> {
+    let s = s.strip_suffix('Z')?;
+    let (date, time) = s.split_once('T')?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let mut time_parts = hms.splitn(3, ':');
+    let hours: i64 = time_parts.next()?.parse().ok()?;
+    let minutes: i64 = time_parts.next()?.parse().ok()?;
+    let seconds: i64 = time_parts.next()?.parse().ok()?;
+    let millis: i64 = format!("{:0<3}", fraction).get(..3)?.parse().ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    Some((((days * 24 + hours) * 60 + minutes) * 60 + seconds) as f64 * 1000.0 + millis as f64)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// msgpack decoder that understands msgpackr's inline record definitions
+struct Unpacker<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// Key lists of records defined so far, by record id (0x40..0x7f)
+    structures: Vec<Option<Vec<String>>>,
+}
+
+impl<'a> Unpacker<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            structures: vec![None; 64],
+        }
+    }
+
+    fn unpack(mut self) -> Result<JsValue> {
+        let value = self.read()?;
+        if self.position != self.bytes.len() {
+            return Err(Self::invalid("trailing bytes after msgpack value"));
+        }
+        Ok(value)
+    }
+
+    fn read(&mut self) -> Result<JsValue> {
+        let byte = self.take(1)?[0];
+        Ok(match byte {
+            0x00..=0x3f => JsValue::Number(byte as f64),
+            0x40..=0x7f => match self.structures[(byte - 0x40) as usize].clone() {
+                Some(keys) => self.read_record(keys)?,
+                None => JsValue::Number(byte as f64),
+            },
+            0x80..=0x8f => self.read_map((byte & 0x0f) as usize)?,
+            0x90..=0x9f => self.read_array((byte & 0x0f) as usize)?,
+            0xa0..=0xbf => self.read_str((byte & 0x1f) as usize)?,
+            0xc0 => JsValue::Null,
+            0xc2 => JsValue::Bool(false),
+            0xc3 => JsValue::Bool(true),
+            0xc4 => {
+                let len = self.read_uint(1)? as usize;
+                JsValue::Binary(self.take(len)?.to_vec())
+            }
+            0xc5 => {
+                let len = self.read_uint(2)? as usize;
+                JsValue::Binary(self.take(len)?.to_vec())
+            }
+            0xc6 => {
+                let len = self.read_uint(4)? as usize;
+                JsValue::Binary(self.take(len)?.to_vec())
+            }
+            0xc7 => {
+                let len = self.read_uint(1)? as usize;
+                self.read_ext(len)?
+            }
+            0xc8 => {
+                let len = self.read_uint(2)? as usize;
+                self.read_ext(len)?
+            }
+            0xc9 => {
+                let len = self.read_uint(4)? as usize;
+                self.read_ext(len)?
+            }
+            0xca => JsValue::Number(f32::from_bits(self.read_uint(4)? as u32) as f64),
+            0xcb => JsValue::Number(f64::from_bits(self.read_uint(8)?)),
+            0xcc => JsValue::Number(self.read_uint(1)? as f64),
+            0xcd => JsValue::Number(self.read_uint(2)? as f64),
+            0xce => JsValue::Number(self.read_uint(4)? as f64),
+            0xcf => JsValue::Number(self.read_uint(8)? as f64),
+            0xd0 => JsValue::Number(self.read_uint(1)? as u8 as i8 as f64),
+            0xd1 => JsValue::Number(self.read_uint(2)? as u16 as i16 as f64),
+            0xd2 => JsValue::Number(self.read_uint(4)? as u32 as i32 as f64),
+            0xd3 => JsValue::Number(self.read_uint(8)? as i64 as f64),
+            0xd4 => {
+                // Peek for a record definition: fixext 1 of type 'r'
+                if self.bytes.get(self.position) == Some(&EXT_RECORD) {
+                    let id = self.take(2)?[1];
+                    let keys = match self.read()? {
+                        JsValue::Array(keys) => keys.iter().map(Self::key).collect::<Result<Vec<_>>>()?,
+                        _ => return Err(Self::invalid("record definition without a key list")),
+                    };
+                    self.structures[(id & 0x3f) as usize] = Some(keys.clone());
+                    self.read_record(keys)?
+                } else {
+                    self.read_ext(1)?
+                }
+            }
+            0xd5 => self.read_ext(2)?,
+            0xd6 => self.read_ext(4)?,
+            0xd7 => self.read_ext(8)?,
+            0xd8 => self.read_ext(16)?,
+            0xd9 => {
+                let len = self.read_uint(1)? as usize;
+                self.read_str(len)?
+            }
+            0xda => {
+                let len = self.read_uint(2)? as usize;
+                self.read_str(len)?
+            }
+            0xdb => {
+                let len = self.read_uint(4)? as usize;
+                self.read_str(len)?
+            }
+            0xdc => {
+                let len = self.read_uint(2)? as usize;
+                self.read_array(len)?
+            }
+            0xdd => {
+                let len = self.read_uint(4)? as usize;
+                self.read_array(len)?
+            }
+            0xde => {
+                let len = self.read_uint(2)? as usize;
+                self.read_map(len)?
+            }
+            0xdf => {
+                let len = self.read_uint(4)? as usize;
+                self.read_map(len)?
+            }
+            0xe0..=0xff => JsValue::Number(byte as i8 as f64),
+            0xc1 => return Err(Self::invalid("reserved byte 0xc1")),
+        })
+    }
+
+    fn read_record(&mut self, keys: Vec<String>) -> Result<JsValue> {
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            entries.push((key, self.read()?));
+        }
+        Ok(JsValue::Object(entries))
+    }
+
+    fn read_map(&mut self, len: usize) -> Result<JsValue> {
+        let mut entries = Vec::with_capacity(len.min(4096));
+        for _ in 0..len {
+            let key = Self::key(&self.read()?)?;
+            entries.push((key, self.read()?));
+        }
+        Ok(JsValue::Object(entries))
+    }
+
+    fn read_array(&mut self, len: usize) -> Result<JsValue> {
+        let mut items = Vec::with_capacity(len.min(4096));
+        for _ in 0..len {
+            items.push(self.read()?);
+        }
+        Ok(JsValue::Array(items))
+    }
+
+    fn read_str(&mut self, len: usize) -> Result<JsValue> {
+        let bytes = self.take(len)?;
+        let s = std::str::from_utf8(bytes).map_err(|_| Self::invalid("string is not UTF-8"))?;
+        Ok(JsValue::String(s.to_string()))
+    }
+
+    fn read_ext(&mut self, len: usize) -> Result<JsValue> {
+        let ext_type = self.take(1)?[0] as i8;
+        let data = self.take(len)?;
+
+        match (ext_type, len) {
+            // msgpackr's encoding of `undefined`
+            (0, 1) => Ok(JsValue::Undefined),
+            (EXT_TIMESTAMP, 4) => {
+                let secs = u32::from_be_bytes(data.try_into().unwrap());
+                Ok(JsValue::Date(secs as f64 * 1000.0))
+            }
+            (EXT_TIMESTAMP, 8) => {
+                let value = u64::from_be_bytes(data.try_into().unwrap());
+                let nanos = value >> 34;
+                let secs = value & 0x3_ffff_ffff;
+                Ok(JsValue::Date(secs as f64 * 1000.0 + (nanos / 1_000_000) as f64))
+            }
+            (EXT_TIMESTAMP, 12) => {
+                let nanos = u32::from_be_bytes(data[0..4].try_into().unwrap());
+                let secs = i64::from_be_bytes(data[4..12].try_into().unwrap());
+                Ok(JsValue::Date(secs as f64 * 1000.0 + (nanos / 1_000_000) as f64))
+            }
+            _ => Err(Self::invalid(&format!("unsupported msgpack extension type {}", ext_type))),
+        }
+    }
+
+    fn read_uint(&mut self, len: usize) -> Result<u64> {
+        Ok(self.take(len)?.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| Self::invalid("unexpected end of data"))?;
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    /// Object keys are strings in JavaScript, whatever they were packed as
+    fn key(value: &JsValue) -> Result<String> {
+        match value {
+            JsValue::String(s) => Ok(s.clone()),
+            JsValue::Number(n) => Ok(js_number(*n)),
+            _ => Err(Self::invalid("unsupported object key")),
+        }
+    }
+
+    fn invalid(reason: &str) -> OpenDBSError {
+        OpenDBSError::Corruption(format!("Invalid .odbs msgpack data: {}", reason))
+    }
+}
+
+/// Parses plain JSON `.odbs` files while keeping object key order
+impl<'de> Deserialize<'de> for JsValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct JsValueVisitor;
+
+        impl<'de> Visitor<'de> for JsValueVisitor {
+            type Value = JsValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON value")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> std::result::Result<JsValue, E> {
+                Ok(JsValue::Null)
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<JsValue, E> {
+                Ok(JsValue::Bool(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<JsValue, E> {
+                Ok(JsValue::Number(v as f64))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<JsValue, E> {
+                Ok(JsValue::Number(v as f64))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<JsValue, E> {
+                Ok(JsValue::Number(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<JsValue, E> {
+                Ok(JsValue::String(v.to_string()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<JsValue, A::Error> {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(JsValue::Array(items))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<JsValue, A::Error> {
+                let mut entries = Vec::new();
+                while let Some((key, value)) = map.next_entry::<String, JsValue>()? {
+                    entries.push((key, value));
+                }
+                Ok(JsValue::Object(entries))
+            }
+        }
+
+        deserializer.deserialize_any(JsValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EngineOptions, StorageEngine};
+    use std::io::Write;
+
+    #[test]
+    fn test_js_number_formatting() {
+        let cases = [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (42.0, "42"),
+            (-1.5, "-1.5"),
+            (123.456, "123.456"),
+            (0.000001, "0.000001"),
+            (1e-7, "1e-7"),
+            (1e21, "1e+21"),
+            (1.5e300, "1.5e+300"),
+            (123456789012345680000.0, "123456789012345680000"),
+            (f64::NAN, "null"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(js_number(n), expected, "formatting {}", n);
+        }
+    }
+
+    #[test]
+    fn test_iso_dates_roundtrip() {
+        let millis = parse_iso_millis("2024-02-29T13:45:30.250Z").unwrap();
+        assert_eq!(iso_string(millis).unwrap(), "2024-02-29T13:45:30.250Z");
+        assert_eq!(iso_string(0.0).unwrap(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(JsValue::String("1970-01-02T00:00:00Z".into()).as_epoch_secs(), Some(86_400));
+    }
+
+    /// A rack file laid out the way msgpackr packs it, with record
+    /// definitions, a reused record, `undefined` and a timestamp
+    fn odbs_fixture(hash: &str) -> Vec<u8> {
+        let mut packed = Vec::new();
+        let str8 = |out: &mut Vec<u8>, s: &str| {
+            out.push(0xa0 | s.len() as u8);
+            out.extend_from_slice(s.as_bytes());
+        };
+
+        // File record: signature, timestamp, encoding, hash, data
+        packed.extend_from_slice(&[0xd4, 0x72, 0x40, 0x95]);
+        for key in ["signature", "timestamp", "encoding", "hash", "data"] {
+            str8(&mut packed, key);
+        }
+        str8(&mut packed, "OPENDBS_V1");
+        packed.extend_from_slice(&[0xcb]);
+        packed.extend_from_slice(&1.7e12f64.to_bits().to_be_bytes());
+        str8(&mut packed, "msgpack+brotli");
+        packed.extend_from_slice(&[0xd9, hash.len() as u8]);
+        packed.extend_from_slice(hash.as_bytes());
+
+        // Rack record: documents, nextId, indexedFields, type, schema, createdAt
+        packed.extend_from_slice(&[0xd4, 0x72, 0x41, 0x96]);
+        for key in ["documents", "nextId", "indexedFields", "type", "schema", "createdAt"] {
+            str8(&mut packed, key);
+        }
+
+        // documents: { "1": {...}, "4": {...} }
+        packed.extend_from_slice(&[0xd4, 0x72, 0x42, 0x92]);
+        str8(&mut packed, "1");
+        str8(&mut packed, "4");
+        packed.extend_from_slice(&[0xd4, 0x72, 0x43, 0x94]);
+        for key in ["id", "data", "createdAt", "updatedAt"] {
+            str8(&mut packed, key);
+        }
+        str8(&mut packed, "1");
+        packed.extend_from_slice(&[0x82]);
+        str8(&mut packed, "name");
+        str8(&mut packed, "Alice");
+        str8(&mut packed, "score");
+        packed.extend_from_slice(&[0xcb]);
+        packed.extend_from_slice(&0.5f64.to_bits().to_be_bytes());
+        str8(&mut packed, "2024-01-01T00:00:00.000Z");
+        str8(&mut packed, "2024-01-02T00:00:00.000Z");
+        // Second document reuses record 0x43
+        packed.push(0x43);
+        str8(&mut packed, "4");
+        packed.extend_from_slice(&[0x81]);
+        str8(&mut packed, "name");
+        str8(&mut packed, "Bob");
+        str8(&mut packed, "2024-01-03T00:00:00.000Z");
+        str8(&mut packed, "2024-01-03T00:00:00.000Z");
+
+        packed.extend_from_slice(&[0xcc, 0x07]); // nextId: 7
+        packed.extend_from_slice(&[0x91]);
+        str8(&mut packed, "name");
+        str8(&mut packed, "sql");
+        packed.extend_from_slice(&[0xd4, 0x00, 0x00]); // schema: undefined
+        packed.extend_from_slice(&[0xd6, 0xff]); // createdAt: 2024-01-01
+        packed.extend_from_slice(&1_704_067_200u32.to_be_bytes());
+
+        let mut compressed = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 4, 22);
+            writer.write_all(&packed).unwrap();
+        }
+        compressed
+    }
+
+    #[test]
+    fn test_read_odbs_verifies_and_converts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.odbs");
+
+        // What JSON.stringify(data) produced in the Node engine
+        let json = concat!(
+            r#"{"documents":{"1":{"id":"1","data":{"name":"Alice","score":0.5},"#,
+            r#""createdAt":"2024-01-01T00:00:00.000Z","updatedAt":"2024-01-02T00:00:00.000Z"},"#,
+            r#""4":{"id":"4","data":{"name":"Bob"},"createdAt":"2024-01-03T00:00:00.000Z","#,
+            r#""updatedAt":"2024-01-03T00:00:00.000Z"}},"nextId":7,"indexedFields":["name"],"#,
+            r#""type":"sql","createdAt":"2024-01-01T00:00:00.000Z"}"#
+        );
+        let hash = hex::encode(Sha256::digest(json.as_bytes()));
+
+        fs::write(&path, odbs_fixture(&hash)).unwrap();
+        let rack = read_odbs(&path).unwrap();
+        assert_eq!(rack.next_id, 7);
+        assert_eq!(rack.meta.rack_type, RackType::Sql);
+        assert_eq!(rack.meta.indexes, vec!["name".to_string()]);
+        assert_eq!(rack.meta.created_at, 1_704_067_200);
+        assert!(rack.meta.schema.is_none());
+        assert_eq!(rack.documents.len(), 2);
+        assert_eq!(rack.documents[0].data["score"], 0.5);
+        assert_eq!(rack.documents[0].updated_at, 1_704_153_600);
+
+        fs::write(&path, odbs_fixture(&"0".repeat(64))).unwrap();
+        assert!(matches!(read_odbs(&path), Err(OpenDBSError::Corruption(_))));
+    }
+
+    #[test]
+    fn test_node_odbs_files_are_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("app");
+        fs::create_dir_all(&db_path).unwrap();
+
+        // The Node engine's plain JSON fallback of the .odbs format
+        let odbs = r#"{"signature":"OPENDBS_V1","data":{"documents":{
+            "2":{"id":"2","data":{"name":"Alice"},"createdAt":"2024-01-01T00:00:00.000Z","updatedAt":"2024-01-01T00:00:00.000Z"}},
+            "nextId":5,"indexedFields":["name"],"type":"sql","createdAt":"2024-01-01T00:00:00.000Z"}}"#;
+        fs::write(db_path.join("users.odbs"), odbs).unwrap();
+        fs::write(db_path.join("broken.odbs"), "not an odbs file").unwrap();
+
+        let root = dir.path().to_str().unwrap();
+        let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert!(!db_path.join("users.odbs").exists());
+        assert!(db_path.join("users.odbs.migrated").exists());
+        let report: Value = serde_json::from_str(&engine.get_load_report().unwrap()).unwrap();
+        assert_eq!(report.as_array().unwrap().len(), 1);
+        assert_eq!(report[0]["rack"], "broken");
+        assert!(!db_path.join("broken.odbs").exists());
+
+        let info: Value = serde_json::from_str(&engine.get_rack_info("app", "users").unwrap()).unwrap();
+        assert_eq!(info["type"], "sql");
+        assert_eq!(info["indexes"][0], "name");
+        assert_eq!(info["created_at"], 1_704_067_200);
+
+        assert_eq!(engine.find("app", "users", r#"{"name":"Alice"}"#, None, None).unwrap().len(), 1);
+        assert_eq!(engine.insert("app", "users", r#"{"name":"Bob"}"#, None).unwrap(), "5");
+    }
+}
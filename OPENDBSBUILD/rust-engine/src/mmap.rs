@@ -0,0 +1,86 @@
+use crate::error::Result;
+use crate::segment::{self, RecordLocation, SegmentRecord};
+use memmap2::Mmap;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Read-only mappings of a rack's segment files.
+///
+/// Records are decoded straight from the mapping, so a rack only has to keep
+/// the location of each live document in memory. Mappings are created on
+/// first use and extended when the active segment has grown past them.
+#[derive(Debug)]
+pub struct MappedSegments {
+    dir: PathBuf,
+    maps: Mutex<HashMap<u64, Arc<Mmap>>>,
+    /// Readers hold this while looking up a location and decoding it;
+    /// compaction takes it exclusively to swap locations and mappings
+    swap: RwLock<()>,
+}
+
+impl MappedSegments {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            maps: Mutex::new(HashMap::new()),
+            swap: RwLock::new(()),
+        }
+    }
+
+    /// Keep locations and mappings consistent for the guard's lifetime
+    pub fn pin(&self) -> RwLockReadGuard<'_, ()> {
+        self.swap.read()
+    }
+
+    /// Decode the record at `location`
+    pub fn read(&self, location: RecordLocation) -> Result<SegmentRecord> {
+        let map = self.map(location.segment, location.offset + location.len)?;
+        segment::decode_at(&map, location)
+    }
+
+    /// Map every segment up to and including `number`, so readers keep
+    /// seeing the current files after compaction replaces them
+    pub fn preload(&self, number: u64) -> Result<()> {
+        for (seg_number, _) in segment::list_segments(&self.dir)? {
+            if seg_number <= number {
+                self.map(seg_number, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Swap in a compacted segment `base`: `update` repoints locations while
+    /// readers are held off, then mappings of replaced segments are dropped
+    pub fn replace<F: FnOnce()>(&self, base: u64, update: F) {
+        let _swap = self.swap.write();
+        update();
+        self.maps.lock().retain(|&number, _| number > base);
+    }
+
+    /// Drop every mapping
+    pub fn clear(&self) {
+        let _swap = self.swap.write();
+        self.maps.lock().clear();
+    }
+
+    /// Mapping of segment `number` covering at least `min_len` bytes
+    fn map(&self, number: u64, min_len: u64) -> Result<Arc<Mmap>> {
+        let mut maps = self.maps.lock();
+        if let Some(map) = maps.get(&number) {
+            if map.len() as u64 >= min_len {
+                return Ok(Arc::clone(map));
+            }
+        }
+
+        let file = File::open(segment::segment_path(&self.dir, number))?;
+        // SAFETY: segments are append-only while a rack is loaded. Records
+        // are never rewritten in place and replaced files are swapped in by
+        // rename, so mapped bytes do not change underneath readers.
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        maps.insert(number, Arc::clone(&map));
+        Ok(map)
+    }
+}
@@ -0,0 +1,407 @@
+//! Operation log for point-in-time recovery.
+//!
+//! Unlike the WAL, which is truncated at every checkpoint, the operation log
+//! keeps every mutation with a sequence number and a timestamp until no
+//! snapshot needs it any more. A database can then be rebuilt as of any
+//! instant from the newest snapshot before it plus the operations logged
+//! after that snapshot. Snapshots taken by the engine are recorded in a
+//! catalog next to the log, together with the last sequence number they
+//! contain.
+
+use crate::crypto::Keyring;
+use crate::durability::{self, Durability};
+use crate::error::{OpenDBSError, Result};
+use crate::meta::{IndexOptions, RackMeta};
+use crate::snapshot::SnapshotManifest;
+use crate::storage::Document;
+use crate::wal::{self, WalOp};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Directory inside the engine root holding the log and snapshot catalog
+pub const OPLOG_DIR: &str = "_oplog";
+
+/// Extension of log segment files, named after their first sequence number
+const OPLOG_EXTENSION: &str = "oplog";
+
+/// File listing the snapshots recovery can start from
+const CATALOG_FILE: &str = "snapshots.json";
+
+/// Size after which the log continues in a new segment file
+const MAX_OPLOG_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A logged operation on a database
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum OplogOp {
+    Insert { rack: String, document: Document },
+    Update { rack: String, document: Document },
+    Delete { rack: String, id: String },
+    CreateRack { rack: String, meta: RackMeta },
+    DropRack { rack: String },
+    ClearRack { rack: String },
+    RenameRack { rack: String, new_name: String },
+    DuplicateRack { source_database: String, source_rack: String, rack: String },
+    CreateIndex {
+        rack: String,
+        field: String,
+        #[serde(default)]
+        options: IndexOptions,
+    },
+    DropIndex { rack: String, field: String },
+    CreateDatabase,
+    DropDatabase,
+    /// The database was replaced wholesale from a snapshot
+    Restore,
+}
+
+impl OplogOp {
+    /// The operation matching a document mutation written to the WAL
+    pub fn from_wal(rack: &str, op: &WalOp) -> Self {
+        let rack = rack.to_string();
+        match op.clone() {
+            WalOp::Insert { document } => OplogOp::Insert { rack, document },
+            WalOp::Update { document } => OplogOp::Update { rack, document },
+            WalOp::Delete { id } => OplogOp::Delete { rack, id },
+        }
+    }
+}
+
+/// An operation with its position in the log
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OplogRecord {
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch; never decreases along the log
+    pub timestamp_ms: u64,
+    pub database: String,
+    #[serde(flatten)]
+    pub op: OplogOp,
+}
+
+/// A snapshot recovery can start from
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CatalogEntry {
+    pub path: PathBuf,
+    pub taken_at_ms: u64,
+    /// Last sequence number whose effects the snapshot contains
+    pub seq: u64,
+    pub databases: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ActiveSegment {
+    file: File,
+    len: u64,
+    last_seq: u64,
+    last_timestamp_ms: u64,
+}
+
+/// Append-only, segmented log of every mutation
+#[derive(Debug)]
+pub struct OperationLog {
+    dir: PathBuf,
+    active: Mutex<ActiveSegment>,
+    catalog: Mutex<Vec<CatalogEntry>>,
+    durability: Durability,
+    cipher: Option<Arc<Keyring>>,
+    /// Records were appended since the last fsync
+    unsynced: AtomicBool,
+}
+
+impl OperationLog {
+    /// Open (or start) the log in `root`, dropping a torn tail
+    pub fn open(root: &Path, durability: Durability, cipher: Option<Arc<Keyring>>) -> Result<Self> {
+        let dir = root.join(OPLOG_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let catalog_path = dir.join(CATALOG_FILE);
+        let catalog = if catalog_path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&catalog_path)?))?
+        } else {
+            Vec::new()
+        };
+
+        let (path, first_seq) = match list_segments(&dir)?.pop() {
+            Some((first_seq, path)) => (path, first_seq),
+            None => (segment_path(&dir, 1), 1),
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let (records, valid_len) = wal::read_frames::<OplogRecord>(&mut file, cipher.as_deref())?;
+        if valid_len < file.metadata()?.len() {
+            tracing::warn!(
+                "Discarding torn tail of operation log {} at offset {}",
+                path.display(),
+                valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let last = records.last();
+        let active = ActiveSegment {
+            file,
+            len: valid_len,
+            last_seq: last.map_or(first_seq - 1, |record| record.seq),
+            last_timestamp_ms: last.map_or(0, |record| record.timestamp_ms),
+        };
+
+        Ok(Self {
+            dir,
+            active: Mutex::new(active),
+            catalog: Mutex::new(catalog),
+            durability,
+            cipher,
+            unsynced: AtomicBool::new(false),
+        })
+    }
+
+    /// Log an operation on `database`, returning its sequence number
+    pub fn append(&self, database: &str, op: OplogOp) -> Result<u64> {
+        let mut active = self.active.lock();
+        if active.len >= MAX_OPLOG_SEGMENT_BYTES {
+            active.file.sync_all()?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(segment_path(&self.dir, active.last_seq + 1))?;
+            durability::sync_dir(&self.dir)?;
+            active.file = file;
+            active.len = 0;
+        }
+
+        let record = OplogRecord {
+            seq: active.last_seq + 1,
+            timestamp_ms: now_ms().max(active.last_timestamp_ms),
+            database: database.to_string(),
+            op,
+        };
+        let frame = wal::encode_frame(&record, self.cipher.as_deref())?;
+        active.file.write_all(&frame)?;
+        match self.durability {
+            Durability::Always => active.file.sync_data()?,
+            Durability::BatchedMs(_) => self.unsynced.store(true, Ordering::SeqCst),
+            Durability::Never => {}
+        }
+
+        active.len += frame.len() as u64;
+        active.last_seq = record.seq;
+        active.last_timestamp_ms = record.timestamp_ms;
+        Ok(record.seq)
+    }
+
+    /// Flush records appended since the last fsync
+    pub fn sync(&self) -> Result<()> {
+        if self.unsynced.swap(false, Ordering::SeqCst) {
+            self.active.lock().file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Record a snapshot containing every operation logged so far, then
+    /// drop log segments that no remaining snapshot needs
+    pub fn register_snapshot(&self, path: &Path, databases: &[String]) -> Result<()> {
+        let seq = self.active.lock().last_seq;
+        let mut catalog = self.catalog.lock();
+
+        // Forget snapshots that were deleted
+        catalog.retain(|entry| entry.path.exists());
+        catalog.push(CatalogEntry {
+            path: path.to_path_buf(),
+            taken_at_ms: now_ms(),
+            seq,
+            databases: databases.to_vec(),
+        });
+        durability::write_atomic(&self.dir.join(CATALOG_FILE), &serde_json::to_vec_pretty(&*catalog)?)?;
+
+        // Every database needs the log from its oldest snapshot on
+        let keep_from = catalog.iter().map(|entry| entry.seq).min().unwrap_or(0);
+        let segments = list_segments(&self.dir)?;
+        for pair in segments.windows(2) {
+            let (_, path) = &pair[0];
+            let (next_first_seq, _) = pair[1];
+            if next_first_seq <= keep_from + 1 {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The newest complete snapshot of `database` taken at or before `timestamp_ms`
+    pub fn snapshot_before(&self, database: &str, timestamp_ms: u64) -> Option<(CatalogEntry, SnapshotManifest)> {
+        let catalog = self.catalog.lock();
+        catalog
+            .iter()
+            .filter(|entry| entry.taken_at_ms <= timestamp_ms && entry.databases.iter().any(|db| db == database))
+            .rev()
+            .find_map(|entry| {
+                let manifest = SnapshotManifest::load(&entry.path).ok()?;
+                Some((entry.clone(), manifest))
+            })
+    }
+
+    /// Operations on `database` after sequence number `after_seq` and no
+    /// later than `until_ms`, oldest first
+    pub fn records(&self, database: &str, after_seq: u64, until_ms: u64) -> Result<Vec<OplogRecord>> {
+        // Holding the active segment keeps appends out while reading
+        let _active = self.active.lock();
+        let segments = list_segments(&self.dir)?;
+
+        if let Some((first_seq, _)) = segments.first() {
+            if *first_seq > after_seq + 1 {
+                return Err(OpenDBSError::Corruption(format!(
+                    "Operation log starts at {} but records after {} are needed",
+                    first_seq, after_seq
+                )));
+            }
+        }
+
+        let mut records = Vec::new();
+        for (position, (_, path)) in segments.iter().enumerate() {
+            // Skip segments that end before the range starts
+            if segments
+                .get(position + 1)
+                .is_some_and(|(next_first_seq, _)| *next_first_seq <= after_seq + 1)
+            {
+                continue;
+            }
+
+            let (segment_records, _) = wal::read_frames::<OplogRecord>(&mut File::open(path)?, self.cipher.as_deref())?;
+            for record in segment_records {
+                if record.timestamp_ms > until_ms {
+                    return Ok(records);
+                }
+                if record.seq > after_seq && record.database == database {
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", first_seq, OPLOG_EXTENSION))
+}
+
+/// Log segments in order, with the first sequence number each holds
+fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some(OPLOG_EXTENSION) {
+            continue;
+        }
+        if let Some(first_seq) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            segments.push((first_seq, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::app_engine;
+    use crate::storage::{EngineOptions, StorageEngine};
+    use std::time::Duration;
+
+    #[test]
+    fn test_records_survive_reopen_and_roll() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let log = OperationLog::open(dir.path(), Durability::Always, None).unwrap();
+            assert_eq!(log.append("app", OplogOp::CreateDatabase).unwrap(), 1);
+            log.append("other", OplogOp::CreateDatabase).unwrap();
+            // Force the next record into a new segment
+            log.active.lock().len = MAX_OPLOG_SEGMENT_BYTES;
+            log.append("app", OplogOp::DropDatabase).unwrap();
+        }
+
+        let log = OperationLog::open(dir.path(), Durability::Always, None).unwrap();
+        assert_eq!(log.append("app", OplogOp::CreateDatabase).unwrap(), 4);
+        assert_eq!(list_segments(&log.dir).unwrap().len(), 2);
+
+        let records = log.records("app", 1, u64::MAX).unwrap();
+        let seqs: Vec<u64> = records.iter().map(|record| record.seq).collect();
+        assert_eq!(seqs, vec![3, 4]);
+        assert!(records.windows(2).all(|pair| pair[0].timestamp_ms <= pair[1].timestamp_ms));
+    }
+
+    #[test]
+    fn test_point_in_time_restore() {
+        let options = EngineOptions {
+            oplog: true,
+            ..EngineOptions::default()
+        };
+        let (dir, mut engine) = app_engine(options.clone());
+        let backups = tempfile::tempdir().unwrap();
+        let backup = backups.path().join("backup");
+        engine.create_rack("app", "users", None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+
+        let before_snapshot = now_ms();
+        std::thread::sleep(Duration::from_millis(5));
+        engine.snapshot(backup.to_str().unwrap(), None).unwrap().finish().unwrap();
+        engine.insert("app", "users", r#"{"name":"Bob"}"#, None).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let instant = now_ms();
+        std::thread::sleep(Duration::from_millis(5));
+
+        engine.insert("app", "users", r#"{"name":"Carol"}"#, None).unwrap();
+        engine.delete("app", "users", "1").unwrap();
+        engine.create_rack("app", "orders", None).unwrap();
+        engine.rename_rack("app", "users", "people").unwrap();
+        drop(engine);
+
+        // The log outlives restarts
+        let mut engine = StorageEngine::new(dir.path().to_str().unwrap(), options).unwrap();
+        assert!(engine.restore_to("app", instant, "app_then").unwrap());
+        let names = |engine: &StorageEngine, db: &str, rack: &str| -> Vec<String> {
+            let mut names: Vec<String> = engine
+                .find(db, rack, "{}", None, None)
+                .unwrap()
+                .iter()
+                .map(|doc| serde_json::from_str::<Document>(doc).unwrap().data["name"].to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&engine, "app_then", "users"), vec![r#""Alice""#, r#""Bob""#]);
+        assert!(engine.find("app_then", "orders", "{}", None, None).is_err());
+
+        assert!(engine.restore_to("app", now_ms(), "app_now").unwrap());
+        assert_eq!(names(&engine, "app_now", "people"), vec![r#""Bob""#, r#""Carol""#]);
+        assert!(engine.find("app_now", "orders", "{}", None, None).unwrap().is_empty());
+
+        assert!(!engine.restore_to("app", instant, "app_then").unwrap());
+        assert!(engine.restore_to("app", before_snapshot, "app_early").is_err());
+        assert_eq!(names(&engine, "app", "people"), vec![r#""Bob""#, r#""Carol""#]);
+    }
+}
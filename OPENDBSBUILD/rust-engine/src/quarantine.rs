@@ -0,0 +1,198 @@
+use crate::durability::sync_dir;
+use crate::error::Result;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory inside the engine root that unreadable files are moved to
+pub const QUARANTINE_DIR: &str = "_quarantine";
+
+/// Something that was skipped while loading, and why
+#[derive(Debug, Serialize, Clone)]
+pub struct SkippedEntry {
+    pub database: String,
+    pub rack: String,
+    pub path: String,
+    /// Where the unreadable data was moved or copied to, if anywhere
+    pub quarantined_to: Option<String>,
+    pub reason: String,
+}
+
+/// Collects data that could not be loaded and sets it aside for inspection
+#[derive(Debug)]
+pub struct Quarantine {
+    dir: PathBuf,
+    skipped: Mutex<Vec<SkippedEntry>>,
+    /// Only report skipped data, leaving every file where it is
+    read_only: bool,
+}
+
+impl Quarantine {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            dir: root.join(QUARANTINE_DIR),
+            skipped: Mutex::new(Vec::new()),
+            read_only: false,
+        }
+    }
+
+    /// A quarantine for read-only engines, which notes what was skipped
+    /// but never moves or copies anything
+    pub fn read_only(root: &Path) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(root)
+        }
+    }
+
+    /// Move an unreadable file out of the rack directory
+    pub fn move_file(&self, database: &str, rack: &str, path: &Path, reason: &str) {
+        if self.read_only {
+            return self.note(database, rack, path, reason);
+        }
+        let target = self.target_path(database, rack, path);
+        let moved = target.and_then(|target| {
+            fs::rename(path, &target)?;
+            if let Some(parent) = path.parent() {
+                sync_dir(parent)?;
+            }
+            Ok(target)
+        });
+
+        self.record(database, rack, path, moved, reason);
+    }
+
+    /// Save a copy of unreadable bytes that stay in place (e.g. a damaged
+    /// segment tail that is about to be cut off)
+    pub fn save_bytes(&self, database: &str, rack: &str, path: &Path, suffix: &str, bytes: &[u8], reason: &str) {
+        if self.read_only {
+            return self.note(database, rack, path, reason);
+        }
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(suffix);
+        let copied = self
+            .target_path(database, rack, Path::new(&name))
+            .and_then(|target| {
+                fs::write(&target, bytes)?;
+                Ok(target)
+            });
+
+        self.record(database, rack, path, copied, reason);
+    }
+
+    /// Note something that was skipped without setting any data aside
+    pub fn note(&self, database: &str, rack: &str, path: &Path, reason: &str) {
+        tracing::warn!("Skipping {}: {}", path.display(), reason);
+        self.skipped.lock().push(SkippedEntry {
+            database: database.to_string(),
+            rack: rack.to_string(),
+            path: path.display().to_string(),
+            quarantined_to: None,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Everything skipped so far
+    pub fn report(&self) -> Vec<SkippedEntry> {
+        self.skipped.lock().clone()
+    }
+
+    fn record(&self, database: &str, rack: &str, path: &Path, target: Result<PathBuf>, reason: &str) {
+        let quarantined_to = match target {
+            Ok(target) => {
+                tracing::warn!(
+                    "Quarantined {} to {}: {}",
+                    path.display(),
+                    target.display(),
+                    reason
+                );
+                Some(target.display().to_string())
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Could not quarantine {} ({}): {}",
+                    path.display(),
+                    reason,
+                    e
+                );
+                None
+            }
+        };
+
+        self.skipped.lock().push(SkippedEntry {
+            database: database.to_string(),
+            rack: rack.to_string(),
+            path: path.display().to_string(),
+            quarantined_to,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Free path under `_quarantine/<database>/<rack>/` for a file
+    fn target_path(&self, database: &str, rack: &str, path: &Path) -> Result<PathBuf> {
+        let dir = self.dir.join(database).join(rack);
+        fs::create_dir_all(&dir)?;
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut target = dir.join(&name);
+        let mut attempt = 1;
+        while target.exists() {
+            target = dir.join(format!("{}.{}", name, attempt));
+            attempt += 1;
+        }
+
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment;
+    use crate::storage::tests::app_engine;
+    use crate::storage::{EngineOptions, StorageEngine};
+    use serde_json::Value;
+
+    #[test]
+    fn test_move_file_never_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine = Quarantine::new(dir.path());
+
+        for _ in 0..2 {
+            let bad = dir.path().join("1.dbs");
+            fs::write(&bad, b"{oops").unwrap();
+            quarantine.move_file("app", "users", &bad, "invalid JSON");
+            assert!(!bad.exists());
+        }
+
+        let report = quarantine.report();
+        assert_eq!(report.len(), 2);
+        assert_ne!(report[0].quarantined_to, report[1].quarantined_to);
+        assert!(Path::new(report[1].quarantined_to.as_ref().unwrap()).exists());
+    }
+
+    #[test]
+    fn test_corrupt_files_are_quarantined() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        let rack_path = dir.path().join("app").join("users");
+
+        engine.create_rack("app", "users", None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        fs::write(rack_path.join("5.dbs"), b"{\"id\":\"5\",\"da").unwrap();
+        fs::write(segment::segment_path(&rack_path, 7), b"NOTASEGMENT").unwrap();
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 1);
+        assert!(!rack_path.join("5.dbs").exists());
+
+        let report: Vec<Value> = serde_json::from_str(&engine.get_load_report().unwrap()).unwrap();
+        assert_eq!(report.len(), 2);
+        assert!(dir.path().join("_quarantine/app/users/5.dbs").exists());
+        assert!(!engine.databases.contains_key("_quarantine"));
+    }
+}
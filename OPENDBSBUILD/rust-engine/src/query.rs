@@ -1,7 +1,57 @@
+use crate::index::ordered_key;
+use serde::Deserialize;
 use serde_json::{Map, Value};
+use std::cmp::Ordering;
 
 pub struct QueryEngine;
 
+/// A comparison an ordered index can answer with a range scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeOp {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl RangeOp {
+    fn from_operator(op: &str) -> Option<Self> {
+        match op {
+            "$gt" => Some(Self::Gt),
+            "$gte" => Some(Self::Gte),
+            "$lt" => Some(Self::Lt),
+            "$lte" => Some(Self::Lte),
+            _ => None,
+        }
+    }
+}
+
+/// JSON options accepted by `find`, as in the Node engine:
+/// `{"sort": {"field": "age", "order": "desc"}, "skip": 20, "limit": 10}`
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct FindOptions {
+    pub sort: Option<SortSpec>,
+    pub skip: usize,
+    pub limit: Option<usize>,
+}
+
+/// Field to sort `find` results by
+#[derive(Debug, Deserialize)]
+pub struct SortSpec {
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
 impl QueryEngine {
     pub fn new() -> Self {
         Self
@@ -41,6 +91,54 @@ impl QueryEngine {
         }
     }
 
+    /// Top-level `field: value` and `field: {"$eq": value}` predicates
+    /// with scalar values, which an index can answer
+    pub fn equality_predicates<'a>(&self, query: &'a Value) -> Vec<(&'a str, &'a Value)> {
+        let Value::Object(query_obj) = query else {
+            return Vec::new();
+        };
+        if self.is_operator_object(query_obj) {
+            return Vec::new();
+        }
+
+        query_obj
+            .iter()
+            .filter_map(|(field, query_val)| {
+                let value = match query_val {
+                    Value::Object(ops) if ops.len() == 1 => ops.get("$eq")?,
+                    value => value,
+                };
+                matches!(value, Value::String(_) | Value::Number(_) | Value::Bool(_))
+                    .then_some((field.as_str(), value))
+            })
+            .collect()
+    }
+
+    /// Top-level `field: {"$gt": value, ...}` comparisons, grouped by field
+    pub fn range_predicates<'a>(&self, query: &'a Value) -> Vec<(&'a str, Vec<(RangeOp, &'a Value)>)> {
+        let Value::Object(query_obj) = query else {
+            return Vec::new();
+        };
+        if self.is_operator_object(query_obj) {
+            return Vec::new();
+        }
+
+        query_obj
+            .iter()
+            .filter_map(|(field, query_val)| {
+                let Value::Object(ops) = query_val else {
+                    return None;
+                };
+                // Every operator must hold, so the other ones only narrow further
+                let ranges: Vec<_> = ops
+                    .iter()
+                    .filter_map(|(op, value)| Some((RangeOp::from_operator(op)?, value)))
+                    .collect();
+                (!ranges.is_empty() && self.is_operator_object(ops)).then_some((field.as_str(), ranges))
+            })
+            .collect()
+    }
+
     fn is_operator_object(&self, obj: &Map<String, Value>) -> bool {
         obj.keys().any(|k| k.starts_with('$'))
     }
@@ -50,10 +148,10 @@ impl QueryEngine {
             let result = match op.as_str() {
                 "$eq" => data == target,
                 "$ne" => data != target,
-                "$gt" => self.compare(data, target, |a, b| a > b),
-                "$gte" => self.compare(data, target, |a, b| a >= b),
-                "$lt" => self.compare(data, target, |a, b| a < b),
-                "$lte" => self.compare(data, target, |a, b| a <= b),
+                "$gt" => self.compare(data, target, Ordering::is_gt),
+                "$gte" => self.compare(data, target, Ordering::is_ge),
+                "$lt" => self.compare(data, target, Ordering::is_lt),
+                "$lte" => self.compare(data, target, Ordering::is_le),
                 "$in" => {
                     if let Value::Array(arr) = target {
                         arr.contains(data)
@@ -78,20 +176,131 @@ impl QueryEngine {
         true
     }
 
+    /// Compare two values of the same type. Strings compare as strings,
+    /// even ones that look like dates; other scalars compare the way
+    /// ordered indexes sort them. Values of different types never compare.
     fn compare<F>(&self, a: &Value, b: &Value, op: F) -> bool
     where
-        F: Fn(f64, f64) -> bool,
+        F: Fn(Ordering) -> bool,
     {
-        match (a, b) {
-            (Value::Number(n1), Value::Number(n2)) => {
-                if let (Some(f1), Some(f2)) = (n1.as_f64(), n2.as_f64()) {
-                    op(f1, f2)
-                } else {
-                    false
-                }
-            }
-            // Add string comparison support if needed, currently only numbers
+        if let (Value::String(a), Value::String(b)) = (a, b) {
+            return op(a.cmp(b));
+        }
+        match (ordered_key(a), ordered_key(b)) {
+            (Some(a), Some(b)) if a[0] == b[0] => op(a.cmp(&b)),
             _ => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::IndexKind;
+    use crate::storage::tests::app_engine;
+    use crate::storage::{Document, EngineOptions, StorageEngine};
+
+    #[test]
+    fn test_find_narrows_equality_predicates_with_the_index() {
+        let (_dir, mut engine) = app_engine(EngineOptions::default());
+        engine.create_rack("app", "orders", Some(r#"{"indexes":["status","tier"]}"#)).unwrap();
+        for n in 0..50 {
+            let status = if n % 10 == 0 { "open" } else { "closed" };
+            let order = serde_json::json!({ "n": n, "status": status, "tier": n % 2 });
+            engine.insert("app", "orders", &order.to_string(), None).unwrap();
+        }
+        engine.insert("app", "orders", r#"{"status":"open","tier":"1"}"#, None).unwrap();
+
+        let rack = engine.open_rack("app", "orders").unwrap();
+        let query = serde_json::json!({ "status": "open", "tier": { "$eq": 0 }, "n": { "$gt": 15 } });
+        let predicates = QueryEngine::new().equality_predicates(&query);
+        assert_eq!(predicates.len(), 2);
+        assert_eq!(rack.candidates(&predicates, &[], None).unwrap().unwrap().len(), 5);
+        assert!(rack.candidates(&[], &[], None).unwrap().is_none());
+        drop(rack);
+
+        let found = engine.find("app", "orders", &query.to_string(), None, None).unwrap();
+        assert_eq!(found.len(), 3);
+        // "1" and 1 share an index entry but only one of them matches
+        assert_eq!(engine.find("app", "orders", r#"{"status":"open","tier":"1"}"#, None, None).unwrap().len(), 1);
+        assert_eq!(engine.find("app", "orders", r#"{"tier":1}"#, None, None).unwrap().len(), 25);
+        assert!(engine.find("app", "orders", r#"{"status":"lost"}"#, None, None).unwrap().is_empty());
+
+        // Updates and deletes move documents between index entries
+        let open = engine.find("app", "orders", r#"{"status":"open","n":0}"#, None, None).unwrap();
+        let first: Document = serde_json::from_str(&open[0]).unwrap();
+        assert!(engine.update("app", "orders", &first.id, r#"{"n":0,"status":"closed"}"#, None).unwrap());
+        assert!(engine.delete("app", "orders", "11").unwrap());
+        assert_eq!(engine.find("app", "orders", r#"{"status":"open"}"#, None, None).unwrap().len(), 4);
+        assert_eq!(engine.find("app", "orders", r#"{"status":"closed"}"#, None, None).unwrap().len(), 46);
+    }
+
+    #[test]
+    fn test_ordered_index_ranges_and_sorting() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine.create_rack("app", "events", None).unwrap();
+        for n in 0..20 {
+            let day = format!("2024-01-{:02}T00:00:00Z", n + 1);
+            engine
+                .insert("app", "events", &serde_json::json!({"n": n, "day": day, "kind": n % 2}).to_string(), None)
+                .unwrap();
+        }
+        engine.insert("app", "events", r#"{"kind":0}"#, None).unwrap();
+        assert!(engine.create_index("app", "events", "n", Some(r#"{"type":"ordered"}"#)).unwrap());
+        assert!(engine.create_index("app", "events", "day", Some(r#"{"type":"ordered"}"#)).unwrap());
+        assert!(engine.create_index("app", "events", "kind", Some(r#"{"type":"btree"}"#)).is_err());
+
+        let values = |found: Vec<String>, field: &str| -> Vec<Value> {
+            found
+                .iter()
+                .map(|doc| serde_json::from_str::<Value>(doc).unwrap()["data"][field].clone())
+                .collect()
+        };
+        let rack = engine.open_rack("app", "events").unwrap();
+        let query = serde_json::json!({"n": {"$gte": 5, "$lt": 8}});
+        let ranges = QueryEngine::new().range_predicates(&query);
+        assert_eq!(rack.candidates(&[], &ranges, None).unwrap().unwrap().len(), 3);
+        drop(rack);
+
+        let found = engine.find("app", "events", &query.to_string(), None, None).unwrap();
+        assert_eq!(found.len(), 3);
+        let found = engine
+            .find("app", "events", r#"{"day":{"$gt":"2024-01-18T00:00:00Z"}}"#, None, None)
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        // Strings compare as strings, even where the index orders dates by instant
+        let found = engine.find("app", "events", r#"{"day":{"$gt":"2024"}}"#, None, None).unwrap();
+        assert_eq!(found.len(), 20);
+        let found = engine.find("app", "events", r#"{"day":{"$lt":"2024-01-02"}}"#, None, None).unwrap();
+        assert_eq!(found.len(), 1);
+
+        // Sorting walks the index; documents without the field come last
+        let options = r#"{"sort":{"field":"n","order":"desc"},"limit":3}"#;
+        let found = engine.find("app", "events", "{}", None, Some(options)).unwrap();
+        assert_eq!(values(found, "n"), vec![serde_json::json!(19), serde_json::json!(18), serde_json::json!(17)]);
+        let options = r#"{"sort":{"field":"n"},"skip":18}"#;
+        let found = engine.find("app", "events", "{}", None, Some(options)).unwrap();
+        assert_eq!(values(found, "n"), vec![serde_json::json!(18), serde_json::json!(19), Value::Null]);
+        let options = r#"{"sort":{"field":"n"},"limit":2}"#;
+        let found = engine.find("app", "events", r#"{"kind":1,"n":{"$gt":10}}"#, None, Some(options)).unwrap();
+        assert_eq!(values(found, "n"), vec![serde_json::json!(11), serde_json::json!(13)]);
+
+        // Without an ordered index the matches are sorted the same way
+        let options = r#"{"sort":{"field":"kind","order":"desc"},"limit":1}"#;
+        let found = engine.find("app", "events", "{}", None, Some(options)).unwrap();
+        assert_eq!(values(found, "kind"), vec![serde_json::json!(1)]);
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        let rack = engine.open_rack("app", "events").unwrap();
+        assert!(!rack.index_dirty.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(rack.index.kind("day"), Some(IndexKind::Ordered));
+        drop(rack);
+        let options = r#"{"sort":{"field":"day"},"limit":1}"#;
+        let found = engine.find("app", "events", "{}", None, Some(options)).unwrap();
+        assert_eq!(values(found, "day"), vec![serde_json::json!("2024-01-01T00:00:00Z")]);
+        assert!(engine.verify(None, false).unwrap().issues.is_empty());
+    }
+}
@@ -0,0 +1,599 @@
+use crate::compression;
+use crate::crypto::Keyring;
+use crate::durability::{sync_dir, AtomicFile};
+use crate::error::{OpenDBSError, Result};
+use crate::storage::Document;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC_NUMBER: &[u8; 5] = b"ODBDS";
+const VERSION: u16 = 1;
+
+/// Segment header: magic (5 bytes) + version (u16) + flags (u8)
+const HEADER_LEN: u64 = 8;
+
+/// Record frame header: payload length (u32) + CRC32 of the payload (u32)
+const FRAME_HEADER_LEN: usize = 8;
+
+/// File extension of segment files
+pub const SEGMENT_EXTENSION: &str = "seg";
+
+/// Size after which the active segment is sealed and a new one started
+pub const MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Header flag: the segment was produced by compaction and supersedes
+/// every lower-numbered segment and all legacy `.dbs` files
+pub const HEADER_COMPACTED: u8 = 0x01;
+
+/// Record flag: the payload is Snappy-compressed
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Record flag: the payload is sealed with the engine's keyring (after
+/// compression, if both are set)
+pub const FLAG_ENCRYPTED: u8 = 0x02;
+
+/// Whether a record stores a document or removes one
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Put,
+    Delete,
+}
+
+/// A single entry of a segment file, encoded with bincode.
+///
+/// The document body is kept as raw JSON bytes because bincode cannot
+/// deserialize a self-describing `serde_json::Value`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SegmentRecord {
+    pub kind: RecordKind,
+    pub id: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Payload size of a stored document before and after compression,
+/// plus the bytes its whole record occupies on disk
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecordSize {
+    pub raw: u64,
+    pub stored: u64,
+    pub disk: u64,
+}
+
+impl SegmentRecord {
+    /// Record storing a document, optionally compressing and encrypting its body
+    pub fn put(doc: &Document, compress: bool, cipher: Option<&Keyring>) -> Result<(Self, RecordSize)> {
+        let raw = serde_json::to_vec(&doc.data)?;
+        let raw_len = raw.len() as u64;
+
+        let (mut flags, mut payload) = if compress {
+            (FLAG_COMPRESSED, compression::compress(&raw)?)
+        } else {
+            (0, raw)
+        };
+        if let Some(cipher) = cipher {
+            payload = cipher.seal(&payload, &record_aad(doc.id.as_str(), doc.created_at, doc.updated_at))?;
+            flags |= FLAG_ENCRYPTED;
+        }
+
+        let record = Self {
+            kind: RecordKind::Put,
+            id: doc.id.clone(),
+            created_at: doc.created_at,
+            updated_at: doc.updated_at,
+            flags,
+            payload,
+        };
+
+        let size = RecordSize {
+            raw: raw_len,
+            stored: record.payload.len() as u64,
+            disk: record.frame_len(),
+        };
+
+        Ok((record, size))
+    }
+
+    /// Tombstone removing a document
+    pub fn delete(id: &str) -> Self {
+        Self {
+            kind: RecordKind::Delete,
+            id: id.to_string(),
+            created_at: 0,
+            updated_at: 0,
+            flags: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Decode the stored document of a `Put` record, decrypting and
+    /// decompressing if needed
+    pub fn to_document(&self, cipher: Option<&Keyring>) -> Result<(Document, RecordSize)> {
+        let stored = self.payload.len() as u64;
+        let disk = self.frame_len();
+        let decrypted;
+        let payload = if self.flags & FLAG_ENCRYPTED != 0 {
+            let cipher = cipher.ok_or_else(|| {
+                OpenDBSError::Encryption(format!("Document {} is encrypted but no key is configured", self.id))
+            })?;
+            decrypted = cipher.open(&self.payload, &record_aad(&self.id, self.created_at, self.updated_at))?;
+            &decrypted
+        } else {
+            &self.payload
+        };
+        let decompressed;
+        let raw = if self.flags & FLAG_COMPRESSED != 0 {
+            decompressed = compression::decompress(payload)?;
+            &decompressed
+        } else {
+            payload
+        };
+
+        let size = RecordSize {
+            raw: raw.len() as u64,
+            stored,
+            disk,
+        };
+
+        let document = Document {
+            data: serde_json::from_slice(raw)?,
+            id: self.id.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        };
+
+        Ok((document, size))
+    }
+
+    /// Bytes the framed record occupies in a segment
+    pub fn frame_len(&self) -> u64 {
+        FRAME_HEADER_LEN as u64 + bincode::serialized_size(self).unwrap_or(0)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(self)
+            .map_err(|e| OpenDBSError::Internal(format!("Failed to encode record: {}", e)))?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| OpenDBSError::Internal("Segment record too large".into()))?;
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+}
+
+/// Where a record lives: segment number, frame offset and frame length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLocation {
+    pub segment: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Result of scanning a segment file
+#[derive(Debug)]
+pub struct SegmentScan {
+    /// Length of the prefix made of intact records
+    pub valid_len: u64,
+    /// Actual file length; larger than `valid_len` if the tail is torn or corrupt
+    pub file_len: u64,
+}
+
+impl SegmentScan {
+    pub fn is_complete(&self) -> bool {
+        self.valid_len == self.file_len
+    }
+}
+
+/// Path of segment `number` inside a rack directory
+pub fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:08}.{}", number, SEGMENT_EXTENSION))
+}
+
+/// List the segments of a rack directory, ordered oldest first
+pub fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+
+        if let Some(number) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            segments.push((number, path));
+        }
+    }
+
+    segments.sort_by_key(|(number, _)| *number);
+    Ok(segments)
+}
+
+/// Position of the newest compacted segment in `segments`, if any.
+/// Everything before it, and all legacy files, are superseded.
+pub fn compacted_base(segments: &[(u64, PathBuf)]) -> Option<usize> {
+    segments.iter().rposition(|(_, path)| {
+        let flags = read_header_flags(path).ok().flatten().unwrap_or(0);
+        flags & HEADER_COMPACTED != 0
+    })
+}
+
+/// Read every intact record of a segment, stopping at the first bad frame.
+/// `visit` receives each record with the offset of its frame.
+pub fn scan_segment<F>(path: &Path, mut visit: F) -> Result<SegmentScan>
+where
+    F: FnMut(SegmentRecord, u64) -> Result<()>,
+{
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    if !read_full(&mut reader, &mut header)? {
+        // A crash right after creating the file can leave a short header
+        return Ok(SegmentScan {
+            valid_len: 0,
+            file_len,
+        });
+    }
+    check_header(path, &header)?;
+
+    let mut valid_len = HEADER_LEN;
+    loop {
+        let mut frame_header = [0u8; FRAME_HEADER_LEN];
+        if !read_full(&mut reader, &mut frame_header)? {
+            break;
+        }
+
+        let len = u32::from_le_bytes(frame_header[0..4].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(frame_header[4..8].try_into().unwrap());
+        if valid_len + FRAME_HEADER_LEN as u64 + len > file_len {
+            break;
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        if !read_full(&mut reader, &mut payload)? || crc32fast::hash(&payload) != crc {
+            break;
+        }
+
+        let record: SegmentRecord = match bincode::deserialize(&payload) {
+            Ok(record) => record,
+            Err(_) => break,
+        };
+        visit(record, valid_len)?;
+
+        valid_len += FRAME_HEADER_LEN as u64 + len;
+    }
+
+    Ok(SegmentScan {
+        valid_len,
+        file_len,
+    })
+}
+
+/// Read only the header flags of a segment; `None` if the header is incomplete
+pub fn read_header_flags(path: &Path) -> Result<Option<u8>> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; HEADER_LEN as usize];
+    if !read_full(&mut file, &mut header)? {
+        return Ok(None);
+    }
+    check_header(path, &header)?;
+    Ok(Some(header[7]))
+}
+
+/// Write `records` into a compacted segment that supersedes all older data,
+/// then atomically move it into place as segment `number`.
+/// Returns the size of the new segment file and where each record landed.
+pub fn write_compacted<I>(dir: &Path, number: u64, records: I) -> Result<(u64, Vec<(String, RecordLocation)>)>
+where
+    I: IntoIterator<Item = Result<SegmentRecord>>,
+{
+    let mut file = AtomicFile::create(&segment_path(dir, number))?;
+    file.write_all(&header_bytes(HEADER_COMPACTED))?;
+
+    let mut len = HEADER_LEN;
+    let mut locations = Vec::new();
+    for record in records {
+        let record = record?;
+        let frame = record.encode()?;
+        file.write_all(&frame)?;
+        locations.push((
+            record.id,
+            RecordLocation {
+                segment: number,
+                offset: len,
+                len: frame.len() as u64,
+            },
+        ));
+        len += frame.len() as u64;
+    }
+
+    file.commit()?;
+    Ok((len, locations))
+}
+
+/// Decode the record framed at `location` within the bytes of its segment
+pub fn decode_at(bytes: &[u8], location: RecordLocation) -> Result<SegmentRecord> {
+    let start = location.offset as usize;
+    let frame = start
+        .checked_add(location.len as usize)
+        .and_then(|end| bytes.get(start..end))
+        .filter(|frame| frame.len() >= FRAME_HEADER_LEN)
+        .ok_or_else(|| {
+            OpenDBSError::Corruption(format!(
+                "Record at offset {} of segment {} is out of bounds",
+                location.offset, location.segment
+            ))
+        })?;
+
+    let crc = u32::from_le_bytes(frame[4..8].try_into().unwrap());
+    let payload = &frame[FRAME_HEADER_LEN..];
+    if crc32fast::hash(payload) != crc {
+        return Err(OpenDBSError::Corruption(format!(
+            "Checksum mismatch at offset {} of segment {}",
+            location.offset, location.segment
+        )));
+    }
+
+    bincode::deserialize(payload)
+        .map_err(|e| OpenDBSError::Corruption(format!("Failed to decode record: {}", e)))
+}
+
+/// Remove leftovers of an interrupted compaction
+pub fn remove_temp_files(dir: &Path) -> Result<()> {
+    let suffix = format!(".{}.tmp", SEGMENT_EXTENSION);
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.to_str().is_some_and(|p| p.ends_with(&suffix)) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Context an encrypted payload is bound to, so it cannot be moved to
+/// another document or given other timestamps
+fn record_aad(id: &str, created_at: u64, updated_at: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(id.len() + 16);
+    aad.extend_from_slice(&created_at.to_le_bytes());
+    aad.extend_from_slice(&updated_at.to_le_bytes());
+    aad.extend_from_slice(id.as_bytes());
+    aad
+}
+
+fn check_header(path: &Path, header: &[u8]) -> Result<()> {
+    if &header[0..5] != MAGIC_NUMBER {
+        return Err(OpenDBSError::Corruption(format!(
+            "{} is not a segment file",
+            path.display()
+        )));
+    }
+
+    let version = u16::from_le_bytes([header[5], header[6]]);
+    if version > VERSION {
+        return Err(OpenDBSError::Corruption(format!(
+            "{} uses unsupported segment version {}",
+            path.display(),
+            version
+        )));
+    }
+
+    Ok(())
+}
+
+fn header_bytes(flags: u8) -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[0..5].copy_from_slice(MAGIC_NUMBER);
+    header[5..7].copy_from_slice(&VERSION.to_le_bytes());
+    header[7] = flags;
+    header
+}
+
+/// Appends records to the newest segment of a rack directory
+#[derive(Debug)]
+pub struct SegmentWriter {
+    dir: PathBuf,
+    number: u64,
+    file: File,
+    len: u64,
+}
+
+impl SegmentWriter {
+    /// Open the newest segment for appending, dropping any torn tail,
+    /// or start the first segment if the directory has none
+    pub fn open(dir: &Path) -> Result<Self> {
+        let Some((number, path)) = list_segments(dir)?.pop() else {
+            return Self::create(dir, 1);
+        };
+
+        let scan = match scan_segment(&path, |_, _| Ok(())) {
+            Ok(scan) => scan,
+            // Never append to a file we cannot parse; loading quarantines it
+            Err(OpenDBSError::Corruption(_)) => return Self::create(dir, number + 1),
+            Err(e) => return Err(e),
+        };
+        if scan.valid_len < HEADER_LEN {
+            // Never got past the header; start it over
+            return Self::create(dir, number);
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        if !scan.is_complete() {
+            tracing::warn!(
+                "Discarding torn tail of segment {} at offset {}",
+                path.display(),
+                scan.valid_len
+            );
+            file.set_len(scan.valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(scan.valid_len))?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            number,
+            file,
+            len: scan.valid_len,
+        })
+    }
+
+    fn create(dir: &Path, number: u64) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(segment_path(dir, number))?;
+        file.write_all(&header_bytes(0))?;
+        file.sync_all()?;
+        sync_dir(dir)?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            number,
+            file,
+            len: HEADER_LEN,
+        })
+    }
+
+    /// Number of the segment being appended to
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// Append a record, sealing the segment first if it is full.
+    /// Returns the number of bytes added to the rack directory and where
+    /// the record was written.
+    pub fn append(&mut self, record: &SegmentRecord) -> Result<(u64, RecordLocation)> {
+        let mut added = 0;
+        if self.len >= MAX_SEGMENT_BYTES {
+            self.roll()?;
+            added += HEADER_LEN;
+        }
+
+        let frame = record.encode()?;
+        self.file.write_all(&frame)?;
+        let location = RecordLocation {
+            segment: self.number,
+            offset: self.len,
+            len: frame.len() as u64,
+        };
+        self.len += frame.len() as u64;
+        Ok((added + frame.len() as u64, location))
+    }
+
+    /// Seal the active segment and continue in a new one
+    pub fn roll(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        *self = Self::create(&self.dir, self.number + 1)?;
+        Ok(())
+    }
+
+    /// Flush appended records to stable storage
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Fill `buf` completely. Returns false if the reader hit EOF first.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => return Ok(false),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_segment_roundtrip_and_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let doc = Document {
+            id: "1".into(),
+            data: json!({ "name": "Alice", "tags": ["a", "b"] }),
+            created_at: 10,
+            updated_at: 20,
+        };
+
+        {
+            let mut writer = SegmentWriter::open(dir.path()).unwrap();
+            let (record, _) = SegmentRecord::put(&doc, false, None).unwrap();
+            writer.append(&record).unwrap();
+            let (record, size) = SegmentRecord::put(&doc, true, None).unwrap();
+            assert_eq!(record.flags, FLAG_COMPRESSED);
+            assert_eq!(size.stored, record.payload.len() as u64);
+            writer.append(&record).unwrap();
+            writer.append(&SegmentRecord::delete("2")).unwrap();
+            writer.sync().unwrap();
+        }
+
+        // Half-written frame at the end of the segment
+        let path = segment_path(dir.path(), 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[200, 0, 0, 0, 9, 9, 9]).unwrap();
+        drop(file);
+
+        let mut records = Vec::new();
+        let mut offsets = Vec::new();
+        let scan = scan_segment(&path, |r, offset| {
+            records.push(r);
+            offsets.push(offset);
+            Ok(())
+        })
+        .unwrap();
+        assert!(!scan.is_complete());
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].kind, RecordKind::Delete);
+
+        // Records can be decoded in place from the file contents
+        let bytes = fs::read(&path).unwrap();
+        let location = RecordLocation {
+            segment: 1,
+            offset: offsets[1],
+            len: records[1].frame_len(),
+        };
+        let (mapped, _) = decode_at(&bytes, location).unwrap().to_document(None).unwrap();
+        assert_eq!(mapped.data, doc.data);
+        assert!(decode_at(&bytes, RecordLocation { offset: offsets[1] + 1, ..location }).is_err());
+
+        let (loaded, _) = records.remove(0).to_document(None).unwrap();
+        assert_eq!(loaded.data, doc.data);
+        assert_eq!(loaded.updated_at, 20);
+
+        let (decompressed, size) = records.remove(0).to_document(None).unwrap();
+        assert_eq!(decompressed.data, doc.data);
+        assert_eq!(size.raw, serde_json::to_vec(&doc.data).unwrap().len() as u64);
+
+        // Reopening truncates the torn tail and keeps appending
+        let mut writer = SegmentWriter::open(dir.path()).unwrap();
+        writer.append(&SegmentRecord::delete("1")).unwrap();
+        drop(writer);
+
+        let mut count = 0;
+        let scan = scan_segment(&path, |_, _| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert!(scan.is_complete());
+        assert_eq!(count, 4);
+    }
+}
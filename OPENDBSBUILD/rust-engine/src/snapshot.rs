@@ -0,0 +1,305 @@
+//! Point-in-time copies of databases and their restoration.
+//!
+//! A snapshot directory mirrors the engine root (`<db>/<rack>/<files>`) and
+//! holds a `manifest.json` listing every file with its size and SHA-256.
+//! The manifest is written last, so a directory without one is incomplete.
+
+use crate::durability::{self, sync_dir};
+use crate::error::{OpenDBSError, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the manifest inside a snapshot directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Newest snapshot layout this build reads and writes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A file captured in a snapshot
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotFile {
+    /// Path relative to the snapshot directory, `/`-separated
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Contents of `manifest.json`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotManifest {
+    pub format_version: u32,
+    pub engine_version: String,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    pub databases: Vec<String>,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// A snapshot whose files are in place but not yet checksummed.
+///
+/// Capturing files is quick (hard links where possible), so the engine only
+/// has to be held still for that part; `finish` can run while it is in use.
+#[derive(Debug)]
+pub struct PendingSnapshot {
+    dir: PathBuf,
+    created_at: u64,
+    databases: Vec<String>,
+    files: Vec<PathBuf>,
+}
+
+impl PendingSnapshot {
+    /// Start a snapshot in `dir`, which must not exist or be empty
+    pub fn create(dir: &Path) -> Result<Self> {
+        if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+            return Err(OpenDBSError::Internal(format!(
+                "Snapshot directory {} is not empty",
+                dir.display()
+            )));
+        }
+        fs::create_dir_all(dir)?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            databases: Vec::new(),
+            files: Vec::new(),
+        })
+    }
+
+    /// Record a database, creating its directory in the snapshot
+    pub fn add_database(&mut self, database: &str) -> Result<()> {
+        fs::create_dir_all(self.dir.join(database))?;
+        self.databases.push(database.to_string());
+        Ok(())
+    }
+
+    /// Capture a file that is never modified in place by hard-linking it,
+    /// falling back to a copy across filesystems
+    pub fn link(&mut self, source: &Path, relative: &Path) -> Result<()> {
+        let target = self.prepare(relative)?;
+        if fs::hard_link(source, &target).is_err() {
+            fs::copy(source, &target)?;
+        }
+        Ok(())
+    }
+
+    /// Capture a file that may still be appended to by copying it
+    pub fn copy(&mut self, source: &Path, relative: &Path) -> Result<()> {
+        let target = self.prepare(relative)?;
+        fs::copy(source, target)?;
+        Ok(())
+    }
+
+    fn prepare(&mut self, relative: &Path) -> Result<PathBuf> {
+        let target = self.dir.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.files.push(relative.to_path_buf());
+        Ok(target)
+    }
+
+    /// Checksum every captured file and write the manifest
+    pub fn finish(self) -> Result<SnapshotManifest> {
+        let mut files = Vec::with_capacity(self.files.len());
+        for relative in &self.files {
+            let path = self.dir.join(relative);
+            let (size, sha256) = checksum(File::open(&path)?, None)?;
+            File::open(&path)?.sync_all()?;
+            files.push(SnapshotFile {
+                path: manifest_path(relative),
+                size,
+                sha256,
+            });
+        }
+
+        let manifest = SnapshotManifest {
+            format_version: SNAPSHOT_VERSION,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: self.created_at,
+            databases: self.databases,
+            files,
+        };
+        for database in &manifest.databases {
+            sync_tree(&self.dir.join(database))?;
+        }
+        durability::write_atomic(&self.dir.join(MANIFEST_FILE), &serde_json::to_vec_pretty(&manifest)?)?;
+        Ok(manifest)
+    }
+}
+
+impl SnapshotManifest {
+    /// Read and sanity-check the manifest of a snapshot directory
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Err(OpenDBSError::Corruption(format!(
+                "{} has no {}; the snapshot is incomplete",
+                dir.display(),
+                MANIFEST_FILE
+            )));
+        }
+
+        let manifest: Self = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+        if manifest.format_version > SNAPSHOT_VERSION {
+            return Err(OpenDBSError::Corruption(format!(
+                "{} uses unsupported snapshot version {}",
+                path.display(),
+                manifest.format_version
+            )));
+        }
+
+        // Never follow a manifest outside the snapshot directory
+        let names_ok = manifest.databases.iter().all(|db| is_plain_name(db));
+        let paths_ok = manifest.files.iter().all(|file| {
+            let path = Path::new(&file.path);
+            path.components().all(|c| matches!(c, Component::Normal(_)))
+                && path.components().count() >= 2
+        });
+        if !names_ok || !paths_ok {
+            return Err(OpenDBSError::Corruption(format!("{} lists invalid paths", path.display())));
+        }
+        Ok(manifest)
+    }
+
+    /// Copy the files of `database` into `target`, checking each against
+    /// its recorded size and checksum
+    pub fn restore_database(&self, source: &Path, database: &str, target: &Path) -> Result<()> {
+        fs::create_dir_all(target)?;
+        let prefix = format!("{}/", database);
+
+        for file in self.files.iter().filter(|file| file.path.starts_with(&prefix)) {
+            let relative = &file.path[prefix.len()..];
+            let destination = target.join(relative);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut writer = BufWriter::new(File::create(&destination)?);
+            let (size, sha256) = checksum(File::open(source.join(&file.path))?, Some(&mut writer))?;
+            if size != file.size || sha256 != file.sha256 {
+                return Err(OpenDBSError::Corruption(format!(
+                    "Snapshot file {} does not match its checksum",
+                    file.path
+                )));
+            }
+            writer
+                .into_inner()
+                .map_err(|e| OpenDBSError::IoError(e.into_error()))?
+                .sync_all()?;
+        }
+
+        sync_tree(target)
+    }
+}
+
+/// Size and hex SHA-256 of everything read from `reader`, optionally
+/// copying it to `copy` on the way
+fn checksum<R: Read>(mut reader: R, mut copy: Option<&mut dyn Write>) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        if let Some(copy) = copy.as_mut() {
+            copy.write_all(&buf[..n])?;
+        }
+        size += n as u64;
+    }
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+/// Fsync a directory and every directory below it
+fn sync_tree(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sync_tree(&path)?;
+        }
+    }
+    sync_dir(dir)
+}
+
+fn manifest_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A single path component that is not reserved by the engine
+pub(crate) fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('_') && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::app_engine;
+    use crate::storage::{EngineOptions, StorageEngine, RESTORE_DIR};
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path();
+        let backups = tempfile::tempdir().unwrap();
+        let backup = backups.path().join("backup");
+        engine.create_database("logs").unwrap();
+        engine.create_rack("app", "users", None).unwrap();
+        for name in ["Alice", "Bob"] {
+            engine.insert("app", "users", &format!(r#"{{"name":"{}"}}"#, name), None).unwrap();
+        }
+
+        let names = vec!["app".to_string()];
+        let manifest = engine
+            .snapshot(backup.to_str().unwrap(), Some(&names))
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(manifest.databases, names);
+        assert!(manifest.files.iter().any(|file| file.path == "app/users/rack.meta"));
+        assert!(engine.snapshot(backup.to_str().unwrap(), None).is_err());
+
+        // Later writes and compaction do not leak into the snapshot
+        engine.insert("app", "users", r#"{"name":"Carol"}"#, None).unwrap();
+        engine.delete("app", "users", "1").unwrap();
+        engine.compact("app", "users").unwrap();
+        engine.create_rack("app", "orders", None).unwrap();
+
+        assert_eq!(engine.restore(backup.to_str().unwrap(), None).unwrap(), names);
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 2);
+        assert_eq!(engine.find("app", "users", r#"{"name":"Alice"}"#, None, None).unwrap().len(), 1);
+        assert!(engine.find("app", "orders", "{}", None, None).is_err());
+        assert!(engine.databases.contains_key("logs"));
+        assert!(engine.restore(backup.to_str().unwrap(), Some("logs")).is_err());
+
+        // A damaged snapshot is rejected before anything is replaced
+        engine.insert("app", "users", r#"{"name":"Dave"}"#, None).unwrap();
+        let segment = manifest.files.iter().find(|file| file.path.ends_with(".seg")).unwrap();
+        fs::write(backup.join(&segment.path), b"garbage").unwrap();
+        assert!(matches!(
+            engine.restore(backup.to_str().unwrap(), None),
+            Err(OpenDBSError::Corruption(_))
+        ));
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 3);
+        drop(engine);
+
+        // A committed restore interrupted before its swap is finished at startup
+        let pending = root.join(RESTORE_DIR).join("logs");
+        fs::create_dir_all(pending.join("events")).unwrap();
+        let engine = StorageEngine::new(root.to_str().unwrap(), EngineOptions::default()).unwrap();
+        assert!(!root.join(RESTORE_DIR).exists());
+        assert!(engine.databases.get("logs").unwrap().racks.contains_key("events"));
+    }
+}
@@ -1,17 +1,53 @@
+use crate::crypto::{self, EncryptionOptions, FieldCipher, FieldEncryption, Keyring};
+use crate::durability::{self, Durability};
 use crate::error::{OpenDBSError, Result};
+use crate::lock::DirLock;
+use crate::index::{ordered_key, INDEX_FILE};
+use crate::meta::{IdStrategy, IndexInfo, IndexKind, IndexOptions, RackMeta, RackOptions, META_FILE};
+use crate::query::{FindOptions, QueryEngine, RangeOp, SortOrder, SortSpec};
+use crate::migration;
+use crate::mmap::MappedSegments;
+use crate::oplog::{OperationLog, OplogOp, OplogRecord};
+use crate::quarantine::{Quarantine, SkippedEntry};
+use crate::segment::{self, RecordKind, RecordLocation, RecordSize, SegmentRecord, SegmentWriter};
+use crate::snapshot::{self, PendingSnapshot, SnapshotManifest};
+use crate::transfer::{self, Exporter, Format, ImportError, ImportReport, TransferOptions};
+use crate::verify::{self, VerifyReport};
+use crate::wal::{WalOp, WalRecord, WriteAheadLog};
+use dashmap::mapref::entry::Entry as DashEntry;
 use dashmap::DashMap;
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, Mutex, RawRwLock, RwLock};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, BufWriter};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
-#[allow(dead_code)]
-const MAGIC_NUMBER: &[u8; 5] = b"ODBDS";
-#[allow(dead_code)]
-const VERSION: u16 = 1;
+/// Number of logged mutations after which the WAL is checkpointed
+const WAL_CHECKPOINT_THRESHOLD: usize = 1024;
+
+/// Racks smaller than this on disk are never compacted automatically
+const MIN_COMPACTION_BYTES: u64 = 1024 * 1024;
+
+/// Directory inside the engine root for data being dropped or staged.
+/// Whatever an interrupted operation leaves there is deleted at startup.
+const TMP_DIR: &str = "_tmp";
+
+/// Directory inside the engine root holding restored databases that are
+/// verified and committed but not yet swapped into place
+pub(crate) const RESTORE_DIR: &str = "_restore";
+
+/// Suffix counter keeping staging directory names unique
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Logical clock stamped on racks when they are accessed, for LRU eviction
+static ACCESS_CLOCK: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Document {
@@ -21,47 +57,261 @@ pub struct Document {
     pub updated_at: u64,
 }
 
+/// Engine-wide options
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EngineOptions {
+    /// When acknowledged writes are forced to stable storage
+    pub durability: Durability,
+    /// Upper bound on the uncompressed size of documents kept in memory.
+    /// Least recently used racks are evicted once it is exceeded; `None`
+    /// keeps every rack resident after its first access.
+    pub memory_budget_bytes: Option<u64>,
+    /// Keep an operation log next to the WAL so databases can be restored
+    /// to any instant after a snapshot with `restore_to`
+    pub oplog: bool,
+    /// Encrypt documents, the WAL and the operation log at rest
+    pub encryption: Option<EncryptionOptions>,
+    /// Open for reading only, sharing the data directory with other
+    /// read-only engines instead of locking it exclusively
+    pub read_only: bool,
+}
+
+/// Per-rack storage settings
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RackSettings {
+    /// Snappy-compress document bodies written to segments
+    pub compression: bool,
+    /// Share of dead bytes on disk that triggers an automatic compaction.
+    /// Values above 1.0 disable automatic compaction.
+    pub compaction_threshold: f64,
+    /// Keep only the location of each document in memory and decode it
+    /// from memory-mapped segments on read. Suits large, read-heavy racks.
+    pub mmap: bool,
+}
+
+impl Default for RackSettings {
+    fn default() -> Self {
+        Self {
+            compression: false,
+            compaction_threshold: 0.5,
+            mmap: false,
+        }
+    }
+}
+
+/// Outcome of a rack compaction
+#[derive(Debug, Serialize)]
+pub struct CompactionReport {
+    pub documents: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
 #[derive(Debug)]
 pub struct Database {
     #[allow(dead_code)]
     pub name: String,
     pub path: PathBuf,
-    pub racks: DashMap<String, Rack>,
+    pub racks: DashMap<String, Arc<Rack>>,
 }
 
 #[derive(Debug)]
 pub struct Rack {
-    #[allow(dead_code)]
     pub name: String,
     pub path: PathBuf,
+    /// Name of the database the rack belongs to
+    database: String,
+    /// Documents held in memory. Racks with `mmap` set only keep legacy
+    /// documents here that have not been rewritten into a segment yet.
     pub documents: DashMap<String, Document>,
+    /// Segment locations of documents read through `mapped`
+    locations: DashMap<String, RecordLocation>,
+    /// Memory-mapped segments, used by racks with `mmap` set
+    mapped: MappedSegments,
     pub next_id: AtomicU64,
     pub index: crate::index::Index,
+    /// The index changed since it was last saved to `rack.index`
+    pub(crate) index_dirty: AtomicBool,
+    /// Storage settings, as persisted in `meta`
+    pub settings: RackSettings,
+    /// Contents of the rack's `rack.meta` file
+    meta: Mutex<RackMeta>,
+    /// Payload sizes of live documents, for storage statistics
+    pub sizes: DashMap<String, RecordSize>,
+    /// Appender for the newest segment, opened on first write
+    writer: Mutex<Option<SegmentWriter>>,
+    /// Bytes of segment and legacy document files in the rack directory
+    disk_bytes: AtomicU64,
+    /// Bytes of those files still holding live documents
+    live_bytes: AtomicU64,
+    /// Held for the whole run of a compaction
+    compaction: Mutex<()>,
+    /// Set while a background compaction is scheduled or running
+    compacting: AtomicBool,
+    /// Set under `compaction` once the rack is dropped or moved away, after
+    /// which its directory is no longer its own
+    closed: AtomicBool,
+    /// Whether `documents` is loaded. Handles hold a read lock so the rack
+    /// cannot be evicted while in use; loading and eviction take the write lock.
+    resident: Arc<RwLock<bool>>,
+    /// `ACCESS_CLOCK` value of the most recent access
+    last_access: AtomicU64,
+    /// Uncompressed bytes of the documents held in memory; mapped documents
+    /// are not counted
+    resident_bytes: AtomicU64,
+    /// Where unreadable files found while loading are set aside
+    quarantine: Arc<Quarantine>,
+    /// Keys documents are encrypted with, if encryption is enabled
+    pub(crate) cipher: Option<Arc<Keyring>>,
+    /// Opened by a read-only engine: files are never modified
+    read_only: bool,
+    /// Writes logged in the WAL that may be missing from the segments. A
+    /// read-only engine cannot replay them into the files, so they are
+    /// applied in memory each time the rack is loaded.
+    logged: Mutex<Vec<WalOp>>,
+}
+
+/// A rack whose documents are loaded and stay loaded while the handle lives
+pub struct RackHandle {
+    rack: Arc<Rack>,
+    _resident: ArcRwLockReadGuard<RawRwLock, bool>,
+}
+
+impl Deref for RackHandle {
+    type Target = Rack;
+
+    fn deref(&self) -> &Rack {
+        &self.rack
+    }
 }
 
 #[derive(Debug)]
 pub struct StorageEngine {
     pub root_path: PathBuf,
     pub databases: DashMap<String, Database>,
+    pub options: EngineOptions,
+    /// Absent in read-only engines, which never write
+    wal: Option<Arc<WriteAheadLog>>,
+    /// Log of every mutation for point-in-time recovery, if enabled
+    oplog: Option<Arc<OperationLog>>,
+    /// Files that could not be loaded and were set aside
+    quarantine: Arc<Quarantine>,
+    /// Keys for encryption at rest, if enabled
+    cipher: Option<Arc<Keyring>>,
+    /// Keeps other engines from opening the data directory for writing
+    _lock: DirLock,
 }
 
 impl StorageEngine {
     /// Create a new storage engine
-    pub fn new(path: &str) -> Result<Self> {
+    pub fn new(path: &str, options: EngineOptions) -> Result<Self> {
         let root_path = PathBuf::from(path);
+        let cipher = match &options.encryption {
+            Some(encryption) => Keyring::from_options(encryption, options.read_only)?.map(Arc::new),
+            None => None,
+        };
+        if options.read_only {
+            return Self::open_read_only(root_path, options, cipher);
+        }
+
         fs::create_dir_all(&root_path)?;
+        // Nothing may be read or repaired before the directory is ours
+        let lock = DirLock::acquire(&root_path, false)?;
+        Self::finish_restores(&root_path)?;
+
+        // Bring document files up to date with anything logged before a crash
+        let (wal, pending) = WriteAheadLog::open(&root_path, options.durability, cipher.clone())?;
+        Self::replay(&root_path, &pending, cipher.as_deref())?;
+        wal.truncate()?;
+
+        let wal = Arc::new(wal);
+        let oplog = match options.oplog {
+            true => Some(Arc::new(OperationLog::open(
+                &root_path,
+                options.durability,
+                cipher.clone(),
+            )?)),
+            false => None,
+        };
+        if let Durability::BatchedMs(interval) = options.durability {
+            spawn_wal_syncer(
+                Arc::downgrade(&wal),
+                oplog.as_ref().map(Arc::downgrade),
+                Duration::from_millis(interval.max(1)),
+            );
+        }
 
         let engine = Self {
+            quarantine: Arc::new(Quarantine::new(&root_path)),
             root_path,
             databases: DashMap::new(),
+            options,
+            wal: Some(wal),
+            oplog,
+            cipher,
+            _lock: lock,
         };
 
+        // Finish drops and discard copies interrupted by a crash
+        let tmp_dir = engine.root_path.join(TMP_DIR);
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+
         // Load existing databases
         engine.load_databases()?;
 
         Ok(engine)
     }
 
+    /// Open an existing data directory without creating, repairing or
+    /// replaying anything. Writes left in the WAL are applied in memory, and
+    /// a committed but unfinished restore is read from where it waits.
+    fn open_read_only(root_path: PathBuf, options: EngineOptions, cipher: Option<Arc<Keyring>>) -> Result<Self> {
+        if !root_path.is_dir() {
+            return Err(OpenDBSError::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Data directory {} does not exist", root_path.display()),
+            )));
+        }
+        let lock = DirLock::acquire(&root_path, true)?;
+        let pending = WriteAheadLog::read_records(&root_path, cipher.as_deref())?;
+
+        let engine = Self {
+            quarantine: Arc::new(Quarantine::read_only(&root_path)),
+            root_path,
+            databases: DashMap::new(),
+            options,
+            wal: None,
+            oplog: None,
+            cipher,
+            _lock: lock,
+        };
+        engine.load_databases()?;
+
+        let restore_dir = engine.root_path.join(RESTORE_DIR);
+        if restore_dir.is_dir() {
+            for entry in fs::read_dir(&restore_dir)? {
+                let path = entry?.path();
+                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                    let database = Database::load(&path, name, &engine.quarantine, &engine.cipher, true)?;
+                    engine.databases.insert(name.to_string(), database);
+                }
+            }
+        }
+
+        for record in pending {
+            if let Some(db) = engine.databases.get(&record.database) {
+                if let Some(rack) = db.racks.get(&record.rack) {
+                    rack.logged.lock().push(record.op);
+                }
+            }
+        }
+        Ok(engine)
+    }
+
     /// Load existing databases from disk
     fn load_databases(&self) -> Result<()> {
         if !self.root_path.exists() {
@@ -79,7 +329,20 @@ impl StorageEngine {
                     .ok_or_else(|| OpenDBSError::Internal("Invalid database name".into()))?
                     .to_string();
 
-                let database = Database::load(&path, &db_name)?;
+                // Names starting with '_' are reserved for engine bookkeeping
+                if db_name.starts_with('_') {
+                    continue;
+                }
+
+                if self.options.read_only {
+                    for (rack, odbs_path) in migration::odbs_files(&path)? {
+                        self.quarantine
+                            .note(&db_name, &rack, &odbs_path, "Not imported by a read-only engine");
+                    }
+                } else {
+                    self.migrate_odbs(&path, &db_name)?;
+                }
+                let database = Database::load(&path, &db_name, &self.quarantine, &self.cipher, self.options.read_only)?;
                 self.databases.insert(db_name, database);
             }
         }
@@ -87,14 +350,238 @@ impl StorageEngine {
         Ok(())
     }
 
+    /// Convert racks the Node engine left as `<rack>.odbs` files into rack
+    /// directories. Imported files are renamed with a `.migrated` suffix;
+    /// files that fail their integrity check are quarantined.
+    fn migrate_odbs(&self, db_path: &Path, database: &str) -> Result<()> {
+        for (rack, odbs_path) in migration::odbs_files(db_path)? {
+            let rack_path = db_path.join(&rack);
+            let mut migrated_path = odbs_path.clone().into_os_string();
+            migrated_path.push(migration::MIGRATED_SUFFIX);
+
+            // A rack directory of the same name means an earlier import got
+            // as far as moving it into place
+            if rack_path.exists() {
+                fs::rename(&odbs_path, &migrated_path)?;
+                continue;
+            }
+
+            let odbs = match migration::read_odbs(&odbs_path) {
+                Ok(odbs) => odbs,
+                Err(OpenDBSError::Corruption(reason)) => {
+                    self.quarantine.move_file(database, &rack, &odbs_path, &reason);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let staging = self.staging_dir()?;
+            odbs.meta.save(&staging)?;
+            let compress = odbs.meta.settings.compression;
+            let mut records = Vec::with_capacity(odbs.documents.len() + 1);
+            for doc in &odbs.documents {
+                records.push(SegmentRecord::put(doc, compress, self.cipher.as_deref()).map(|(record, _)| record));
+            }
+            // A tombstone keeps ids the Node engine already handed out from being reused
+            let last_id = (odbs.next_id - 1).to_string();
+            if odbs.next_id > 1 && !odbs.documents.iter().any(|doc| doc.id == last_id) {
+                records.push(Ok(SegmentRecord::delete(&last_id)));
+            }
+            segment::write_compacted(&staging, 1, records)?;
+
+            fs::rename(&staging, &rack_path)?;
+            fs::rename(&odbs_path, &migrated_path)?;
+            durability::sync_dir(db_path)?;
+            tracing::info!(
+                "Imported {} documents into {}/{} from {}",
+                odbs.documents.len(),
+                database,
+                rack,
+                odbs_path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Re-apply logged mutations directly to the rack segments
+    fn replay(root_path: &Path, records: &[WalRecord], cipher: Option<&Keyring>) -> Result<()> {
+        let mut writers: HashMap<PathBuf, (SegmentWriter, RackSettings)> = HashMap::new();
+
+        for record in records {
+            let rack_path = root_path.join(&record.database).join(&record.rack);
+            if !rack_path.is_dir() {
+                tracing::warn!(
+                    "Skipping WAL record for missing rack {}/{}",
+                    record.database,
+                    record.rack
+                );
+                continue;
+            }
+
+            let (writer, settings) = match writers.entry(rack_path) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    let writer = SegmentWriter::open(e.key())?;
+                    // Record flags are self-describing, so defaults are safe here
+                    let settings = RackMeta::load(e.key())
+                        .map(|meta| meta.settings)
+                        .unwrap_or_default();
+                    e.insert((writer, settings))
+                }
+            };
+
+            let seg_record = match &record.op {
+                WalOp::Insert { document } | WalOp::Update { document } => {
+                    SegmentRecord::put(document, settings.compression, cipher)?.0
+                }
+                WalOp::Delete { id } => SegmentRecord::delete(id),
+            };
+            writer.append(&seg_record)?;
+        }
+
+        for (writer, _) in writers.values() {
+            writer.sync()?;
+        }
+
+        if !records.is_empty() {
+            tracing::info!("Replayed {} write-ahead log records", records.len());
+        }
+
+        Ok(())
+    }
+
+    /// Flush every segment written since the last checkpoint, truncate the
+    /// WAL and save the indexes that changed
+    pub fn checkpoint(&self) -> Result<()> {
+        if self.options.durability.syncs() {
+            for db in self.databases.iter() {
+                for rack in db.racks.iter() {
+                    rack.sync()?;
+                }
+            }
+        }
+        for db in self.databases.iter() {
+            for rack in db.racks.iter() {
+                rack.save_index()?;
+            }
+        }
+
+        if let Some(oplog) = &self.oplog {
+            oplog.sync()?;
+        }
+        match &self.wal {
+            Some(wal) => wal.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuse a mutation if the engine was opened read-only
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(OpenDBSError::PermissionDenied(
+                "The engine was opened read-only".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Log a document mutation to the WAL and, if enabled, the operation log
+    fn log_write(&self, record: WalRecord) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            wal.append(&record)?;
+        }
+        if let Some(oplog) = &self.oplog {
+            oplog.append(&record.database, OplogOp::from_wal(&record.rack, &record.op))?;
+        }
+        Ok(())
+    }
+
+    /// Record a structural change in the operation log, if enabled
+    fn log_operation(&self, database: &str, op: OplogOp) -> Result<()> {
+        if let Some(oplog) = &self.oplog {
+            oplog.append(database, op)?;
+        }
+        Ok(())
+    }
+
+    /// Checkpoint once enough mutations have accumulated in the WAL
+    fn maybe_checkpoint(&self) -> Result<()> {
+        if self.wal.as_ref().is_some_and(|wal| wal.pending() >= WAL_CHECKPOINT_THRESHOLD) {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Look up a rack, loading its documents on first access
+    pub(crate) fn open_rack(&self, database: &str, rack: &str) -> Result<RackHandle> {
+        let rack_ref = {
+            let db = self
+                .databases
+                .get(database)
+                .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
+
+            let rack_ref = db
+                .racks
+                .get(rack)
+                .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+
+            Arc::clone(rack_ref.value())
+        };
+
+        let handle = rack_ref.acquire()?;
+        self.enforce_memory_budget(&handle);
+        Ok(handle)
+    }
+
+    /// Evict least recently used racks until resident documents fit the
+    /// memory budget. `keep` is never evicted; racks in use are skipped.
+    fn enforce_memory_budget(&self, keep: &Rack) {
+        let Some(budget) = self.options.memory_budget_bytes else {
+            return;
+        };
+
+        let mut resident = Vec::new();
+        let mut total = 0;
+        for db in self.databases.iter() {
+            for rack in db.racks.iter() {
+                if rack.is_loaded() {
+                    total += rack.resident_bytes.load(Ordering::SeqCst);
+                    resident.push(Arc::clone(rack.value()));
+                }
+            }
+        }
+        if total <= budget {
+            return;
+        }
+
+        resident.sort_by_key(|rack| rack.last_access.load(Ordering::SeqCst));
+        for rack in resident {
+            if total <= budget {
+                break;
+            }
+            if std::ptr::eq(rack.as_ref(), keep) {
+                continue;
+            }
+
+            let bytes = rack.resident_bytes.load(Ordering::SeqCst);
+            if rack.evict() {
+                total = total.saturating_sub(bytes);
+            }
+        }
+    }
+
     /// Create a new database
     pub fn create_database(&mut self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        check_name("Database", name)?;
+
         if self.databases.contains_key(name) {
             return Ok(false);
         }
 
         let db_path = self.root_path.join(name);
         fs::create_dir_all(&db_path)?;
+        durability::sync_dir(&self.root_path)?;
 
         let database = Database {
             name: name.to_string(),
@@ -103,11 +590,15 @@ impl StorageEngine {
         };
 
         self.databases.insert(name.to_string(), database);
+        self.log_operation(name, OplogOp::CreateDatabase)?;
         Ok(true)
     }
 
-    /// Create a new rack in a database
-    pub fn create_rack(&mut self, database: &str, rack: &str) -> Result<bool> {
+    /// Create a new rack in a database, optionally with JSON-encoded
+    /// `RackOptions` (type, schema, indexes and storage settings)
+    pub fn create_rack(&mut self, database: &str, rack: &str, options: Option<&str>) -> Result<bool> {
+        self.check_writable()?;
+        check_name("Rack", rack)?;
         let db = self
             .databases
             .get(database)
@@ -117,288 +608,2804 @@ impl StorageEngine {
             return Ok(false);
         }
 
+        let mut options: RackOptions = match options {
+            Some(options) => serde_json::from_str(options)?,
+            None => RackOptions::default(),
+        };
+        let field_key = options.field_key.take();
+        for index in options.index_options.values_mut() {
+            if !index.fields.is_empty() {
+                index.kind = IndexKind::Ordered;
+            }
+        }
+        let mut meta = RackMeta::new(options);
+        meta.check_indexes()?;
+        if !meta.encrypted_fields.is_empty() {
+            let field_key = field_key.ok_or_else(|| {
+                OpenDBSError::Encryption("Racks with encrypted fields need a `field_key`".into())
+            })?;
+            meta.field_key_check = Some(FieldCipher::new(&field_key)?.key_check().to_string());
+        }
+
         let rack_path = db.path.join(rack);
         fs::create_dir_all(&rack_path)?;
+        meta.save(&rack_path)?;
+        durability::sync_dir(&db.path)?;
 
-        let new_rack = Rack {
-            name: rack.to_string(),
-            path: rack_path,
-            documents: DashMap::new(),
-            next_id: AtomicU64::new(1),
-            index: crate::index::Index::new(),
+        let op = OplogOp::CreateRack {
+            rack: rack.to_string(),
+            meta: meta.clone(),
         };
-
-        db.racks.insert(rack.to_string(), new_rack);
+        let new_rack = Rack::new(
+            rack,
+            &rack_path,
+            database,
+            meta,
+            Arc::clone(&self.quarantine),
+            self.cipher.clone(),
+        );
+        db.racks.insert(rack.to_string(), Arc::new(new_rack));
+        drop(db);
+        self.log_operation(database, op)?;
         Ok(true)
     }
 
-    /// Insert a document into a rack
-    pub fn insert(&mut self, database: &str, rack: &str, data: &str) -> Result<String> {
-        let db = self
-            .databases
-            .get(database)
-            .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
-
-        let rack_ref = db
-            .racks
-            .get(rack)
-            .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
-
-        let json_data: Value = serde_json::from_str(data)?;
-        let id = rack_ref.next_id.fetch_add(1, Ordering::SeqCst).to_string();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let document = Document {
-            id: id.clone(),
-            data: json_data,
-            created_at: now,
-            updated_at: now,
+    /// Drop a database and all of its racks
+    pub fn drop_database(&mut self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        let Some(db) = self.databases.get(name).map(|db| db.path.clone()) else {
+            return Ok(false);
         };
 
-        // Save to disk
-        rack_ref.save_document(&document)?;
-
-        // Update index
-        rack_ref.index.index_document(&id, &document.data);
-
-        // Store in memory
-        rack_ref.documents.insert(id.clone(), document);
+        // Logged writes must not be replayed into a later database of the same name
+        self.checkpoint()?;
+        if let Some((_, database)) = self.databases.remove(name) {
+            for rack in database.racks.iter() {
+                rack.close();
+            }
+        }
 
-        Ok(id)
+        self.discard_dir(&db)?;
+        self.log_operation(name, OplogOp::DropDatabase)?;
+        Ok(true)
     }
 
-    /// Find documents matching a query
-    pub fn find(&self, database: &str, rack: &str, query: &str) -> Result<Vec<String>> {
-        let db = self
+    /// Drop a rack and its documents
+    pub fn drop_rack(&mut self, database: &str, rack: &str) -> Result<bool> {
+        self.check_writable()?;
+        let exists = self
             .databases
             .get(database)
-            .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
+            .is_some_and(|db| db.racks.contains_key(rack));
+        if !exists {
+            return Ok(false);
+        }
 
-        let rack_ref = db
-            .racks
-            .get(rack)
-            .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+        self.checkpoint()?;
+        let dropped = self
+            .databases
+            .get(database)
+            .and_then(|db| db.racks.remove(rack))
+            .map(|(_, dropped)| dropped);
+        if let Some(dropped) = dropped {
+            dropped.close();
+            self.discard_dir(&dropped.path)?;
+        }
+        self.log_operation(database, OplogOp::DropRack { rack: rack.to_string() })?;
+        Ok(true)
+    }
 
-        let query_obj: Value = serde_json::from_str(query)?;
-        let mut results = Vec::new();
-        let query_engine = crate::query::QueryEngine::new();
+    /// Remove every document of a rack, keeping the rack and its settings
+    pub fn clear_rack(&mut self, database: &str, rack: &str) -> Result<bool> {
+        self.check_writable()?;
+        let rack_ref = match self.open_rack(database, rack) {
+            Ok(rack_ref) => rack_ref,
+            Err(OpenDBSError::DatabaseNotFound(_) | OpenDBSError::RackNotFound(_)) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        // Logged writes must not resurrect documents after a crash
+        self.checkpoint()?;
+        rack_ref.clear()?;
+        self.log_operation(database, OplogOp::ClearRack { rack: rack.to_string() })?;
+        Ok(true)
+    }
 
-        for entry in rack_ref.documents.iter() {
-            if query_engine.matches(&entry.value().data, &query_obj) {
-                results.push(serde_json::to_string(&entry.value())?);
+    /// Rename a rack within its database. Returns false if `new_name` is taken.
+    pub fn rename_rack(&mut self, database: &str, rack: &str, new_name: &str) -> Result<bool> {
+        self.check_writable()?;
+        check_name("Rack", new_name)?;
+        {
+            let db = self
+                .databases
+                .get(database)
+                .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
+            if !db.racks.contains_key(rack) {
+                return Err(OpenDBSError::RackNotFound(rack.to_string()));
+            }
+            if db.racks.contains_key(new_name) {
+                return Ok(false);
             }
         }
 
-        Ok(results)
-    }
-
-    /// Update a document
-    pub fn update(&mut self, database: &str, rack: &str, id: &str, data: &str) -> Result<bool> {
+        // Logged writes name the old rack, so make them durable first
+        self.checkpoint()?;
         let db = self
             .databases
             .get(database)
             .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
-
-        let rack_ref = db
+        let (_, old) = db
             .racks
-            .get(rack)
+            .remove(rack)
             .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+        old.close();
 
-        let doc_to_save = if let Some(mut doc) = rack_ref.documents.get_mut(id) {
-            let json_data: Value = serde_json::from_str(data)?;
-            
-            // Remove old index
-            rack_ref.index.remove_document(id, &doc.data);
-
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-
-            doc.data = json_data.clone(); // Update data
-            doc.updated_at = now;
-
-            // Add new index
-            rack_ref.index.index_document(id, &doc.data);
+        let new_path = db.path.join(new_name);
+        if let Err(e) = fs::rename(&old.path, &new_path) {
+            db.racks.insert(rack.to_string(), old);
+            return Err(e.into());
+        }
+        // The rack has moved even if the directory cannot be synced, so it
+        // is registered under its new name before that error is returned
+        let synced = durability::sync_dir(&db.path);
+
+        let renamed = Rack::open(
+            &new_path,
+            database,
+            new_name,
+            Arc::clone(&self.quarantine),
+            self.cipher.clone(),
+            false,
+        );
+        renamed.next_id.store(old.next_id.load(Ordering::SeqCst), Ordering::SeqCst);
+        db.racks.insert(new_name.to_string(), Arc::new(renamed));
+        drop(db);
+        self.log_operation(
+            database,
+            OplogOp::RenameRack {
+                rack: rack.to_string(),
+                new_name: new_name.to_string(),
+            },
+        )?;
+        synced?;
+        Ok(true)
+    }
 
-            Some(doc.clone())
-        } else {
-            None
+    /// Copy a rack with its settings and documents, possibly into another
+    /// database. Returns false if the target rack already exists.
+    pub fn duplicate_rack(
+        &mut self,
+        source_db: &str,
+        source_rack: &str,
+        target_db: &str,
+        target_rack: &str,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        check_name("Rack", target_rack)?;
+        let source = self.open_rack(source_db, source_rack)?;
+        let target_path = {
+            let db = self
+                .databases
+                .get(target_db)
+                .ok_or_else(|| OpenDBSError::DatabaseNotFound(target_db.to_string()))?;
+            if db.racks.contains_key(target_rack) {
+                return Ok(false);
+            }
+            db.path.join(target_rack)
         };
 
-        if let Some(doc) = doc_to_save {
-            rack_ref.save_document(&doc)?;
-            Ok(true)
-        } else {
-            Ok(false)
+        // Build the copy out of sight and move it into place in one rename
+        let staging = self.staging_dir()?;
+        source.meta().duplicate().save(&staging)?;
+        let records = source.ids().into_iter().filter_map(|id| match source.get(&id) {
+            Ok(doc) => Some(SegmentRecord::put(&doc?, source.settings.compression, self.cipher.as_deref()).map(|(record, _)| record)),
+            Err(e) => Some(Err(e)),
+        });
+        segment::write_compacted(&staging, 1, records)?;
+        fs::rename(&staging, &target_path)?;
+        durability::sync_dir(target_path.parent().unwrap_or(&self.root_path))?;
+
+        let copy = Rack::open(
+            &target_path,
+            target_db,
+            target_rack,
+            Arc::clone(&self.quarantine),
+            self.cipher.clone(),
+            false,
+        );
+        copy.next_id.store(source.next_id.load(Ordering::SeqCst), Ordering::SeqCst);
+        if let Some(db) = self.databases.get(target_db) {
+            db.racks.insert(target_rack.to_string(), Arc::new(copy));
         }
+        self.log_operation(
+            target_db,
+            OplogOp::DuplicateRack {
+                source_database: source_db.to_string(),
+                source_rack: source_rack.to_string(),
+                rack: target_rack.to_string(),
+            },
+        )?;
+        Ok(true)
     }
 
-    /// Delete a document
-    pub fn delete(&mut self, database: &str, rack: &str, id: &str) -> Result<bool> {
-        let db = self
-            .databases
-            .get(database)
-            .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
-
-        let rack_ref = db
-            .racks
-            .get(rack)
-            .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+    /// Fresh, empty directory under `_tmp`
+    fn staging_dir(&self) -> Result<PathBuf> {
+        staging_dir_in(&self.root_path)
+    }
 
-        if let Some((_, doc)) = rack_ref.documents.remove(id) {
-            rack_ref.index.remove_document(id, &doc.data);
-            rack_ref.delete_document(id)?;
-            Ok(true)
-        } else {
-            Ok(false)
+    /// Atomically move a directory out of the tree, then delete it. If the
+    /// deletion is interrupted, the leftovers are removed at the next startup.
+    fn discard_dir(&self, path: &Path) -> Result<()> {
+        let target = self.staging_dir()?;
+        fs::remove_dir(&target)?;
+        fs::rename(path, &target)?;
+        if let Some(parent) = path.parent() {
+            durability::sync_dir(parent)?;
         }
+        fs::remove_dir_all(&target)?;
+        Ok(())
     }
 
-    /// Fuzzy search
-    pub fn fuzzy_search(
-        &self,
-        database: &str,
-        rack: &str,
-        field: &str,
-        query: &str,
-        threshold: f64,
-    ) -> Result<Vec<String>> {
-        let db = self
-            .databases
-            .get(database)
-            .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
-
-        let rack_ref = db
-            .racks
-            .get(rack)
-            .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+    /// Capture a point-in-time copy of `databases` (all if `None`) into
+    /// `dest`. Files that are never rewritten in place are hard-linked and
+    /// the active segment of each rack is copied, so this is quick; the
+    /// returned snapshot is checksummed by `PendingSnapshot::finish`, which
+    /// does not need the engine.
+    pub fn snapshot(&self, dest: &str, databases: Option<&[String]>) -> Result<PendingSnapshot> {
+        let dest = std::path::absolute(dest)?;
+        if dest.starts_with(std::path::absolute(&self.root_path)?) {
+            return Err(OpenDBSError::Internal(
+                "Snapshots cannot be written inside the data directory".into(),
+            ));
+        }
 
-        let mut results = Vec::new();
+        let names = match databases {
+            Some(names) => names.to_vec(),
+            None => {
+                let mut names: Vec<String> = self.databases.iter().map(|db| db.key().clone()).collect();
+                names.sort();
+                names
+            }
+        };
+        if let Some(missing) = names.iter().find(|name| !self.databases.contains_key(*name)) {
+            return Err(OpenDBSError::DatabaseNotFound(missing.clone()));
+        }
 
-        for entry in rack_ref.documents.iter() {
-            if let Some(value) = entry.value().data.get(field) {
-                if let Some(text) = value.as_str() {
-                    let similarity = strsim::jaro_winkler(query, text);
-                    if similarity >= threshold {
-                        results.push(serde_json::to_string(&entry.value())?);
+        let mut snapshot = PendingSnapshot::create(&dest)?;
+        for name in &names {
+            let racks: Vec<Arc<Rack>> = match self.databases.get(name) {
+                Some(db) => db.racks.iter().map(|rack| Arc::clone(rack.value())).collect(),
+                None => return Err(OpenDBSError::DatabaseNotFound(name.clone())),
+            };
+            snapshot.add_database(name)?;
+
+            for rack in racks {
+                // Compaction must not swap files while they are captured
+                let _running = rack.compaction.lock();
+                rack.sync()?;
+
+                let active = segment::list_segments(&rack.path)?.pop().map(|(_, path)| path);
+                for entry in fs::read_dir(&rack.path)? {
+                    let path = entry?.path();
+                    if !path.is_file() || path.extension().and_then(|s| s.to_str()) == Some("tmp") {
+                        continue;
+                    }
+                    let relative = Path::new(name).join(&rack.name).join(path.file_name().unwrap_or_default());
+                    if active.as_ref() == Some(&path) {
+                        snapshot.copy(&path, &relative)?;
+                    } else {
+                        snapshot.link(&path, &relative)?;
                     }
                 }
             }
         }
 
-        Ok(results)
+        // Recovery can start from this snapshot once it is finished
+        if let Some(oplog) = &self.oplog {
+            oplog.register_snapshot(&dest, &names)?;
+        }
+        Ok(snapshot)
     }
 
-    /// Get storage statistics
-    pub fn get_stats(&self) -> Result<String> {
-        let mut stats = HashMap::new();
-        stats.insert("databases", self.databases.len());
-
-        let mut total_racks = 0;
-        let mut total_docs = 0;
+    /// Replace `database` (or every database in the snapshot) with its copy
+    /// in the snapshot at `source`. Each database is verified against the
+    /// manifest and staged in full before it atomically replaces the live one.
+    /// Returns the names of the restored databases.
+    pub fn restore(&mut self, source: &str, database: Option<&str>) -> Result<Vec<String>> {
+        self.check_writable()?;
+        let source = Path::new(source);
+        let manifest = SnapshotManifest::load(source)?;
+        let names = match database {
+            Some(name) if manifest.databases.iter().any(|db| db == name) => vec![name.to_string()],
+            Some(name) => return Err(OpenDBSError::DatabaseNotFound(name.to_string())),
+            None => manifest.databases.clone(),
+        };
 
-        for db in self.databases.iter() {
-            total_racks += db.racks.len();
-            for rack in db.racks.iter() {
-                total_docs += rack.documents.len();
+        let mut staged = Vec::with_capacity(names.len());
+        for name in &names {
+            let staging = self.staging_dir()?;
+            if let Err(e) = manifest.restore_database(source, name, &staging) {
+                for (_, dir) in staged.into_iter().chain([(name, staging)]) {
+                    let _ = fs::remove_dir_all(dir);
+                }
+                return Err(e);
             }
+            staged.push((name, staging));
         }
 
-        stats.insert("racks", total_racks);
-        stats.insert("documents", total_docs);
-
-        Ok(serde_json::to_string(&stats)?)
-    }
-}
+        // Logged writes must not be replayed over the restored data
+        self.checkpoint()?;
 
-impl Database {
-    fn load(path: &Path, name: &str) -> Result<Self> {
-        let racks = DashMap::new();
+        let restore_dir = self.root_path.join(RESTORE_DIR);
+        fs::create_dir_all(&restore_dir)?;
+        for (name, staging) in staged {
+            // Committed once it is in `_restore`; startup finishes the swap
+            fs::rename(&staging, restore_dir.join(name))?;
+            durability::sync_dir(&restore_dir)?;
+
+            if let Some((_, database)) = self.databases.remove(name) {
+                for rack in database.racks.iter() {
+                    rack.close();
+                }
+            }
+            if let Some(replaced) = Self::swap_in_restored(&self.root_path, name)? {
+                fs::remove_dir_all(replaced)?;
+            }
+
+            let path = self.root_path.join(name);
+            let database = Database::load(&path, name, &self.quarantine, &self.cipher, false)?;
+            self.databases.insert(name.clone(), database);
+            self.log_operation(name, OplogOp::Restore)?;
+        }
+
+        Ok(names)
+    }
+
+    /// Rebuild `database` as it was at `timestamp_ms` into a new database
+    /// `target`, leaving the original untouched. Starts from the newest
+    /// snapshot taken at or before that instant and replays the operation
+    /// log up to it. Returns false if `target` already exists.
+    pub fn restore_to(&mut self, database: &str, timestamp_ms: u64, target: &str) -> Result<bool> {
+        self.check_writable()?;
+        let oplog = self.oplog.clone().ok_or_else(|| {
+            OpenDBSError::Internal("Point-in-time recovery needs the `oplog` engine option".into())
+        })?;
+        check_name("Database", target)?;
+        if self.databases.contains_key(target) || self.root_path.join(target).exists() {
+            return Ok(false);
+        }
+
+        let (entry, manifest) = oplog.snapshot_before(database, timestamp_ms).ok_or_else(|| {
+            OpenDBSError::Internal(format!(
+                "No snapshot of {} was taken at or before {}",
+                database, timestamp_ms
+            ))
+        })?;
+        let records = oplog.records(database, entry.seq, timestamp_ms)?;
+
+        let staging = self.staging_dir()?;
+        let db_path = staging.join(target);
+        manifest.restore_database(&entry.path, database, &db_path)?;
+        Self::replay_operations(&staging, target, &records, self.cipher.as_deref())?;
+
+        let path = self.root_path.join(target);
+        fs::rename(&db_path, &path)?;
+        durability::sync_dir(&self.root_path)?;
+        fs::remove_dir_all(&staging)?;
+
+        let restored = Database::load(&path, target, &self.quarantine, &self.cipher, false)?;
+        self.databases.insert(target.to_string(), restored);
+        self.log_operation(target, OplogOp::CreateDatabase)?;
+        Ok(true)
+    }
+
+    /// Apply logged operations to the copy of a database in `<root>/<database>`
+    fn replay_operations(
+        root: &Path,
+        database: &str,
+        records: &[OplogRecord],
+        cipher: Option<&Keyring>,
+    ) -> Result<()> {
+        let mut writes = Vec::new();
+        for record in records {
+            let (rack, op) = match &record.op {
+                OplogOp::Insert { rack, document } => (rack, WalOp::Insert { document: document.clone() }),
+                OplogOp::Update { rack, document } => (rack, WalOp::Update { document: document.clone() }),
+                OplogOp::Delete { rack, id } => (rack, WalOp::Delete { id: id.clone() }),
+                op => {
+                    // Document writes go out in batches; structural changes apply in between
+                    Self::replay(root, &writes, cipher)?;
+                    writes.clear();
+                    Self::replay_structural(&root.join(database), database, op)?;
+                    continue;
+                }
+            };
+            writes.push(WalRecord {
+                database: database.to_string(),
+                rack: rack.clone(),
+                op,
+            });
+        }
+
+        Self::replay(root, &writes, cipher)
+    }
+
+    /// Apply a logged change to the racks of a database directory
+    fn replay_structural(db_path: &Path, database: &str, op: &OplogOp) -> Result<()> {
+        match op {
+            OplogOp::CreateRack { rack, meta } => {
+                let rack_path = db_path.join(rack);
+                fs::create_dir_all(&rack_path)?;
+                meta.save(&rack_path)?;
+            }
+            OplogOp::DropRack { rack } => {
+                if db_path.join(rack).exists() {
+                    fs::remove_dir_all(db_path.join(rack))?;
+                }
+            }
+            OplogOp::ClearRack { rack } => {
+                for entry in fs::read_dir(db_path.join(rack))? {
+                    let path = entry?.path();
+                    if path.is_file() && path.file_name().and_then(|n| n.to_str()) != Some(META_FILE) {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+            OplogOp::RenameRack { rack, new_name } => {
+                fs::rename(db_path.join(rack), db_path.join(new_name))?;
+            }
+            OplogOp::DuplicateRack {
+                source_database,
+                source_rack,
+                rack,
+            } => {
+                if source_database != database {
+                    return Err(OpenDBSError::Internal(format!(
+                        "Cannot replay a copy of {}/{} from another database",
+                        source_database, source_rack
+                    )));
+                }
+                let copy_path = db_path.join(rack);
+                fs::create_dir_all(&copy_path)?;
+                for entry in fs::read_dir(db_path.join(source_rack))? {
+                    let path = entry?.path();
+                    if path.is_file() {
+                        fs::copy(&path, copy_path.join(path.file_name().unwrap_or_default()))?;
+                    }
+                }
+            }
+            OplogOp::CreateIndex { rack, field, options } => {
+                let rack_path = db_path.join(rack);
+                let mut meta = RackMeta::load(&rack_path)?;
+                if !meta.indexes.contains(field) {
+                    meta.indexes.push(field.clone());
+                    if *options != IndexOptions::default() {
+                        meta.index_options.insert(field.clone(), options.clone());
+                    }
+                    meta.save(&rack_path)?;
+                }
+            }
+            OplogOp::DropIndex { rack, field } => {
+                let rack_path = db_path.join(rack);
+                let mut meta = RackMeta::load(&rack_path)?;
+                meta.indexes.retain(|indexed| indexed != field);
+                meta.index_options.remove(field);
+                meta.save(&rack_path)?;
+            }
+            OplogOp::DropDatabase => {
+                fs::remove_dir_all(db_path)?;
+                fs::create_dir_all(db_path)?;
+            }
+            OplogOp::Restore => {
+                return Err(OpenDBSError::Internal(format!(
+                    "{} was restored from a snapshot after the chosen one; pick a later instant",
+                    database
+                )));
+            }
+            OplogOp::CreateDatabase | OplogOp::Insert { .. } | OplogOp::Update { .. } | OplogOp::Delete { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Swap in restored databases whose restore was interrupted by a crash
+    fn finish_restores(root_path: &Path) -> Result<()> {
+        let restore_dir = root_path.join(RESTORE_DIR);
+        if !restore_dir.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&restore_dir)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                // The replaced copy is under `_tmp`, which is purged later on
+                Self::swap_in_restored(root_path, name)?;
+            }
+        }
+        fs::remove_dir(&restore_dir)?;
+        Ok(())
+    }
+
+    /// Move `_restore/<name>` to `<name>`, moving any existing database into
+    /// `_tmp` first. Returns where the replaced database went.
+    fn swap_in_restored(root_path: &Path, name: &str) -> Result<Option<PathBuf>> {
+        let restored = root_path.join(RESTORE_DIR).join(name);
+        let target = root_path.join(name);
+
+        let replaced = if target.exists() {
+            let trash = staging_dir_in(root_path)?;
+            fs::remove_dir(&trash)?;
+            fs::rename(&target, &trash)?;
+            Some(trash)
+        } else {
+            None
+        };
+
+        fs::rename(&restored, &target)?;
+        durability::sync_dir(root_path)?;
+        durability::sync_dir(&root_path.join(RESTORE_DIR))?;
+        Ok(replaced)
+    }
+
+    /// Insert a document into a rack, encrypting the rack's encrypted fields
+    /// with `field_key`. Without the key those fields cannot be written.
+    pub fn insert(
+        &mut self,
+        database: &str,
+        rack: &str,
+        data: &str,
+        field_key: Option<&str>,
+    ) -> Result<String> {
+        self.check_writable()?;
+        let id = {
+            let rack_ref = self.open_rack(database, rack)?;
+
+            let mut json_data: Value = serde_json::from_str(data)?;
+            let supplied = rack_ref.take_caller_id(&mut json_data)?;
+            rack_ref.seal_fields(&mut json_data, field_key)?;
+            rack_ref.check_unique(&json_data, None)?;
+            let id = match supplied {
+                Some(id) => {
+                    if rack_ref.contains(&id) {
+                        return Err(OpenDBSError::DuplicateId(id));
+                    }
+                    rack_ref.note_id(&id);
+                    id
+                }
+                None => rack_ref.new_id()?,
+            };
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            let document = Document {
+                id: id.clone(),
+                data: json_data,
+                created_at: now,
+                updated_at: now,
+            };
+
+            // Log before touching the segment
+            self.log_write(WalRecord {
+                database: database.to_string(),
+                rack: rack.to_string(),
+                op: WalOp::Insert {
+                    document: document.clone(),
+                },
+            })?;
+
+            // Save to disk, index and store in memory
+            rack_ref.put(document)?;
+            rack_ref.rack.schedule_compaction();
+
+            id
+        };
+
+        self.maybe_checkpoint()?;
+        Ok(id)
+    }
+
+    /// Find documents matching a query. With the rack's `field_key`,
+    /// encrypted fields are decrypted before matching and in the results;
+    /// without it they are matched and returned as ciphertext. `options`
+    /// are JSON `FindOptions` (sort, skip and limit).
+    pub fn find(
+        &self,
+        database: &str,
+        rack: &str,
+        query: &str,
+        field_key: Option<&str>,
+        options: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let rack_ref = self.open_rack(database, rack)?;
+        let cipher = rack_ref.field_cipher(field_key)?;
+
+        let query_obj: Value = serde_json::from_str(query)?;
+        let options: FindOptions = match options {
+            Some(options) => serde_json::from_str(options)?,
+            None => FindOptions::default(),
+        };
+        let query_engine = QueryEngine::new();
+        let predicates = query_engine.equality_predicates(&query_obj);
+        let ranges = query_engine.range_predicates(&query_obj);
+        let candidates = rack_ref.candidates(&predicates, &ranges, cipher.as_ref())?;
+        let encrypted_fields = rack_ref.meta().encrypted_fields;
+
+        let matching = |document: &Document| -> Result<Option<Document>> {
+            match &cipher {
+                Some(cipher) => {
+                    let mut plain = document.clone();
+                    cipher.open_fields(&mut plain.data, &encrypted_fields)?;
+                    Ok(query_engine.matches(&plain.data, &query_obj).then_some(plain))
+                }
+                None => Ok(query_engine.matches(&document.data, &query_obj).then(|| document.clone())),
+            }
+        };
+
+        // As in the Node engine, a limit of 0 means no limit
+        let limit = options.limit.filter(|limit| *limit > 0).unwrap_or(usize::MAX);
+        let mut skip = options.skip;
+        let mut results = Vec::new();
+        // Returns whether more documents are wanted
+        let mut take = |document: Document| -> Result<bool> {
+            if skip > 0 {
+                skip -= 1;
+            } else {
+                results.push(serde_json::to_string(&document)?);
+            }
+            Ok(results.len() < limit)
+        };
+        let mut visit_id = |id: &String| -> Result<bool> {
+            let wanted = candidates.as_ref().is_none_or(|ids| ids.contains(id));
+            match rack_ref.get(id)? {
+                Some(document) if wanted => match matching(&document)? {
+                    Some(document) => take(document),
+                    None => Ok(true),
+                },
+                _ => Ok(true),
+            }
+        };
+
+        let sorted = options
+            .sort
+            .as_ref()
+            .and_then(|sort| rack_ref.sorted_by_index(sort, &predicates, &ranges));
+        match (&options.sort, sorted) {
+            // Walk an ordered index, stopping once the limit is reached
+            (_, Some((ids, unindexed_can_match))) => {
+                let mut more = true;
+                for id in &ids {
+                    if !visit_id(id)? {
+                        more = false;
+                        break;
+                    }
+                }
+                if more && unindexed_can_match {
+                    let indexed: HashSet<&String> = ids.iter().collect();
+                    let mut rest: Vec<String> = match &candidates {
+                        Some(candidates) => candidates.iter().cloned().collect(),
+                        None => rack_ref.ids(),
+                    };
+                    rest.retain(|id| !indexed.contains(id));
+                    rest.sort();
+                    for id in &rest {
+                        if !visit_id(id)? {
+                            break;
+                        }
+                    }
+                }
+            }
+            (Some(sort), None) => {
+                let mut found = Vec::new();
+                let mut collect = |document: &Document| {
+                    found.extend(matching(document)?);
+                    Ok(())
+                };
+                match &candidates {
+                    Some(ids) => {
+                        for id in ids {
+                            if let Some(document) = rack_ref.get(id)? {
+                                collect(&document)?;
+                            }
+                        }
+                    }
+                    None => rack_ref.for_each_document(collect)?,
+                }
+
+                // Documents without a comparable value come last
+                let mut keyed: Vec<_> = found
+                    .into_iter()
+                    .map(|document| (document.data.get(&sort.field).and_then(ordered_key), document))
+                    .collect();
+                keyed.sort_by(|(a_key, a), (b_key, b)| {
+                    let by_value = match (a_key, b_key) {
+                        (Some(a_key), Some(b_key)) if sort.order == SortOrder::Desc => b_key.cmp(a_key),
+                        (Some(a_key), Some(b_key)) => a_key.cmp(b_key),
+                        (Some(_), None) => std::cmp::Ordering::Less,
+                        (None, Some(_)) => std::cmp::Ordering::Greater,
+                        (None, None) => std::cmp::Ordering::Equal,
+                    };
+                    by_value.then_with(|| a.id.cmp(&b.id))
+                });
+                for (_, document) in keyed {
+                    if !take(document)? {
+                        break;
+                    }
+                }
+            }
+            // Only documents the index picked need matching
+            (None, None) => match &candidates {
+                Some(ids) => {
+                    for id in ids {
+                        if !visit_id(id)? {
+                            break;
+                        }
+                    }
+                }
+                None => rack_ref.for_each_document(|document| {
+                    if let Some(document) = matching(document)? {
+                        take(document)?;
+                    }
+                    Ok(())
+                })?,
+            },
+        }
+
+        Ok(results)
+    }
+
+    /// Update a document, encrypting the rack's encrypted fields with
+    /// `field_key` as `insert` does
+    pub fn update(
+        &mut self,
+        database: &str,
+        rack: &str,
+        id: &str,
+        data: &str,
+        field_key: Option<&str>,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        {
+            let rack_ref = self.open_rack(database, rack)?;
+
+            let old_doc = match rack_ref.get(id)? {
+                Some(doc) => doc,
+                None => return Ok(false),
+            };
+
+            let mut json_data: Value = serde_json::from_str(data)?;
+            if rack_ref.take_caller_id(&mut json_data)?.is_some_and(|supplied| supplied != id) {
+                return Err(OpenDBSError::InvalidDocument(format!(
+                    "{} cannot be changed by an update",
+                    transfer::ID_FIELD
+                )));
+            }
+            rack_ref.seal_fields(&mut json_data, field_key)?;
+            rack_ref.check_unique(&json_data, Some(id))?;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            let doc = Document {
+                data: json_data,
+                updated_at: now,
+                ..old_doc.clone()
+            };
+
+            // Log before touching the segment
+            self.log_write(WalRecord {
+                database: database.to_string(),
+                rack: rack.to_string(),
+                op: WalOp::Update {
+                    document: doc.clone(),
+                },
+            })?;
+
+            rack_ref.put(doc)?;
+            rack_ref.rack.schedule_compaction();
+        }
+
+        self.maybe_checkpoint()?;
+        Ok(true)
+    }
+
+    /// Delete a document
+    pub fn delete(&mut self, database: &str, rack: &str, id: &str) -> Result<bool> {
+        self.check_writable()?;
+        {
+            let rack_ref = self.open_rack(database, rack)?;
+
+            if !rack_ref.contains(id) {
+                return Ok(false);
+            }
+
+            // Log before touching the segment
+            self.log_write(WalRecord {
+                database: database.to_string(),
+                rack: rack.to_string(),
+                op: WalOp::Delete { id: id.to_string() },
+            })?;
+
+            rack_ref.remove(id)?;
+            rack_ref.rack.schedule_compaction();
+        }
+
+        self.maybe_checkpoint()?;
+        Ok(true)
+    }
+
+    /// Stream a rack's documents into a file at `path`, returning how many
+    /// were written. The file only appears once the export is complete.
+    pub fn export_rack(
+        &self,
+        database: &str,
+        rack: &str,
+        path: &str,
+        format: &str,
+        options: Option<&str>,
+    ) -> Result<usize> {
+        let format: Format = format.parse()?;
+        let options = TransferOptions::parse(options)?;
+        let rack_ref = self.open_rack(database, rack)?;
+
+        // CSV needs its header before the first row
+        let mut columns = BTreeSet::new();
+        if format == Format::Csv {
+            rack_ref.for_each_document(|document| {
+                transfer::collect_columns(&transfer::to_record(document, options), &mut columns);
+                Ok(())
+            })?;
+        }
+
+        let mut file = durability::AtomicFile::create(Path::new(path))?;
+        let mut exporter = Exporter::new(&mut file, format, columns)?;
+        rack_ref.for_each_document(|document| exporter.write(&transfer::to_record(document, options)))?;
+        let count = exporter.finish()?;
+        file.commit()?;
+        Ok(count)
+    }
+
+    /// Stream documents from a file into a rack. Records that cannot be
+    /// imported are skipped and listed in the report with their line.
+    pub fn import_rack(
+        &mut self,
+        database: &str,
+        rack: &str,
+        path: &str,
+        format: &str,
+        options: Option<&str>,
+    ) -> Result<ImportReport> {
+        self.check_writable()?;
+        let format: Format = format.parse()?;
+        let options = TransferOptions::parse(options)?;
+        let reader = BufReader::new(File::open(path)?);
+        let rack_ref = self.open_rack(database, rack)?;
+
+        let mut report = ImportReport::default();
+        transfer::read_records(reader, format, |line, record| {
+            let record = match record
+                .and_then(|record| transfer::from_record(record, options))
+                .and_then(|record| rack_ref.check_sealed(record))
+                .and_then(|record| {
+                    rack_ref
+                        .check_unique(&record.data, record.id.as_deref())
+                        .map_err(|e| e.to_string())?;
+                    Ok(record)
+                }) {
+                Ok(record) => record,
+                Err(message) => {
+                    report.errors.push(ImportError { line, message });
+                    return Ok(());
+                }
+            };
+
+            let id = match record.id {
+                Some(id) => {
+                    rack_ref.note_id(&id);
+                    id
+                }
+                None => match rack_ref.new_id() {
+                    Ok(id) => id,
+                    Err(e) => {
+                        report.errors.push(ImportError {
+                            line,
+                            message: e.to_string(),
+                        });
+                        return Ok(());
+                    }
+                },
+            };
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let created_at = record.created_at.unwrap_or(now);
+            let document = Document {
+                id,
+                data: record.data,
+                created_at,
+                updated_at: record.updated_at.unwrap_or(created_at),
+            };
+
+            // Imported ids replace existing documents
+            let op = match rack_ref.contains(&document.id) {
+                true => WalOp::Update {
+                    document: document.clone(),
+                },
+                false => WalOp::Insert {
+                    document: document.clone(),
+                },
+            };
+            self.log_write(WalRecord {
+                database: database.to_string(),
+                rack: rack.to_string(),
+                op,
+            })?;
+
+            rack_ref.put(document)?;
+            report.imported += 1;
+            self.maybe_checkpoint()
+        })?;
+
+        rack_ref.rack.schedule_compaction();
+        drop(rack_ref);
+        self.maybe_checkpoint()?;
+        Ok(report)
+    }
+
+    /// Fuzzy search
+    pub fn fuzzy_search(
+        &self,
+        database: &str,
+        rack: &str,
+        field: &str,
+        query: &str,
+        threshold: f64,
+    ) -> Result<Vec<String>> {
+        let rack_ref = self.open_rack(database, rack)?;
+
+        let mut results = Vec::new();
+
+        rack_ref.for_each_document(|document| {
+            if let Some(value) = document.data.get(field) {
+                if let Some(text) = value.as_str() {
+                    let similarity = strsim::jaro_winkler(query, text);
+                    if similarity >= threshold {
+                        results.push(serde_json::to_string(document)?);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(results)
+    }
+
+    /// Declare an index on a top-level field of a rack and index the
+    /// documents it already holds. `options` are JSON `IndexOptions`.
+    /// Returns false if the field is indexed already.
+    pub fn create_index(&mut self, database: &str, rack: &str, field: &str, options: Option<&str>) -> Result<bool> {
+        self.check_writable()?;
+        let mut options: IndexOptions = match options {
+            Some(options) => serde_json::from_str(options)?,
+            None => IndexOptions::default(),
+        };
+        if !options.fields.is_empty() {
+            options.kind = IndexKind::Ordered;
+        }
+
+        let rack_ref = self.open_rack(database, rack)?;
+        if !rack_ref.create_index(field, &options)? {
+            return Ok(false);
+        }
+        drop(rack_ref);
+
+        self.log_operation(
+            database,
+            OplogOp::CreateIndex {
+                rack: rack.to_string(),
+                field: field.to_string(),
+                options,
+            },
+        )?;
+        Ok(true)
+    }
+
+    /// Drop the index on `field` of a rack. Returns false if there was none.
+    pub fn drop_index(&mut self, database: &str, rack: &str, field: &str) -> Result<bool> {
+        self.check_writable()?;
+        let rack_ref = self.open_rack(database, rack)?;
+        if !rack_ref.drop_index(field)? {
+            return Ok(false);
+        }
+        drop(rack_ref);
+
+        self.log_operation(
+            database,
+            OplogOp::DropIndex {
+                rack: rack.to_string(),
+                field: field.to_string(),
+            },
+        )?;
+        Ok(true)
+    }
+
+    /// Indexes a rack declares, with their type, uniqueness and fields
+    pub fn list_indexes(&self, database: &str, rack: &str) -> Result<Vec<IndexInfo>> {
+        let db = self
+            .databases
+            .get(database)
+            .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
+
+        let rack_ref = db
+            .racks
+            .get(rack)
+            .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+
+        Ok(rack_ref.meta().index_infos())
+    }
+
+    /// Compact a rack's files, returning a JSON `CompactionReport`
+    pub fn compact(&self, database: &str, rack: &str) -> Result<String> {
+        self.check_writable()?;
+        let rack_ref = self.open_rack(database, rack)?;
+        let report = rack_ref.compact()?;
+        Ok(serde_json::to_string(&report)?)
+    }
+
+    /// Make `new_key` the active encryption key and re-encrypt every rack
+    /// with it in the background. Older keys stay configured, since
+    /// snapshots and the operation log may still need them.
+    pub fn rotate_key(&self, new_key: &str) -> Result<()> {
+        self.check_writable()?;
+        let keyring = self
+            .cipher
+            .clone()
+            .ok_or_else(|| OpenDBSError::Encryption("Encryption is not enabled".into()))?;
+        keyring.rotate(new_key)?;
+
+        let racks: Vec<Arc<Rack>> = self
+            .databases
+            .iter()
+            .flat_map(|db| db.racks.iter().map(|rack| Arc::clone(rack.value())).collect::<Vec<_>>())
+            .collect();
+        keyring.begin_rotation();
+        std::thread::spawn(move || {
+            for rack in racks {
+                if let Err(e) = rack.reencrypt() {
+                    tracing::error!("Re-encrypting {} failed: {}", rack.path.display(), e);
+                }
+            }
+            keyring.end_rotation();
+            tracing::info!("Re-encrypted all racks with key {}", keyring.active_key_id());
+        });
+        Ok(())
+    }
+
+    /// Check every rack of `database` (or of all databases) for damaged
+    /// files, id mismatches, a stale `next_id` and index drift. With `repair`
+    /// the index is rebuilt, `next_id` fixed and orphaned files dropped.
+    pub fn verify(&self, database: Option<&str>, repair: bool) -> Result<VerifyReport> {
+        if repair {
+            self.check_writable()?;
+        }
+        if let Some(name) = database {
+            if !self.databases.contains_key(name) {
+                return Err(OpenDBSError::DatabaseNotFound(name.to_string()));
+            }
+        }
+
+        let mut report = VerifyReport::default();
+        for db in self.databases.iter() {
+            if database.is_some_and(|name| name != db.key()) {
+                continue;
+            }
+
+            let racks: Vec<Arc<Rack>> = db.racks.iter().map(|rack| Arc::clone(rack.value())).collect();
+            for rack in racks {
+                let handle = match rack.acquire() {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        report.issues.push(verify::VerifyIssue {
+                            database: db.key().clone(),
+                            rack: rack.name.clone(),
+                            kind: verify::IssueKind::UnreadableFile,
+                            detail: e.to_string(),
+                            repaired: false,
+                        });
+                        continue;
+                    }
+                };
+                verify::verify_rack(db.key(), &handle, repair, &self.quarantine, &mut report)?;
+                self.enforce_memory_budget(&handle);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// A rack's `rack.meta` contents as JSON
+    pub fn get_rack_info(&self, database: &str, rack: &str) -> Result<String> {
+        let db = self
+            .databases
+            .get(database)
+            .ok_or_else(|| OpenDBSError::DatabaseNotFound(database.to_string()))?;
+
+        let rack_ref = db
+            .racks
+            .get(rack)
+            .ok_or_else(|| OpenDBSError::RackNotFound(rack.to_string()))?;
+
+        Ok(serde_json::to_string(&rack_ref.meta())?)
+    }
+
+    /// Files skipped while loading, as a JSON array of `SkippedEntry`
+    pub fn get_load_report(&self) -> Result<String> {
+        let report: Vec<SkippedEntry> = self.quarantine.report();
+        Ok(serde_json::to_string(&report)?)
+    }
+
+    /// Get storage statistics. Document and byte counts cover the racks
+    /// currently loaded in memory.
+    pub fn get_stats(&self) -> Result<String> {
+        let mut stats = HashMap::new();
+        stats.insert("databases", self.databases.len());
+
+        let mut total_racks = 0;
+        let mut loaded_racks = 0;
+        let mut total_docs = 0;
+        let mut raw_bytes = 0;
+        let mut stored_bytes = 0;
+
+        for db in self.databases.iter() {
+            total_racks += db.racks.len();
+            for rack in db.racks.iter() {
+                if rack.is_loaded() {
+                    loaded_racks += 1;
+                }
+                total_docs += rack.document_count();
+                for size in rack.sizes.iter() {
+                    raw_bytes += size.raw as usize;
+                    stored_bytes += size.stored as usize;
+                }
+            }
+        }
+
+        stats.insert("racks", total_racks);
+        stats.insert("loaded_racks", loaded_racks);
+        stats.insert("documents", total_docs);
+        stats.insert("uncompressed_bytes", raw_bytes);
+        stats.insert("compressed_bytes", stored_bytes);
+        if let Some(keyring) = &self.cipher {
+            stats.insert("encryption_keys", keyring.key_count());
+            stats.insert("key_rotations_running", keyring.rotations_running());
+        }
+
+        Ok(serde_json::to_string(&stats)?)
+    }
+}
+
+impl Database {
+    fn load(
+        path: &Path,
+        name: &str,
+        quarantine: &Arc<Quarantine>,
+        cipher: &Option<Arc<Keyring>>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let racks = DashMap::new();
 
         // Load racks
         for entry in fs::read_dir(path)? {
             let entry = entry?;
             let rack_path = entry.path();
 
-            if rack_path.is_dir() {
-                let rack_name = rack_path
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .ok_or_else(|| OpenDBSError::Internal("Invalid rack name".into()))?
-                    .to_string();
+            if rack_path.is_dir() {
+                let rack_name = rack_path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .ok_or_else(|| OpenDBSError::Internal("Invalid rack name".into()))?
+                    .to_string();
+
+                // Documents are only read on first access
+                let rack = Rack::open(
+                    &rack_path,
+                    name,
+                    &rack_name,
+                    Arc::clone(quarantine),
+                    cipher.clone(),
+                    read_only,
+                );
+                racks.insert(rack_name, Arc::new(rack));
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            racks,
+        })
+    }
+}
+
+impl Rack {
+    fn new(
+        name: &str,
+        path: &Path,
+        database: &str,
+        meta: RackMeta,
+        quarantine: Arc<Quarantine>,
+        cipher: Option<Arc<Keyring>>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            database: database.to_string(),
+            documents: DashMap::new(),
+            locations: DashMap::new(),
+            mapped: MappedSegments::new(path),
+            next_id: AtomicU64::new(1),
+            index: crate::index::Index::with_fields(meta.indexed_fields()).with_compound(meta.compound_indexes()),
+            index_dirty: AtomicBool::new(false),
+            settings: meta.settings.clone(),
+            meta: Mutex::new(meta),
+            sizes: DashMap::new(),
+            writer: Mutex::new(None),
+            disk_bytes: AtomicU64::new(0),
+            live_bytes: AtomicU64::new(0),
+            compaction: Mutex::new(()),
+            compacting: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            resident: Arc::new(RwLock::new(true)),
+            last_access: AtomicU64::new(ACCESS_CLOCK.fetch_add(1, Ordering::SeqCst)),
+            resident_bytes: AtomicU64::new(0),
+            quarantine,
+            cipher,
+            read_only: false,
+            logged: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Open an existing rack without reading its documents
+    fn open(
+        path: &Path,
+        database: &str,
+        name: &str,
+        quarantine: Arc<Quarantine>,
+        cipher: Option<Arc<Keyring>>,
+        read_only: bool,
+    ) -> Self {
+        let meta = match RackMeta::load(path) {
+            Ok(meta) => {
+                if RackMeta::needs_upgrade(path) && !read_only {
+                    if let Err(e) = meta.save(path) {
+                        tracing::warn!("Could not write {} for {}: {}", META_FILE, path.display(), e);
+                    }
+                }
+                meta
+            }
+            Err(e) => {
+                quarantine.move_file(database, name, &path.join(META_FILE), &e.to_string());
+                RackMeta::new(RackOptions::default())
+            }
+        };
+
+        let mut rack = Self::new(name, path, database, meta, quarantine, cipher);
+        rack.read_only = read_only;
+        *rack.resident.write() = false;
+        rack
+    }
+
+    /// Make sure the documents are in memory and keep them there until the
+    /// returned handle is dropped
+    pub(crate) fn acquire(self: &Arc<Self>) -> Result<RackHandle> {
+        let mut resident = self.resident.read_arc();
+        if !*resident {
+            drop(resident);
+            let mut loading = self.resident.write_arc();
+            if !*loading {
+                // An unreadable rack is left in place and stays offline
+                if let Err(e) = self.load_contents() {
+                    self.clear_contents();
+                    self.quarantine.note(&self.database, &self.name, &self.path, &e.to_string());
+                    return Err(e);
+                }
+                *loading = true;
+            }
+            resident = ArcRwLockWriteGuard::downgrade(loading);
+        }
+
+        let now = ACCESS_CLOCK.fetch_add(1, Ordering::SeqCst) + 1;
+        self.last_access.store(now, Ordering::SeqCst);
+
+        Ok(RackHandle {
+            rack: Arc::clone(self),
+            _resident: resident,
+        })
+    }
+
+    /// Whether the documents are currently in memory
+    fn is_loaded(&self) -> bool {
+        self.resident.try_read().is_some_and(|resident| *resident)
+    }
+
+    /// Drop the in-memory documents unless the rack is in use. Files and
+    /// `next_id` are kept, so the next access simply reloads them.
+    fn evict(&self) -> bool {
+        let Some(mut resident) = self.resident.try_write() else {
+            return false;
+        };
+        if !*resident {
+            return false;
+        }
+
+        // Reloading can then skip rebuilding the index
+        if let Err(e) = self.write_index() {
+            tracing::warn!("Could not save the index of {}: {}", self.path.display(), e);
+        }
+        self.clear_contents();
+        *resident = false;
+        tracing::debug!("Evicted rack {}", self.path.display());
+        true
+    }
+
+    fn clear_contents(&self) {
+        self.documents.clear();
+        self.documents.shrink_to_fit();
+        self.locations.clear();
+        self.locations.shrink_to_fit();
+        self.mapped.clear();
+        self.sizes.clear();
+        self.sizes.shrink_to_fit();
+        self.index.clear();
+        self.resident_bytes.store(0, Ordering::SeqCst);
+    }
+
+    /// Read the rack's documents, setting aside anything unreadable instead of failing
+    fn load_contents(&self) -> Result<()> {
+        let (path, database, name) = (self.path.as_path(), self.database.as_str(), self.name.as_str());
+        let quarantine = &self.quarantine;
+        let mut max_id = 0u64;
+        let mut disk_bytes = 0u64;
+
+        // A compacted segment supersedes every older segment and legacy file
+        let segments = segment::list_segments(path)?;
+        let start = segment::compacted_base(&segments);
+        let watermark = self.index_watermark()?;
+
+        // Load legacy one-file-per-document storage first; segments override it
+        for doc_path in legacy_files(path)? {
+            let file = File::open(&doc_path)?;
+            let file_len = file.metadata()?.len();
+            if start.is_some() {
+                disk_bytes += file_len;
+                continue;
+            }
+
+            let document: Document = match serde_json::from_reader(BufReader::new(file)) {
+                Ok(document) => document,
+                Err(e) => {
+                    quarantine.move_file(database, name, &doc_path, &e.to_string());
+                    continue;
+                }
+            };
+            disk_bytes += file_len;
+
+            if let Ok(id_num) = document.id.parse::<u64>() {
+                max_id = max_id.max(id_num);
+            }
+
+            let size = RecordSize {
+                raw: file_len,
+                stored: file_len,
+                disk: file_len,
+            };
+            self.sizes.insert(document.id.clone(), size);
+            self.documents.insert(document.id.clone(), document);
+        }
+
+        // Replay segments oldest first
+        for (position, (number, seg_path)) in segments.iter().enumerate() {
+            if start.is_some_and(|start| position < start) {
+                disk_bytes += fs::metadata(seg_path)?.len();
+                continue;
+            }
+
+            let scan = segment::scan_segment(seg_path, |record, offset| {
+                // Ids of deleted documents still count, so they are never reused
+                if let Ok(id_num) = record.id.parse::<u64>() {
+                    max_id = max_id.max(id_num);
+                }
+
+                match record.kind {
+                    RecordKind::Put => match record.to_document(self.cipher.as_deref()) {
+                        Ok((document, size)) if self.settings.mmap => {
+                            let location = RecordLocation {
+                                segment: *number,
+                                offset,
+                                len: size.disk,
+                            };
+                            self.sizes.insert(document.id.clone(), size);
+                            self.documents.remove(&document.id);
+                            self.locations.insert(document.id, location);
+                        }
+                        Ok((document, size)) => {
+                            self.sizes.insert(document.id.clone(), size);
+                            self.documents.insert(document.id.clone(), document);
+                        }
+                        // A wrong key must not get every record set aside
+                        Err(e @ OpenDBSError::Encryption(_)) => return Err(e),
+                        Err(e) => quarantine.save_bytes(
+                            database,
+                            name,
+                            seg_path,
+                            &format!(".{}.record", offset),
+                            &record.payload,
+                            &format!("Unreadable record for document {}: {}", record.id, e),
+                        ),
+                    },
+                    RecordKind::Delete => {
+                        self.sizes.remove(&record.id);
+                        self.documents.remove(&record.id);
+                        self.locations.remove(&record.id);
+                    }
+                }
+                Ok(())
+            });
+
+            let scan = match scan {
+                Ok(scan) => scan,
+                Err(OpenDBSError::Corruption(reason)) => {
+                    quarantine.move_file(database, name, seg_path, &reason);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Set aside a damaged tail and cut it off so appends stay readable
+            if !scan.is_complete() {
+                let reason = format!("Damaged data after offset {}", scan.valid_len);
+                if self.read_only {
+                    // Records before the damage were loaded; the file stays as it is
+                    quarantine.note(database, name, seg_path, &reason);
+                } else if scan.valid_len == 0 {
+                    quarantine.move_file(database, name, seg_path, &reason);
+                    continue;
+                } else {
+                    let mut file = fs::OpenOptions::new().read(true).write(true).open(seg_path)?;
+                    let mut tail = Vec::new();
+                    file.seek(SeekFrom::Start(scan.valid_len))?;
+                    file.read_to_end(&mut tail)?;
+                    quarantine.save_bytes(database, name, seg_path, ".tail", &tail, &reason);
+                    file.set_len(scan.valid_len)?;
+                    file.sync_all()?;
+                }
+            }
+            disk_bytes += scan.valid_len;
+        }
+
+        // Logged writes the files may be missing, for read-only engines
+        for op in self.logged.lock().iter() {
+            match op {
+                WalOp::Insert { document } | WalOp::Update { document } => {
+                    if let Ok(id_num) = document.id.parse::<u64>() {
+                        max_id = max_id.max(id_num);
+                    }
+                    let raw = serde_json::to_vec(&document.data)?.len() as u64;
+                    let size = RecordSize {
+                        raw,
+                        stored: raw,
+                        disk: 0,
+                    };
+                    self.sizes.insert(document.id.clone(), size);
+                    self.locations.remove(&document.id);
+                    self.documents.insert(document.id.clone(), document.clone());
+                }
+                WalOp::Delete { id } => {
+                    self.sizes.remove(id);
+                    self.documents.remove(id);
+                    self.locations.remove(id);
+                }
+            }
+        }
+
+        // The saved index only holds if the files are unchanged since
+        let saved = match &watermark {
+            Some(watermark) if self.logged.lock().is_empty() => {
+                self.index.load(path, watermark, self.cipher.as_deref())?
+            }
+            _ => false,
+        };
+        if !saved {
+            self.rebuild_index()?;
+        }
+
+        let live_bytes = self.sizes.iter().map(|size| size.disk).sum();
+        let resident_bytes = match self.settings.mmap {
+            true => 0,
+            false => self.sizes.iter().map(|size| size.raw).sum(),
+        };
+        // Evicted racks keep counting from where they were
+        self.next_id.fetch_max(max_id.saturating_add(1), Ordering::SeqCst);
+        self.disk_bytes.store(disk_bytes, Ordering::SeqCst);
+        self.live_bytes.store(live_bytes, Ordering::SeqCst);
+        self.resident_bytes.store(resident_bytes, Ordering::SeqCst);
+
+        Ok(())
+    }
 
-                let rack = Rack::load(&rack_path, &rack_name)?;
-                racks.insert(rack_name, rack);
+    /// The rack's metadata
+    pub fn meta(&self) -> RackMeta {
+        self.meta.lock().clone()
+    }
+
+    /// A fresh id for an inserted document, following the rack's id strategy
+    pub(crate) fn new_id(&self) -> Result<String> {
+        let strategy = self.meta.lock().id_strategy;
+        Ok(match strategy {
+            IdStrategy::Counter => self.next_id.fetch_add(1, Ordering::SeqCst).to_string(),
+            IdStrategy::UuidV4 => uuid::Uuid::new_v4().to_string(),
+            IdStrategy::UuidV7 => uuid::Uuid::now_v7().to_string(),
+            IdStrategy::Ulid => ulid::Ulid::new().to_string(),
+            IdStrategy::Caller => {
+                return Err(OpenDBSError::InvalidDocument(format!(
+                    "Rack {} needs an {} in every document",
+                    self.name,
+                    transfer::ID_FIELD
+                )))
             }
+        })
+    }
+
+    /// Take `_id` out of a document written to a rack whose ids come from
+    /// callers. Other racks keep `_id` as ordinary data.
+    fn take_caller_id(&self, data: &mut Value) -> Result<Option<String>> {
+        match self.meta.lock().id_strategy {
+            IdStrategy::Caller => take_id(data),
+            _ => Ok(None),
         }
+    }
 
-        Ok(Self {
-            name: name.to_string(),
-            path: path.to_path_buf(),
-            racks,
+    /// Keep the counter past an id chosen by the caller, so it is never
+    /// handed out again
+    pub(crate) fn note_id(&self, id: &str) {
+        if let Ok(id_num) = id.parse::<u64>() {
+            self.next_id.fetch_max(id_num.saturating_add(1), Ordering::SeqCst);
+        }
+    }
+
+    /// Tombstone for the last counter id if no live document has it.
+    /// Compaction drops tombstones, and this one keeps the counter from
+    /// going back to ids already handed out when the rack is next loaded.
+    fn high_water_mark(&self) -> Option<SegmentRecord> {
+        let last_id = self.next_id.load(Ordering::SeqCst).saturating_sub(1).to_string();
+        (last_id != "0" && !self.contains(&last_id)).then(|| SegmentRecord::delete(&last_id))
+    }
+
+    /// Ids of the documents that can satisfy every equality and range
+    /// predicate, intersected from the index smallest first, or None if no
+    /// predicate is indexed. Deterministic encrypted fields are looked up by the
+    /// ciphertext of the value when the field cipher is given.
+    pub(crate) fn candidates(
+        &self,
+        predicates: &[(&str, &Value)],
+        ranges: &[(&str, Vec<(RangeOp, &Value)>)],
+        cipher: Option<&FieldCipher>,
+    ) -> Result<Option<HashSet<String>>> {
+        let encrypted = self.meta.lock().encrypted_fields.clone();
+        let mut sets = Vec::new();
+        for &(field, value) in predicates {
+            let ids = match (encrypted.get(field), cipher) {
+                (Some(&FieldEncryption::Deterministic), Some(cipher)) => {
+                    let sealed = cipher.seal_value(field, value, FieldEncryption::Deterministic)?;
+                    self.index.candidates(field, &sealed)
+                }
+                _ => self.index.candidates(field, value),
+            };
+            sets.extend(ids);
+        }
+        for (field, ops) in ranges {
+            sets.extend(self.index.range(field, ops));
+        }
+        sets.extend(self.index.compound_candidates(predicates, ranges));
+
+        sets.sort_by_key(HashSet::len);
+        let mut sets = sets.into_iter();
+        let Some(mut candidates) = sets.next() else {
+            return Ok(None);
+        };
+        for ids in sets {
+            if candidates.is_empty() {
+                break;
+            }
+            candidates.retain(|id| ids.contains(id));
+        }
+        Ok(Some(candidates))
+    }
+
+    /// Fail with `UniqueViolation` if a document other than `id` holds one
+    /// of `data`'s values of a unique index. Writers hold the engine
+    /// exclusively, so nothing can take the value between the check and
+    /// the write.
+    pub(crate) fn check_unique(&self, data: &Value, id: Option<&str>) -> Result<()> {
+        let unique: Vec<String> = {
+            let meta = self.meta.lock();
+            meta.indexes
+                .iter()
+                .filter(|field| meta.index_options(field).unique)
+                .cloned()
+                .collect()
+        };
+        for field in unique {
+            let Some(value) = data.get(&field) else {
+                continue;
+            };
+            // Only scalar values are constrained, and equal numbers clash
+            // however they are written
+            let Some(key) = ordered_key(value) else {
+                continue;
+            };
+            let ids: Vec<String> = match self.index.candidates(&field, value) {
+                Some(ids) => ids.into_iter().collect(),
+                // Without its index the constraint is checked against every document
+                None => self.ids(),
+            };
+            // Candidates share an index entry, which is not exact equality
+            for other in ids.iter().filter(|other| Some(other.as_str()) != id) {
+                if let Some(existing) = self.get(other)? {
+                    if existing.data.get(&field).and_then(ordered_key).as_ref() == Some(&key) {
+                        return Err(OpenDBSError::UniqueViolation {
+                            field,
+                            value: value.to_string(),
+                            existing_id: other.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids in the order `sort` asks for, read from an ordered index on its
+    /// field and narrowed by range predicates on that field. The flag tells
+    /// whether documents the index leaves out (the field is missing or not
+    /// a scalar) can still match; they sort last. None without an ordered
+    /// index on the field.
+    pub(crate) fn sorted_by_index(
+        &self,
+        sort: &SortSpec,
+        predicates: &[(&str, &Value)],
+        ranges: &[(&str, Vec<(RangeOp, &Value)>)],
+    ) -> Option<(Vec<String>, bool)> {
+        let ops: Vec<_> = ranges
+            .iter()
+            .filter(|(field, _)| *field == sort.field)
+            .flat_map(|(_, ops)| ops.iter().copied())
+            .collect();
+        let ids = self.index.scan(&sort.field, &ops, sort.order == SortOrder::Desc)?;
+        let constrained = !ops.is_empty() || predicates.iter().any(|(field, _)| *field == sort.field);
+        Some((ids, !constrained))
+    }
+
+    /// Cipher for the rack's encrypted fields, checking `field_key` against
+    /// the stored fingerprint. `None` without a key or encrypted fields.
+    fn field_cipher(&self, field_key: Option<&str>) -> Result<Option<FieldCipher>> {
+        let (Some(field_key), Some(key_check)) = (field_key, self.meta.lock().field_key_check.clone()) else {
+            return Ok(None);
+        };
+        let cipher = FieldCipher::new(field_key)?;
+        if cipher.key_check() != key_check {
+            return Err(OpenDBSError::Encryption(format!("Wrong field key for rack {}", self.name)));
+        }
+        Ok(Some(cipher))
+    }
+
+    /// Encrypt the declared fields of a document written by a caller.
+    /// Values are always encrypted here; only internal rewrites of stored
+    /// documents keep ciphertext as it is.
+    fn seal_fields(&self, data: &mut Value, field_key: Option<&str>) -> Result<()> {
+        let fields = self.meta.lock().encrypted_fields.clone();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        match self.field_cipher(field_key)? {
+            Some(cipher) => cipher.seal_fields(data, &fields),
+            None => match fields.keys().find(|field| data.get(field.as_str()).is_some()) {
+                Some(field) => Err(OpenDBSError::Encryption(format!(
+                    "Field {} of rack {} is encrypted; writing it needs the field key",
+                    field, self.name
+                ))),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Refuse an imported record holding plaintext in an encrypted field
+    fn check_sealed(&self, record: transfer::ImportedRecord) -> std::result::Result<transfer::ImportedRecord, String> {
+        let meta = self.meta.lock();
+        match crypto::plaintext_field(&record.data, &meta.encrypted_fields) {
+            Some(field) => Err(format!("Field {} must be imported encrypted", field)),
+            None => Ok(record),
+        }
+    }
+
+    /// Number of live documents
+    pub fn document_count(&self) -> usize {
+        self.documents.len() + self.locations.len()
+    }
+
+    /// Whether a live document with this id exists
+    pub fn contains(&self, id: &str) -> bool {
+        self.documents.contains_key(id) || self.locations.contains_key(id)
+    }
+
+    /// Ids of all live documents
+    pub fn ids(&self) -> Vec<String> {
+        self.documents
+            .iter()
+            .map(|entry| entry.key().clone())
+            .chain(self.locations.iter().map(|entry| entry.key().clone()))
+            .collect()
+    }
+
+    /// Look up a live document, decoding it from its segment if mapped
+    pub fn get(&self, id: &str) -> Result<Option<Document>> {
+        if let Some(doc) = self.documents.get(id) {
+            return Ok(Some(doc.clone()));
+        }
+
+        let _pin = self.mapped.pin();
+        match self.locations.get(id).map(|location| *location) {
+            Some(location) => self.decode(id, location).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Visit every live document. Mapped documents are decoded one at a
+    /// time, so only the one being visited is held in memory.
+    pub fn for_each_document<F>(&self, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&Document) -> Result<()>,
+    {
+        for entry in self.documents.iter() {
+            visit(entry.value())?;
+        }
+
+        let _pin = self.mapped.pin();
+        for entry in self.locations.iter() {
+            let document = self.decode(entry.key(), *entry.value())?;
+            visit(&document)?;
+        }
+        Ok(())
+    }
+
+    /// Decode a mapped document, checking the record is the one expected
+    fn decode(&self, id: &str, location: RecordLocation) -> Result<Document> {
+        let record = self.mapped.read(location)?;
+        if record.kind != RecordKind::Put || record.id != id {
+            return Err(OpenDBSError::Corruption(format!(
+                "Segment {} of {} holds no document {} at offset {}",
+                location.segment,
+                self.path.display(),
+                id,
+                location.offset
+            )));
+        }
+        Ok(record.to_document(self.cipher.as_deref())?.0)
+    }
+
+    /// Persist a document and make it visible, returning the version it replaced
+    pub(crate) fn put(&self, doc: Document) -> Result<Option<Document>> {
+        let (record, size) = SegmentRecord::put(&doc, self.settings.compression, self.cipher.as_deref())?;
+
+        // Publishing under the writer lock guarantees that everything in a
+        // sealed segment is already reflected in `documents` (see `compact`)
+        self.with_writer(|writer| {
+            let (added, location) = writer.append(&record)?;
+            self.disk_bytes.fetch_add(added, Ordering::SeqCst);
+            self.track_size(&doc.id, Some(size));
+
+            let id = doc.id.clone();
+            let old = if self.settings.mmap {
+                let old = self.get(&id)?;
+                self.locations.insert(id.clone(), location);
+                self.documents.remove(&id);
+                old
+            } else {
+                self.documents.insert(id.clone(), doc.clone())
+            };
+
+            if let Some(old) = &old {
+                self.index.remove_document(&id, &old.data);
+            }
+            self.index.index_document(&id, &doc.data);
+            self.index_dirty.store(true, Ordering::SeqCst);
+
+            Ok(old)
         })
     }
-}
 
-impl Rack {
-    fn load(path: &Path, name: &str) -> Result<Self> {
-        let documents = DashMap::new();
-        let index = crate::index::Index::new();
-        let mut max_id = 0u64;
+    /// Persist a tombstone and drop the document, returning it if it existed
+    fn remove(&self, id: &str) -> Result<Option<Document>> {
+        self.with_writer(|writer| {
+            let Some(removed) = self.get(id)? else {
+                return Ok(None);
+            };
 
-        // Load documents
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let doc_path = entry.path();
+            let (added, _) = writer.append(&SegmentRecord::delete(id))?;
+            self.disk_bytes.fetch_add(added, Ordering::SeqCst);
+            self.track_size(id, None);
 
-            if doc_path.is_file() && doc_path.extension().and_then(|s| s.to_str()) == Some("dbs") {
-                let file = File::open(&doc_path)?;
-                let reader = BufReader::new(file);
-                let document: Document = serde_json::from_reader(reader)?;
+            self.documents.remove(id);
+            self.locations.remove(id);
+            self.index.remove_document(id, &removed.data);
+            self.index_dirty.store(true, Ordering::SeqCst);
 
-                if let Ok(id_num) = document.id.parse::<u64>() {
-                    max_id = max_id.max(id_num);
+            Ok(Some(removed))
+        })
+    }
+
+    /// Run `f` with the segment writer locked, opening it on first use
+    fn with_writer<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut SegmentWriter) -> Result<T>,
+    {
+        let mut writer = self.writer.lock();
+        if writer.is_none() {
+            *writer = Some(SegmentWriter::open(&self.path)?);
+        }
+        f(writer.as_mut().unwrap())
+    }
+
+    /// Record the size of a live document, or forget it once deleted
+    fn track_size(&self, id: &str, size: Option<RecordSize>) {
+        let resident = !self.settings.mmap;
+        let old = match size {
+            Some(size) => {
+                self.live_bytes.fetch_add(size.disk, Ordering::SeqCst);
+                if resident {
+                    self.resident_bytes.fetch_add(size.raw, Ordering::SeqCst);
                 }
+                self.sizes.insert(id.to_string(), size)
+            }
+            None => self.sizes.remove(id).map(|(_, size)| size),
+        };
 
-                index.index_document(&document.id, &document.data);
-                documents.insert(document.id.clone(), document);
+        if let Some(old) = old {
+            self.live_bytes.fetch_sub(old.disk, Ordering::SeqCst);
+            if resident {
+                self.resident_bytes.fetch_sub(old.raw, Ordering::SeqCst);
             }
         }
+    }
 
+    /// Make every record appended since the last sync durable
+    pub(crate) fn sync(&self) -> Result<()> {
+        match self.writer.lock().as_ref() {
+            Some(writer) => writer.sync(),
+            None => Ok(()),
+        }
+    }
 
-        Ok(Self {
-            name: name.to_string(),
-            path: path.to_path_buf(),
-            documents,
-            next_id: AtomicU64::new(max_id + 1),
-            index,
+    /// Declare an index on `field` and index the stored documents.
+    /// Returns false if the field is indexed already.
+    fn create_index(&self, field: &str, options: &IndexOptions) -> Result<bool> {
+        let mut meta = self.meta.lock();
+        if meta.indexes.iter().any(|indexed| indexed == field) {
+            return Ok(false);
+        }
+        meta.check_index(field, options)?;
+        if options.unique {
+            self.check_distinct(field)?;
+        }
+        meta.indexes.push(field.to_string());
+        if *options != IndexOptions::default() {
+            meta.index_options.insert(field.to_string(), options.clone());
+        }
+        meta.save(&self.path)?;
+        drop(meta);
+
+        match options.fields.is_empty() {
+            true => self.index.add_field(field, options.kind),
+            false => self.index.add_compound(field, options.fields.clone()),
+        };
+        self.for_each_document(|document| {
+            self.index.index_field(field, &document.id, &document.data);
+            Ok(())
+        })?;
+        self.index_dirty.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Fail with `UniqueViolation` if two documents hold the same scalar
+    /// value of `field`
+    fn check_distinct(&self, field: &str) -> Result<()> {
+        // Values are told apart the way `check_unique` does it
+        let mut seen: HashMap<Vec<u8>, String> = HashMap::new();
+        self.for_each_document(|document| {
+            let Some(value) = document.data.get(field) else {
+                return Ok(());
+            };
+            let Some(key) = ordered_key(value) else {
+                return Ok(());
+            };
+            if let Some(existing_id) = seen.get(&key) {
+                return Err(OpenDBSError::UniqueViolation {
+                    field: field.to_string(),
+                    value: value.to_string(),
+                    existing_id: existing_id.clone(),
+                });
+            }
+            seen.insert(key, document.id.clone());
+            Ok(())
         })
     }
 
-    fn save_document(&self, doc: &Document) -> Result<()> {
-        let doc_path = self.path.join(format!("{}.dbs", doc.id));
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(doc_path)?;
+    /// Drop the index on `field`. Returns false if there was none.
+    fn drop_index(&self, field: &str) -> Result<bool> {
+        let mut meta = self.meta.lock();
+        let Some(position) = meta.indexes.iter().position(|indexed| indexed == field) else {
+            return Ok(false);
+        };
+        meta.indexes.remove(position);
+        meta.index_options.remove(field);
+        meta.save(&self.path)?;
+        drop(meta);
+
+        self.index.remove_field(field);
+        self.index_dirty.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Rebuild the index from the documents
+    pub(crate) fn rebuild_index(&self) -> Result<()> {
+        self.index.clear();
+        self.for_each_document(|document| {
+            self.index.index_document(&document.id, &document.data);
+            Ok(())
+        })?;
+        self.index_dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Numbers and lengths of the segment files, which a saved index is
+    /// checked against. None while legacy document files are present.
+    fn index_watermark(&self) -> Result<Option<Vec<(u64, u64)>>> {
+        if !legacy_files(&self.path)?.is_empty() {
+            return Ok(None);
+        }
+        let mut watermark = Vec::new();
+        for (number, seg_path) in segment::list_segments(&self.path)? {
+            watermark.push((number, fs::metadata(&seg_path)?.len()));
+        }
+        Ok(Some(watermark))
+    }
+
+    /// Save the index to `rack.index` if it changed and the rack is loaded
+    pub(crate) fn save_index(&self) -> Result<()> {
+        let Some(resident) = self.resident.try_read() else {
+            return Ok(());
+        };
+        if !*resident {
+            return Ok(());
+        }
+        self.write_index()
+    }
+
+    /// Save the index if it changed; the caller keeps the rack loaded
+    fn write_index(&self) -> Result<()> {
+        if self.read_only || !self.index_dirty.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // Writers publish index changes under the writer lock, so the
+        // entries and file lengths captured under it agree
+        let encoded = {
+            let _writer = self.writer.lock();
+            let Some(watermark) = self.index_watermark()? else {
+                return Ok(());
+            };
+            self.index_dirty.store(false, Ordering::SeqCst);
+            self.index.encode(watermark)?
+        };
+        crate::index::Index::save(&self.path, &encoded, self.cipher.as_deref())
+    }
+
+    /// Remove `rack.index` once the segments it was built from are rewritten
+    fn discard_saved_index(&self) -> Result<()> {
+        self.index_dirty.store(true, Ordering::SeqCst);
+        match fs::remove_file(self.path.join(INDEX_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the share of dead bytes on disk crossed the rack's threshold
+    fn needs_compaction(&self) -> bool {
+        let disk = self.disk_bytes.load(Ordering::SeqCst);
+        if disk < MIN_COMPACTION_BYTES {
+            return false;
+        }
+
+        let live = self.live_bytes.load(Ordering::SeqCst).min(disk);
+        (disk - live) as f64 / disk as f64 >= self.settings.compaction_threshold
+    }
+
+    /// Start a background compaction if the rack needs one and none is running
+    fn schedule_compaction(self: &Arc<Self>) {
+        if !self.needs_compaction() || self.compacting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let rack = Arc::clone(self);
+        std::thread::spawn(move || {
+            match rack.acquire().and_then(|rack| rack.compact()) {
+                Ok(report) => tracing::info!(
+                    "Compacted rack {}: {} documents, {} -> {} bytes",
+                    rack.path.display(),
+                    report.documents,
+                    report.bytes_before,
+                    report.bytes_after
+                ),
+                Err(e) => tracing::error!("Compaction of {} failed: {}", rack.path.display(), e),
+            }
+            rack.compacting.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Rewrite all live documents into one compacted segment, swap it in
+    /// atomically and delete the files it supersedes.
+    ///
+    /// Writers are only held up while the active segment is sealed; readers
+    /// of `documents` are never blocked.
+    pub(crate) fn compact(&self) -> Result<CompactionReport> {
+        let _running = self.compaction.lock();
+        self.compact_files()
+    }
+
+    /// The body of `compact`, for callers already holding `compaction`
+    fn compact_files(&self) -> Result<CompactionReport> {
+        segment::remove_temp_files(&self.path)?;
+        let bytes_before = self.disk_bytes.load(Ordering::SeqCst);
+
+        // Seal the active segment. Everything up to it is now immutable and,
+        // because writers publish under the same lock, visible in `documents`.
+        let base = self.with_writer(|writer| {
+            let sealed = writer.number();
+            writer.roll()?;
+            Ok(sealed)
+        })?;
+        let base_path = segment::segment_path(&self.path, base);
+        let mut removed_bytes = fs::metadata(&base_path)?.len();
+
+        // Readers of mapped documents keep using the old files until the
+        // compacted segment is swapped in below
+        if self.settings.mmap {
+            self.mapped.preload(base)?;
+        }
+
+        // Copy documents that are still live. Writes racing with this land in
+        // newer segments, which take precedence on load.
+        let mut documents = 0;
+        let high_water_mark = self.high_water_mark();
+        let records = self
+            .ids()
+            .into_iter()
+            .filter_map(|id| {
+                let doc = match self.get(&id) {
+                    Ok(doc) => doc?,
+                    Err(e) => return Some(Err(e)),
+                };
+                documents += 1;
+                Some(SegmentRecord::put(&doc, self.settings.compression, self.cipher.as_deref()).map(|(record, _)| record))
+            })
+            .chain(high_water_mark.map(Ok));
+        let (new_len, moved) = segment::write_compacted(&self.path, base, records)?;
+
+        // Everything older is superseded by the compacted segment now
+        for (number, seg_path) in segment::list_segments(&self.path)? {
+            if number < base {
+                removed_bytes += fs::metadata(&seg_path)?.len();
+                fs::remove_file(seg_path)?;
+            }
+        }
+        for doc_path in legacy_files(&self.path)? {
+            removed_bytes += fs::metadata(&doc_path)?.len();
+            fs::remove_file(doc_path)?;
+        }
+        self.discard_saved_index()?;
+        durability::sync_dir(&self.path)?;
+
+        if self.settings.mmap {
+            self.mapped.replace(base, || {
+                for (id, location) in moved {
+                    match self.locations.entry(id) {
+                        // Unless a racing write already moved it past the base
+                        DashEntry::Occupied(mut entry) => {
+                            if entry.get().segment <= base {
+                                entry.insert(location);
+                            }
+                        }
+                        // Legacy documents now live in the compacted segment
+                        DashEntry::Vacant(entry) => {
+                            if self.documents.remove(entry.key()).is_some() {
+                                entry.insert(location);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        self.disk_bytes.fetch_add(new_len, Ordering::SeqCst);
+        let _ = self
+            .disk_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bytes| {
+                Some(bytes.saturating_sub(removed_bytes))
+            });
 
-        let writer = BufWriter::new(file);
-        serde_json::to_writer(writer, doc)?;
+        Ok(CompactionReport {
+            documents,
+            bytes_before,
+            bytes_after: self.disk_bytes.load(Ordering::SeqCst),
+        })
+    }
 
+    /// Rewrite the rack with the active encryption key. A rack that was not
+    /// loaded before is evicted again afterwards.
+    fn reencrypt(self: &Arc<Self>) -> Result<()> {
+        // Closing waits for this, so a rack dropped or renamed since the
+        // rotation started is skipped rather than written to
+        let _running = self.compaction.lock();
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let was_loaded = self.is_loaded();
+        self.acquire()?.compact_files()?;
+        if !was_loaded {
+            self.evict();
+        }
         Ok(())
     }
 
-    fn delete_document(&self, id: &str) -> Result<()> {
-        let doc_path = self.path.join(format!("{}.dbs", id));
-        if doc_path.exists() {
+    /// Drop every document, replacing the rack's files with an empty
+    /// compacted segment. Ids keep counting from where they were.
+    pub(crate) fn clear(&self) -> Result<()> {
+        let _running = self.compaction.lock();
+        segment::remove_temp_files(&self.path)?;
+
+        // Writers stay blocked until the in-memory state is empty as well
+        let mut writer = self.writer.lock();
+        let active = match writer.as_mut() {
+            Some(active) => active,
+            None => writer.insert(SegmentWriter::open(&self.path)?),
+        };
+        let base = active.number();
+        active.roll()?;
+        if self.settings.mmap {
+            self.mapped.preload(base)?;
+        }
+
+        // The empty compacted segment supersedes everything written before
+        // and keeps the last counter id, as compaction does
+        let last_id = self.next_id.load(Ordering::SeqCst).saturating_sub(1);
+        let high_water_mark = (last_id > 0).then(|| SegmentRecord::delete(&last_id.to_string()));
+        segment::write_compacted(&self.path, base, high_water_mark.map(Ok))?;
+        let mut disk_bytes = 0;
+        for (number, seg_path) in segment::list_segments(&self.path)? {
+            if number < base {
+                fs::remove_file(seg_path)?;
+            } else {
+                disk_bytes += fs::metadata(&seg_path)?.len();
+            }
+        }
+        for doc_path in legacy_files(&self.path)? {
             fs::remove_file(doc_path)?;
         }
+        self.discard_saved_index()?;
+        durability::sync_dir(&self.path)?;
+
+        self.mapped.replace(base, || self.locations.clear());
+        self.documents.clear();
+        self.sizes.clear();
+        self.index.clear();
+        self.disk_bytes.store(disk_bytes, Ordering::SeqCst);
+        self.live_bytes.store(0, Ordering::SeqCst);
+        self.resident_bytes.store(0, Ordering::SeqCst);
         Ok(())
     }
+
+    /// Wait for a running compaction and release the rack's files, before
+    /// its directory is moved or dropped
+    fn close(&self) {
+        let _running = self.compaction.lock();
+        self.closed.store(true, Ordering::SeqCst);
+        *self.writer.lock() = None;
+        self.mapped.clear();
+    }
+}
+
+/// Periodically fsync the WAL and operation log until the engine owning them is dropped
+fn spawn_wal_syncer(wal: Weak<WriteAheadLog>, oplog: Option<Weak<OperationLog>>, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Some(wal) = wal.upgrade() else {
+            break;
+        };
+        if let Err(e) = wal.sync() {
+            tracing::error!("Background WAL sync failed: {}", e);
+        }
+        if let Some(oplog) = oplog.as_ref().and_then(Weak::upgrade) {
+            if let Err(e) = oplog.sync() {
+                tracing::error!("Background operation log sync failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Fail unless `name` can name a database or rack directory: a single
+/// path component that is not reserved by the engine
+fn check_name(kind: &str, name: &str) -> Result<()> {
+    if snapshot::is_plain_name(name) {
+        return Ok(());
+    }
+    Err(OpenDBSError::PermissionDenied(format!(
+        "{} names must not be empty, start with '_' or '.' or contain a path separator: {}",
+        kind, name
+    )))
+}
+
+/// Remove `_id` from a document body, returning it as a string
+fn take_id(data: &mut Value) -> Result<Option<String>> {
+    let Value::Object(fields) = data else {
+        return Ok(None);
+    };
+    match fields.remove(transfer::ID_FIELD) {
+        Some(Value::String(id)) if !id.is_empty() => Ok(Some(id)),
+        Some(Value::Number(id)) => Ok(Some(id.to_string())),
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => Err(OpenDBSError::InvalidDocument(format!(
+            "{} must be a non-empty string or a number",
+            transfer::ID_FIELD
+        ))),
+    }
+}
+
+/// Fresh, empty directory under `<root>/_tmp`
+fn staging_dir_in(root_path: &Path) -> Result<PathBuf> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = root_path
+        .join(TMP_DIR)
+        .join(format!("{}-{}", nanos, TMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Legacy one-file-per-document `.dbs` files of a rack directory
+pub(crate) fn legacy_files(rack_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(rack_path)? {
+        let doc_path = entry?.path();
+        if doc_path.is_file() && doc_path.extension().and_then(|s| s.to_str()) == Some("dbs") {
+            files.push(doc_path);
+        }
+    }
+    Ok(files)
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A fresh data directory and an engine over it holding the database `app`
+    pub(crate) fn app_engine(options: EngineOptions) -> (tempfile::TempDir, StorageEngine) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = StorageEngine::new(dir.path().to_str().unwrap(), options).unwrap();
+        engine.create_database("app").unwrap();
+        (dir, engine)
+    }
+
+    #[test]
+    fn test_wal_restores_lost_segment_writes() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine.create_rack("app", "users", None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+        drop(engine);
+
+        // Simulate a crash before the segment write reached the disk
+        let seg_path = segment::segment_path(&dir.path().join("app").join("users"), 1);
+        let file = fs::OpenOptions::new().write(true).open(&seg_path).unwrap();
+        file.set_len(5).unwrap();
+        drop(file);
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        let found = engine.find("app", "users", r#"{"name":"Alice"}"#, None, None).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(engine.wal.as_ref().unwrap().pending(), 0);
+    }
+
+    #[test]
+    fn test_read_only_engine_changes_nothing() {
+        fn listing(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    files.extend(listing(&path));
+                } else {
+                    files.push((path.clone(), fs::read(&path).unwrap()));
+                }
+            }
+            files.sort();
+            files
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let read_only = EngineOptions {
+            read_only: true,
+            ..EngineOptions::default()
+        };
+        assert!(StorageEngine::new(dir.path().join("missing").to_str().unwrap(), read_only.clone()).is_err());
+
+        {
+            let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+            engine.create_database("app").unwrap();
+            engine.create_rack("app", "users", None).unwrap();
+            engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+            engine.checkpoint().unwrap();
+            engine.insert("app", "users", r#"{"name":"Bob"}"#, None).unwrap();
+        }
+
+        // Bob only survives in the WAL
+        let seg_path = segment::segment_path(&dir.path().join("app").join("users"), 1);
+        let len = fs::metadata(&seg_path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&seg_path).unwrap().set_len(len - 3).unwrap();
+        let before = listing(dir.path());
+
+        let mut engine = StorageEngine::new(root, read_only).unwrap();
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 2);
+        assert_eq!(engine.find("app", "users", r#"{"name":"Bob"}"#, None, None).unwrap().len(), 1);
+
+        let denied = |result: Result<bool>| matches!(result, Err(OpenDBSError::PermissionDenied(_)));
+        assert!(matches!(
+            engine.insert("app", "users", "{}", None),
+            Err(OpenDBSError::PermissionDenied(_))
+        ));
+        assert!(denied(engine.update("app", "users", "1", "{}", None)));
+        assert!(denied(engine.delete("app", "users", "1")));
+        assert!(denied(engine.create_database("other")));
+        assert!(denied(engine.create_rack("app", "other", None)));
+        assert!(denied(engine.drop_rack("app", "users")));
+        assert!(engine.compact("app", "users").is_err());
+        assert!(engine.verify(None, true).is_err());
+        engine.verify(None, false).unwrap();
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        assert_eq!(listing(dir.path()), before);
+    }
+
+    #[test]
+    fn test_legacy_documents_are_overridden_by_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let rack_path = dir.path().join("app").join("users");
+        fs::create_dir_all(&rack_path).unwrap();
+        fs::write(
+            rack_path.join("7.dbs"),
+            r#"{"id":"7","data":{"name":"Bob"},"created_at":1,"updated_at":1}"#,
+        )
+        .unwrap();
+
+        {
+            let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+            assert!(engine.update("app", "users", "7", r#"{"name":"Robert"}"#, None).unwrap());
+            assert_eq!(engine.insert("app", "users", "{}", None).unwrap(), "8");
+            engine.checkpoint().unwrap();
+        }
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "users", r#"{"name":"Robert"}"#, None, None).unwrap().len(), 1);
+        assert!(engine.find("app", "users", r#"{"name":"Bob"}"#, None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compressed_rack_roundtrip() {
+        let body = format!(r#"{{"text":"{}"}}"#, "opendbs ".repeat(200));
+
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine
+            .create_rack("app", "logs", Some(r#"{"compression":true}"#))
+            .unwrap();
+        engine.insert("app", "logs", &body, None).unwrap();
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "logs", "{}", None, None).unwrap().len(), 1);
+
+        let stats: HashMap<String, usize> =
+            serde_json::from_str(&engine.get_stats().unwrap()).unwrap();
+        assert!(stats["compressed_bytes"] < stats["uncompressed_bytes"]);
+    }
+
+    #[test]
+    fn test_compaction_keeps_live_documents_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let rack_path = dir.path().join("app").join("users");
+        fs::create_dir_all(&rack_path).unwrap();
+        fs::write(
+            rack_path.join("1.dbs"),
+            r#"{"id":"1","data":{"name":"Legacy"},"created_at":1,"updated_at":1}"#,
+        )
+        .unwrap();
+
+        {
+            let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+            for i in 0..50 {
+                let id = engine.insert("app", "users", &format!(r#"{{"n":{}}}"#, i), None).unwrap();
+                engine.update("app", "users", &id, &format!(r#"{{"n":{}}}"#, i + 1000), None).unwrap();
+                if i % 2 == 0 {
+                    engine.delete("app", "users", &id).unwrap();
+                }
+            }
+
+            let report: Value = serde_json::from_str(&engine.compact("app", "users").unwrap()).unwrap();
+            assert_eq!(report["documents"], 26);
+            assert!(report["bytes_after"].as_u64() < report["bytes_before"].as_u64());
+
+            // Writes after compaction land in the new active segment
+            engine.insert("app", "users", r#"{"n":-1}"#, None).unwrap();
+            engine.checkpoint().unwrap();
+        }
+
+        assert!(!rack_path.join("1.dbs").exists());
+        assert_eq!(segment::list_segments(&rack_path).unwrap().len(), 2);
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 27);
+        assert_eq!(engine.find("app", "users", r#"{"name":"Legacy"}"#, None, None).unwrap().len(), 1);
+        assert_eq!(engine.find("app", "users", r#"{"n":{"$lt":1000}}"#, None, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_batched_durability_keeps_writes() {
+        let options = EngineOptions {
+            durability: Durability::BatchedMs(5),
+            ..EngineOptions::default()
+        };
+
+        let (dir, mut engine) = app_engine(options.clone());
+        let root = dir.path().to_str().unwrap();
+        engine.create_rack("app", "events", None).unwrap();
+        engine.insert("app", "events", r#"{"kind":"login"}"#, None).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        drop(engine);
+
+        let engine = StorageEngine::new(root, options).unwrap();
+        assert_eq!(engine.find("app", "events", "{}", None, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_cold_racks_are_evicted_and_reloaded() {
+        let options = EngineOptions {
+            memory_budget_bytes: Some(1),
+            ..EngineOptions::default()
+        };
+
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine.create_rack("app", "users", None).unwrap();
+        engine.create_rack("app", "orders", None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+        engine.insert("app", "orders", r#"{"total":5}"#, None).unwrap();
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        let mut engine = StorageEngine::new(root, options).unwrap();
+        let is_loaded = |engine: &StorageEngine, rack: &str| {
+            engine.databases.get("app").unwrap().racks.get(rack).unwrap().is_loaded()
+        };
+        assert!(!is_loaded(&engine, "users"));
+        assert!(!is_loaded(&engine, "orders"));
+
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 1);
+        assert!(is_loaded(&engine, "users"));
+
+        // Touching another rack pushes the least recently used one out
+        assert_eq!(engine.find("app", "orders", "{}", None, None).unwrap().len(), 1);
+        assert!(is_loaded(&engine, "orders"));
+        assert!(!is_loaded(&engine, "users"));
+
+        // Reloading keeps ids counting from where they were
+        assert_eq!(engine.insert("app", "users", r#"{"name":"Bob"}"#, None).unwrap(), "2");
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 2);
+        assert!(!is_loaded(&engine, "orders"));
+    }
+
+    #[test]
+    fn test_mapped_rack_reads_from_segments() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine
+            .create_rack("app", "users", Some(r#"{"mmap":true,"compression":true}"#))
+            .unwrap();
+        for name in ["Alice", "Bob", "Carol"] {
+            engine.insert("app", "users", &format!(r#"{{"name":"{}"}}"#, name), None).unwrap();
+        }
+        engine.update("app", "users", "2", r#"{"name":"Robert"}"#, None).unwrap();
+        engine.delete("app", "users", "3").unwrap();
+
+        let rack = engine.open_rack("app", "users").unwrap();
+        assert!(rack.documents.is_empty());
+        assert_eq!(rack.document_count(), 2);
+        drop(rack);
+
+        engine.compact("app", "users").unwrap();
+        engine.insert("app", "users", r#"{"name":"Dave"}"#, None).unwrap();
+        assert_eq!(engine.find("app", "users", r#"{"name":"Robert"}"#, None, None).unwrap().len(), 1);
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 3);
+        assert_eq!(engine.fuzzy_search("app", "users", "name", "Alise", 0.8).unwrap().len(), 1);
+        assert!(engine.verify(None, false).unwrap().issues.is_empty());
+    }
+
+    #[test]
+    fn test_drop_clear_rename_and_duplicate() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine.create_database("archive").unwrap();
+        engine.create_rack("app", "users", Some(r#"{"compression":true}"#)).unwrap();
+        for name in ["Alice", "Bob", "Carol"] {
+            engine.insert("app", "users", &format!(r#"{{"name":"{}"}}"#, name), None).unwrap();
+        }
+
+        assert!(engine.duplicate_rack("app", "users", "archive", "users").unwrap());
+        assert!(engine.duplicate_rack("app", "users", "app", "copy").unwrap());
+        assert!(!engine.duplicate_rack("app", "users", "app", "copy").unwrap());
+        // Targets must stay a plain directory inside their database
+        for bad in ["../escaped", "nested/rack", "..", "_reserved", ""] {
+            let denied = |result: Result<bool>| matches!(result, Err(OpenDBSError::PermissionDenied(_)));
+            assert!(denied(engine.duplicate_rack("app", "users", "app", bad)), "{}", bad);
+            assert!(denied(engine.rename_rack("app", "copy", bad)), "{}", bad);
+            assert!(denied(engine.create_rack("app", bad, None)), "{}", bad);
+        }
+        assert!(!dir.path().join("escaped").exists());
+
+        assert!(engine.clear_rack("app", "users").unwrap());
+        assert!(engine.find("app", "users", "{}", None, None).unwrap().is_empty());
+        assert_eq!(engine.insert("app", "users", r#"{"name":"Dave"}"#, None).unwrap(), "4");
+
+        assert!(engine.rename_rack("app", "copy", "people").unwrap());
+        assert!(!engine.rename_rack("app", "people", "users").unwrap());
+        assert_eq!(engine.insert("app", "people", "{}", None).unwrap(), "4");
+        assert!(engine.drop_rack("app", "people").unwrap());
+        assert!(!engine.drop_rack("app", "people").unwrap());
+        assert!(engine.find("app", "people", "{}", None, None).is_err());
+
+        engine.create_database("scratch").unwrap();
+        assert!(engine.drop_database("scratch").unwrap());
+        assert!(!engine.drop_database("scratch").unwrap());
+        drop(engine);
+
+        // Leftovers of an interrupted drop are purged on the next start
+        fs::create_dir_all(dir.path().join(TMP_DIR).join("1-0").join("users")).unwrap();
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert!(!dir.path().join(TMP_DIR).exists());
+        assert!(!dir.path().join("scratch").exists());
+        assert!(!dir.path().join("app").join("people").exists());
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 1);
+        assert_eq!(engine.find("archive", "users", "{}", None, None).unwrap().len(), 3);
+        assert_eq!(engine.databases.get("app").unwrap().racks.len(), 1);
+
+        let info: Value = serde_json::from_str(&engine.get_rack_info("archive", "users").unwrap()).unwrap();
+        assert_eq!(info["type"], "nosql");
+        assert_eq!(info["settings"]["compression"], true);
+    }
+
+    #[test]
+    fn test_encryption_at_rest_and_key_rotation() {
+        fn contains(dir: &Path, needle: &[u8]) -> bool {
+            fs::read_dir(dir).unwrap().any(|entry| {
+                let path = entry.unwrap().path();
+                match path.is_dir() {
+                    true => contains(&path, needle),
+                    false => fs::read(&path).unwrap().windows(needle.len()).any(|w| w == needle),
+                }
+            })
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let (key_a, key_b) = ("11".repeat(32), "22".repeat(32));
+        let options = |key: &str| EngineOptions {
+            encryption: Some(EncryptionOptions {
+                key: Some(key.to_string()),
+                ..EncryptionOptions::default()
+            }),
+            ..EngineOptions::default()
+        };
+
+        {
+            let mut engine = StorageEngine::new(root, options(&key_a)).unwrap();
+            engine.create_database("app").unwrap();
+            engine.create_rack("app", "users", None).unwrap();
+            engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+        }
+        assert!(!contains(dir.path(), b"Alice"));
+
+        // The WAL still holds the insert and cannot be read with another key
+        let wrong = StorageEngine::new(root, options(&key_b));
+        assert!(matches!(wrong, Err(OpenDBSError::Encryption(_))));
+
+        {
+            let mut engine = StorageEngine::new(root, options(&key_a)).unwrap();
+            assert_eq!(engine.find("app", "users", r#"{"name":"Alice"}"#, None, None).unwrap().len(), 1);
+            engine.rotate_key(&key_b).unwrap();
+            let keyring = engine.cipher.clone().unwrap();
+            for _ in 0..500 {
+                if keyring.rotations_running() == 0 {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(keyring.rotations_running(), 0);
+            engine.insert("app", "users", r#"{"name":"Bob"}"#, None).unwrap();
+            engine.checkpoint().unwrap();
+        }
+
+        // Everything was rewritten with the new key, so the old one is not needed
+        let mut engine = StorageEngine::new(root, options(&key_b)).unwrap();
+        assert_eq!(engine.find("app", "users", "{}", None, None).unwrap().len(), 2);
+
+        // A rack renamed while the rotation runs is skipped, not recreated
+        let renamed = Arc::clone(engine.databases.get("app").unwrap().racks.get("users").unwrap().value());
+        assert!(engine.rename_rack("app", "users", "members").unwrap());
+        renamed.reencrypt().unwrap();
+        assert!(!renamed.path.exists());
+        drop(renamed);
+        assert!(engine.rename_rack("app", "members", "users").unwrap());
+        drop(engine);
+
+        let engine = StorageEngine::new(root, options(&key_a)).unwrap();
+        let found = engine.find("app", "users", "{}", None, None);
+        assert!(matches!(found, Err(OpenDBSError::Encryption(_))));
+    }
+
+    #[test]
+    fn test_encrypted_fields() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let key = "33".repeat(32);
+        let options = format!(
+            r#"{{"encrypted_fields":{{"ssn":"deterministic","password_hash":"randomized"}},"indexes":["ssn"],"field_key":"{}"}}"#,
+            key
+        );
+        assert!(engine.create_rack("app", "users", Some(r#"{"encrypted_fields":{"ssn":"randomized"}}"#)).is_err());
+        engine.create_rack("app", "users", Some(&options)).unwrap();
+
+        let alice = r#"{"name":"Alice","ssn":"123-45-6789","password_hash":"secret-hash"}"#;
+        assert!(matches!(
+            engine.insert("app", "users", alice, None),
+            Err(OpenDBSError::Encryption(_))
+        ));
+        let id = engine.insert("app", "users", alice, Some(&key)).unwrap();
+        engine
+            .insert("app", "users", r#"{"name":"Bob","ssn":"000-00-0000","note":"$enc:hello"}"#, Some(&key))
+            .unwrap();
+        assert_eq!(engine.find("app", "users", "{}", Some(&key), None).unwrap().len(), 2);
+        assert!(engine.find("app", "users", "{}", Some(&"44".repeat(32)), None).is_err());
+
+        // Without the key only ciphertext is visible
+        let found = engine.find("app", "users", r#"{"name":"Alice"}"#, None, None).unwrap();
+        let stored: Document = serde_json::from_str(&found[0]).unwrap();
+        assert!(crypto::is_encrypted_value(&stored.data["ssn"]));
+
+        let found = engine.find("app", "users", r#"{"ssn":"123-45-6789"}"#, Some(&key), None).unwrap();
+        let plain: Document = serde_json::from_str(&found[0]).unwrap();
+        assert_eq!(plain.id, id);
+        assert_eq!(plain.data["password_hash"], "secret-hash");
+
+        // Deterministic fields can be looked up by the ciphertext of a value
+        let rack = engine.open_rack("app", "users").unwrap();
+        let cipher = rack.field_cipher(Some(&key)).unwrap().unwrap();
+        let sealed = cipher
+            .seal_value("ssn", &serde_json::json!("123-45-6789"), crypto::FieldEncryption::Deterministic)
+            .unwrap();
+        let ids = rack.index.search("ssn", sealed.as_str().unwrap()).unwrap();
+        assert!(ids.contains(&id));
+        assert!(rack.create_index("password_hash", &IndexOptions::default()).is_err());
+        drop(rack);
+
+        // Callers cannot write ciphertext of their own, even copied from a read
+        let updated = serde_json::json!({ "name": "Alice", "ssn": stored.data["ssn"] }).to_string();
+        assert!(matches!(
+            engine.update("app", "users", &id, &updated, None),
+            Err(OpenDBSError::Encryption(_))
+        ));
+        assert!(engine.update("app", "users", &id, r#"{"name":"Alice"}"#, None).unwrap());
+        let forged = r#"{"name":"Mallory","ssn":"$enc:d:00"}"#;
+        assert!(engine.insert("app", "users", forged, None).is_err());
+        engine.insert("app", "users", forged, Some(&key)).unwrap();
+        let found = engine.find("app", "users", r#"{"name":"Mallory"}"#, Some(&key), None).unwrap();
+        let mallory: Document = serde_json::from_str(&found[0]).unwrap();
+        assert_eq!(mallory.data["ssn"], "$enc:d:00");
+        engine.checkpoint().unwrap();
+        drop(engine);
+
+        let rack_path = dir.path().join("app").join("users");
+        for entry in fs::read_dir(&rack_path).unwrap() {
+            let contents = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!contents.windows(11).any(|w| w == b"123-45-6789"));
+        }
+    }
+
+    #[test]
+    fn test_id_strategies() {
+        let (dir, mut engine) = app_engine(EngineOptions::default());
+        let root = dir.path().to_str().unwrap();
+        engine.create_rack("app", "uuids", Some(r#"{"id_strategy":"uuid_v7"}"#)).unwrap();
+        engine.create_rack("app", "ulids", Some(r#"{"id_strategy":"ulid"}"#)).unwrap();
+        engine.create_rack("app", "keys", Some(r#"{"id_strategy":"caller"}"#)).unwrap();
+        engine.create_rack("app", "counted", None).unwrap();
+
+        let first = engine.insert("app", "uuids", r#"{"n":1}"#, None).unwrap();
+        let second = engine.insert("app", "uuids", r#"{"n":2}"#, None).unwrap();
+        assert_eq!(uuid::Uuid::parse_str(&first).unwrap().get_version_num(), 7);
+        assert!(first < second);
+        let ulid = engine.insert("app", "ulids", r#"{"n":1}"#, None).unwrap();
+        assert!(ulid::Ulid::from_string(&ulid).is_ok());
+
+        // Caller-supplied ids are required, unique and cannot change
+        assert!(matches!(
+            engine.insert("app", "keys", r#"{"n":1}"#, None),
+            Err(OpenDBSError::InvalidDocument(_))
+        ));
+        assert!(engine.insert("app", "keys", r#"{"_id":["a"]}"#, None).is_err());
+        assert_eq!(engine.insert("app", "keys", r#"{"_id":"alice","n":1}"#, None).unwrap(), "alice");
+        assert!(matches!(
+            engine.insert("app", "keys", r#"{"_id":"alice","n":2}"#, None),
+            Err(OpenDBSError::DuplicateId(_))
+        ));
+        assert!(engine.update("app", "keys", "alice", r#"{"_id":"alice","n":3}"#, None).unwrap());
+        assert!(engine.update("app", "keys", "alice", r#"{"_id":"bob"}"#, None).is_err());
+        let found = engine.find("app", "keys", r#"{"n":3}"#, None, None).unwrap();
+        let alice: Document = serde_json::from_str(&found[0]).unwrap();
+        assert_eq!(alice.id, "alice");
+        assert!(alice.data.get("_id").is_none());
+
+        // The largest numeric id does not overflow the counter
+        let max = u64::MAX.to_string();
+        let huge = serde_json::json!({ "_id": max }).to_string();
+        assert_eq!(engine.insert("app", "keys", &huge, None).unwrap(), max);
+
+        // Other racks keep `_id` as data and choose the id themselves
+        assert_eq!(engine.insert("app", "counted", r#"{"_id":7}"#, None).unwrap(), "1");
+        let found = engine.find("app", "counted", "{}", None, None).unwrap();
+        let counted: Document = serde_json::from_str(&found[0]).unwrap();
+        assert_eq!(counted.data["_id"], 7);
+        assert!(engine.update("app", "counted", "1", r#"{"_id":8}"#, None).unwrap());
+        assert_eq!(engine.insert("app", "counted", "{}", None).unwrap(), "2");
+        assert!(engine.delete("app", "counted", "2").unwrap());
+        engine.compact("app", "counted").unwrap();
+        engine.clear_rack("app", "uuids").unwrap();
+        drop(engine);
+
+        // The counter does not go back after compaction and a restart
+        let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.insert("app", "counted", "{}", None).unwrap(), "3");
+        assert_eq!(engine.find("app", "keys", "{}", None, None).unwrap().len(), 2);
+        let meta = engine.open_rack("app", "uuids").unwrap().meta();
+        assert_eq!(meta.id_strategy, IdStrategy::UuidV7);
+    }
+}
@@ -0,0 +1,499 @@
+//! Streaming export and import of rack documents as NDJSON, CSV or a JSON
+//! array.
+//!
+//! Each document becomes one record: its data object, plus `_id`,
+//! `_created_at` and `_updated_at` when ids and timestamps are kept.
+//!
+//! CSV flattens nested objects into dotted column names (`address.city`).
+//! Cells hold strings as-is and everything else (numbers, booleans, arrays,
+//! empty objects) as JSON. Strings that would read back as JSON are written
+//! JSON-quoted, and null fields are left empty, so importing an export gives
+//! back the same documents, except that null and missing fields both read
+//! back as missing.
+
+use crate::error::{OpenDBSError, Result};
+use crate::storage::Document;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+
+/// Record field holding the document id
+pub const ID_FIELD: &str = "_id";
+
+/// Record field holding the creation time (seconds since the epoch)
+pub const CREATED_AT_FIELD: &str = "_created_at";
+
+/// Record field holding the last update time (seconds since the epoch)
+pub const UPDATED_AT_FIELD: &str = "_updated_at";
+
+/// File format of an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line
+    Ndjson,
+    /// Header row plus one row per document
+    Csv,
+    /// A single JSON array of objects
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = OpenDBSError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ndjson" | "jsonl" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(OpenDBSError::Internal(format!("Unsupported format: {}", s))),
+        }
+    }
+}
+
+/// JSON options accepted by `export_rack` and `import_rack`
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct TransferOptions {
+    /// Export `_id`; on import, reuse `_id` instead of assigning a new id
+    pub keep_ids: bool,
+    /// Export `_created_at` / `_updated_at`; on import, reuse them
+    pub keep_timestamps: bool,
+}
+
+impl TransferOptions {
+    pub fn parse(options: Option<&str>) -> Result<Self> {
+        Ok(match options {
+            Some(json) => serde_json::from_str(json)?,
+            None => Self::default(),
+        })
+    }
+}
+
+/// A record that failed to import
+#[derive(Debug, Serialize, Clone)]
+pub struct ImportError {
+    /// Line of the file (for JSON arrays, the element number)
+    pub line: u64,
+    pub message: String,
+}
+
+/// Result of an import
+#[derive(Debug, Serialize, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub errors: Vec<ImportError>,
+}
+
+/// A record read back into document parts
+#[derive(Debug)]
+pub struct ImportedRecord {
+    pub id: Option<String>,
+    pub data: Value,
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+}
+
+/// The record written for a document
+pub fn to_record(doc: &Document, options: TransferOptions) -> Value {
+    let mut record = match &doc.data {
+        Value::Object(fields) => fields.clone(),
+        other => {
+            let mut fields = Map::new();
+            fields.insert("value".to_string(), other.clone());
+            fields
+        }
+    };
+    if options.keep_ids {
+        record.insert(ID_FIELD.to_string(), Value::String(doc.id.clone()));
+    }
+    if options.keep_timestamps {
+        record.insert(CREATED_AT_FIELD.to_string(), doc.created_at.into());
+        record.insert(UPDATED_AT_FIELD.to_string(), doc.updated_at.into());
+    }
+    Value::Object(record)
+}
+
+/// Split a record into document parts. Reserved fields are always removed
+/// from the data, and only used when the options say so.
+pub fn from_record(record: Value, options: TransferOptions) -> std::result::Result<ImportedRecord, String> {
+    let mut fields = match record {
+        Value::Object(fields) => fields,
+        _ => return Err("expected a JSON object".to_string()),
+    };
+
+    let id = match fields.remove(ID_FIELD) {
+        Some(Value::String(id)) if !id.is_empty() => Some(id),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        None | Some(Value::Null) => None,
+        Some(_) => return Err(format!("{} must be a string or a number", ID_FIELD)),
+    };
+    let mut timestamp = |field: &str| match fields.remove(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("{} must be a non-negative integer", field)),
+    };
+    let created_at = timestamp(CREATED_AT_FIELD)?;
+    let updated_at = timestamp(UPDATED_AT_FIELD)?;
+
+    Ok(ImportedRecord {
+        id: id.filter(|_| options.keep_ids),
+        data: Value::Object(fields),
+        created_at: created_at.filter(|_| options.keep_timestamps),
+        updated_at: updated_at.filter(|_| options.keep_timestamps),
+    })
+}
+
+/// CSV columns needed for `record`, added to `columns`
+pub fn collect_columns(record: &Value, columns: &mut BTreeSet<String>) {
+    let mut cells = Vec::new();
+    flatten("", record, &mut cells);
+    columns.extend(cells.into_iter().map(|(column, _)| column));
+}
+
+/// Writes records one at a time in the chosen format
+pub struct Exporter<W: Write> {
+    inner: ExporterInner<W>,
+    count: usize,
+}
+
+enum ExporterInner<W: Write> {
+    Ndjson(W),
+    Json(W),
+    Csv(Box<csv::Writer<W>>, Vec<String>),
+}
+
+impl<W: Write> Exporter<W> {
+    /// Start an export. CSV needs every column up front, see `collect_columns`.
+    pub fn new(mut out: W, format: Format, columns: BTreeSet<String>) -> Result<Self> {
+        let inner = match format {
+            Format::Ndjson => ExporterInner::Ndjson(out),
+            Format::Json => {
+                out.write_all(b"[")?;
+                ExporterInner::Json(out)
+            }
+            Format::Csv => {
+                let columns: Vec<String> = columns.into_iter().collect();
+                let mut writer = csv::Writer::from_writer(out);
+                if !columns.is_empty() {
+                    writer.write_record(&columns).map_err(csv_error)?;
+                }
+                ExporterInner::Csv(Box::new(writer), columns)
+            }
+        };
+        Ok(Self { inner, count: 0 })
+    }
+
+    pub fn write(&mut self, record: &Value) -> Result<()> {
+        match &mut self.inner {
+            ExporterInner::Ndjson(out) => {
+                serde_json::to_writer(&mut *out, record)?;
+                out.write_all(b"\n")?;
+            }
+            ExporterInner::Json(out) => {
+                out.write_all(if self.count == 0 { b"\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *out, record)?;
+            }
+            ExporterInner::Csv(writer, columns) => {
+                let mut cells = Vec::new();
+                flatten("", record, &mut cells);
+                let row = columns.iter().map(|column| {
+                    cells
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .map_or("", |(_, cell)| cell.as_str())
+                });
+                writer.write_record(row).map_err(csv_error)?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Finish the file and return the number of records written
+    pub fn finish(self) -> Result<usize> {
+        match self.inner {
+            ExporterInner::Ndjson(mut out) => out.flush()?,
+            ExporterInner::Json(mut out) => {
+                out.write_all(if self.count == 0 { b"]\n" } else { b"\n]\n" })?;
+                out.flush()?;
+            }
+            ExporterInner::Csv(mut writer, _) => writer.flush()?,
+        }
+        Ok(self.count)
+    }
+}
+
+/// Read records from `reader`, handing each one to `visit` with its line
+/// number. Records that cannot be parsed are passed on as errors; reading
+/// only stops early when the file cannot be resynchronized.
+pub fn read_records<R, F>(reader: R, format: Format, mut visit: F) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(u64, std::result::Result<Value, String>) -> Result<()>,
+{
+    match format {
+        Format::Ndjson => {
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_str(&line).map_err(|e| e.to_string());
+                visit(number as u64 + 1, record)?;
+            }
+            Ok(())
+        }
+        Format::Csv => read_csv(reader, visit),
+        Format::Json => read_json_array(reader, visit),
+    }
+}
+
+fn read_csv<R, F>(reader: R, mut visit: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(u64, std::result::Result<Value, String>) -> Result<()>,
+{
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers().map_err(csv_error)?.clone();
+
+    for row in reader.records() {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                if matches!(e.kind(), csv::ErrorKind::Io(_)) {
+                    return Err(csv_error(e));
+                }
+                visit(line, Err(e.to_string()))?;
+                continue;
+            }
+        };
+        let line = row.position().map_or(0, |position| position.line());
+        visit(line, unflatten(&headers, &row))?;
+    }
+    Ok(())
+}
+
+fn read_json_array<R, F>(reader: R, visit: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(u64, std::result::Result<Value, String>) -> Result<()>,
+{
+    /// Hands array elements to the callback as they are parsed
+    struct ElementVisitor<F> {
+        visit: F,
+        failure: Option<OpenDBSError>,
+    }
+
+    impl<'de, F> Visitor<'de> for &mut ElementVisitor<F>
+    where
+        F: FnMut(u64, std::result::Result<Value, String>) -> Result<()>,
+    {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a JSON array")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+            let mut number = 0;
+            while let Some(element) = seq.next_element::<Value>()? {
+                number += 1;
+                if let Err(e) = (self.visit)(number, Ok(element)) {
+                    self.failure = Some(e);
+                    return Err(de::Error::custom("import aborted"));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut visitor = ElementVisitor { visit, failure: None };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let parsed = deserializer.deserialize_seq(&mut visitor).and_then(|_| deserializer.end());
+
+    if let Some(failure) = visitor.failure {
+        return Err(failure);
+    }
+    if let Err(e) = parsed {
+        // A syntax error cannot be skipped over; report where it stopped
+        (visitor.visit)(e.line() as u64, Err(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Flatten `value` into `(column, cell)` pairs
+fn flatten(prefix: &str, value: &Value, cells: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (key, field) in fields {
+                let column = match prefix {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", prefix, key),
+                };
+                flatten(&column, field, cells);
+            }
+        }
+        Value::Null => {}
+        Value::String(s) => {
+            // Quote strings that would otherwise read back as something else
+            let cell = if s.is_empty() || serde_json::from_str::<Value>(s).is_ok() {
+                Value::String(s.clone()).to_string()
+            } else {
+                s.clone()
+            };
+            cells.push((prefix.to_string(), cell));
+        }
+        other => cells.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+/// Rebuild a nested object from a CSV row
+fn unflatten(headers: &csv::StringRecord, row: &csv::StringRecord) -> std::result::Result<Value, String> {
+    let mut record = Map::new();
+    for (column, cell) in headers.iter().zip(row.iter()) {
+        if cell.is_empty() {
+            continue;
+        }
+        let value = serde_json::from_str(cell).unwrap_or_else(|_| Value::String(cell.to_string()));
+
+        let mut path = column.split('.').peekable();
+        let mut target = &mut record;
+        while let Some(key) = path.next() {
+            if path.peek().is_none() {
+                if target.insert(key.to_string(), value).is_some() {
+                    return Err(format!("column {} conflicts with another column", column));
+                }
+                break;
+            }
+            let next = target
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            target = match next {
+                Value::Object(fields) => fields,
+                _ => return Err(format!("column {} conflicts with column {}", column, key)),
+            };
+        }
+    }
+    Ok(Value::Object(record))
+}
+
+fn csv_error(e: csv::Error) -> OpenDBSError {
+    OpenDBSError::IoError(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::storage::tests::app_engine;
+    use crate::storage::EngineOptions;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn roundtrip(format: Format, records: &[Value]) -> Vec<(u64, std::result::Result<Value, String>)> {
+        let mut columns = BTreeSet::new();
+        for record in records {
+            collect_columns(record, &mut columns);
+        }
+        let mut out = Vec::new();
+        let mut exporter = Exporter::new(&mut out, format, columns).unwrap();
+        for record in records {
+            exporter.write(record).unwrap();
+        }
+        assert_eq!(exporter.finish().unwrap(), records.len());
+
+        let mut read = Vec::new();
+        read_records(Cursor::new(out), format, |line, record| {
+            read.push((line, record));
+            Ok(())
+        })
+        .unwrap();
+        read
+    }
+
+    #[test]
+    fn test_formats_roundtrip() {
+        let records = vec![
+            json!({"name": "Alice", "address": {"city": "Oslo", "zip": "0150"}, "tags": ["a", "b"], "_id": "1"}),
+            json!({"name": "true", "age": 42, "empty": "", "meta": {}, "_id": "2"}),
+        ];
+
+        for format in [Format::Ndjson, Format::Csv, Format::Json] {
+            let read = roundtrip(format, &records);
+            let values: Vec<Value> = read.into_iter().map(|(_, record)| record.unwrap()).collect();
+            assert_eq!(values, records, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_import_reports_bad_lines() {
+        let ndjson = "{\"a\":1}\nnot json\n\n[1]\n{\"a\":2}\n";
+        let mut results = Vec::new();
+        read_records(Cursor::new(ndjson), Format::Ndjson, |line, record| {
+            results.push((line, record.and_then(|r| from_record(r, TransferOptions::default()))));
+            Ok(())
+        })
+        .unwrap();
+        let failed: Vec<u64> = results.iter().filter(|(_, r)| r.is_err()).map(|(line, _)| *line).collect();
+        assert_eq!(failed, vec![2, 4]);
+
+        let csv = "a.b,a\n1,2\n";
+        let mut errors = 0;
+        read_records(Cursor::new(csv), Format::Csv, |_, record| {
+            errors += record.is_err() as usize;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(errors, 1);
+
+        let record = from_record(json!({"_id": 7, "_created_at": 5, "x": 1}), TransferOptions::default()).unwrap();
+        assert_eq!(record.id, None);
+        assert_eq!(record.created_at, None);
+        assert_eq!(record.data, json!({"x": 1}));
+    }
+
+    #[test]
+    fn test_export_and_import_racks() {
+        let (_dir, mut engine) = app_engine(EngineOptions::default());
+        let files = tempfile::tempdir().unwrap();
+        engine.create_rack("app", "users", None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice","address":{"city":"Oslo"}}"#, None).unwrap();
+        engine.insert("app", "users", r#"{"name":"Bob","tags":["x"]}"#, None).unwrap();
+        engine.delete("app", "users", "1").unwrap();
+
+        let keep = Some(r#"{"keep_ids":true,"keep_timestamps":true}"#);
+        for format in ["ndjson", "csv", "json"] {
+            let path = files.path().join(format!("users.{}", format));
+            let path = path.to_str().unwrap();
+            assert_eq!(engine.export_rack("app", "users", path, format, keep).unwrap(), 1);
+
+            engine.create_rack("app", format, None).unwrap();
+            let report = engine.import_rack("app", format, path, format, keep).unwrap();
+            assert_eq!(report.imported, 1);
+            assert!(report.errors.is_empty());
+
+            let original = engine.find("app", "users", "{}", None, None).unwrap();
+            assert_eq!(engine.find("app", format, "{}", None, None).unwrap(), original);
+            assert_eq!(engine.insert("app", format, r#"{"name":"Carol"}"#, None).unwrap(), "3");
+        }
+
+        let path = files.path().join("bad.ndjson");
+        fs::write(&path, "{\"name\":\"Dave\",\"_id\":\"9\"}\n{oops\n").unwrap();
+        let report = engine
+            .import_rack("app", "users", path.to_str().unwrap(), "ndjson", None)
+            .unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 2);
+        // Without keep_ids a new id is assigned and `_id` is not stored
+        assert_eq!(engine.find("app", "users", r#"{"name":"Dave"}"#, None, None).unwrap().len(), 1);
+        assert!(!engine.find("app", "users", "{}", None, None).unwrap().iter().any(|doc| doc.contains("_id")));
+    }
+}
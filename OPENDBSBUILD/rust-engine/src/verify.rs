@@ -0,0 +1,283 @@
+use crate::durability::sync_dir;
+use crate::error::{OpenDBSError, Result};
+use crate::index::Index;
+use crate::quarantine::Quarantine;
+use crate::segment::{self, RecordKind};
+use crate::storage::{legacy_files, Document, Rack};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::atomic::Ordering;
+
+/// Kind of inconsistency found by `verify`
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A document or segment file cannot be parsed
+    UnreadableFile,
+    /// A segment record fails its checksum or cannot be decoded
+    DamagedSegment,
+    /// A legacy `<id>.dbs` file holds a document with a different id
+    IdMismatch,
+    /// A file superseded by compaction or left by an interrupted write
+    OrphanFile,
+    /// `next_id` is not above every numeric document id
+    NextIdTooLow,
+    /// The in-memory index does not match the documents
+    IndexMismatch,
+}
+
+/// A single inconsistency
+#[derive(Debug, Serialize, Clone)]
+pub struct VerifyIssue {
+    pub database: String,
+    pub rack: String,
+    pub kind: IssueKind,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+/// Result of checking one or more databases
+#[derive(Debug, Serialize, Default)]
+pub struct VerifyReport {
+    pub racks_checked: usize,
+    pub documents_checked: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+/// Check a rack's files and in-memory state, optionally fixing what is found
+pub fn verify_rack(
+    database: &str,
+    rack: &Rack,
+    repair: bool,
+    quarantine: &Quarantine,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let mut issues = Vec::new();
+    let mut issue = |kind: IssueKind, detail: String| {
+        issues.push(VerifyIssue {
+            database: database.to_string(),
+            rack: rack.name.clone(),
+            kind,
+            detail,
+            repaired: false,
+        });
+    };
+
+    let segments = segment::list_segments(&rack.path)?;
+    let base = segment::compacted_base(&segments);
+
+    // Segment files: every record must pass its checksum and decode
+    let mut damaged_segments = false;
+    for (position, (_, seg_path)) in segments.iter().enumerate() {
+        if base.is_some_and(|base| position < base) {
+            issue(
+                IssueKind::OrphanFile,
+                format!("{} is superseded by a compacted segment", seg_path.display()),
+            );
+            continue;
+        }
+
+        let mut undecodable = Vec::new();
+        let scan = segment::scan_segment(seg_path, |record, _| {
+            if record.kind == RecordKind::Put {
+                if let Err(e) = record.to_document(rack.cipher.as_deref()) {
+                    undecodable.push(format!("document {}: {}", record.id, e));
+                }
+            }
+            Ok(())
+        });
+
+        match scan {
+            Ok(scan) if !scan.is_complete() => {
+                damaged_segments = true;
+                issue(
+                    IssueKind::DamagedSegment,
+                    format!("{} is damaged after offset {}", seg_path.display(), scan.valid_len),
+                );
+            }
+            Ok(_) => {}
+            Err(OpenDBSError::Corruption(reason)) => {
+                damaged_segments = true;
+                issue(IssueKind::UnreadableFile, reason);
+            }
+            Err(e) => return Err(e),
+        }
+
+        for detail in undecodable {
+            damaged_segments = true;
+            issue(
+                IssueKind::DamagedSegment,
+                format!("{}: {}", seg_path.display(), detail),
+            );
+        }
+    }
+
+    // Legacy document files: must parse and be named after their id
+    let mut unreadable = Vec::new();
+    let mut mismatched = Vec::new();
+    let mut superseded = Vec::new();
+    for doc_path in legacy_files(&rack.path)? {
+        if base.is_some() {
+            issue(
+                IssueKind::OrphanFile,
+                format!("{} is superseded by a compacted segment", doc_path.display()),
+            );
+            superseded.push(doc_path);
+            continue;
+        }
+
+        let file = File::open(&doc_path)?;
+        match serde_json::from_reader::<_, Document>(BufReader::new(file)) {
+            Ok(document) => {
+                let stem = doc_path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                if stem != document.id {
+                    issue(
+                        IssueKind::IdMismatch,
+                        format!("{} holds document {}", doc_path.display(), document.id),
+                    );
+                    mismatched.push((doc_path, document.id));
+                }
+            }
+            Err(e) => {
+                issue(IssueKind::UnreadableFile, format!("{}: {}", doc_path.display(), e));
+                unreadable.push(doc_path);
+            }
+        }
+    }
+
+    // Leftovers of interrupted atomic writes
+    let mut temp_files = Vec::new();
+    for entry in fs::read_dir(&rack.path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("tmp") {
+            issue(
+                IssueKind::OrphanFile,
+                format!("{} was left by an interrupted write", path.display()),
+            );
+            temp_files.push(path);
+        }
+    }
+
+    // next_id must stay above every numeric id
+    let max_id = rack
+        .ids()
+        .iter()
+        .filter_map(|id| id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0);
+    let next_id = rack.next_id.load(Ordering::SeqCst);
+    let next_id_too_low = next_id <= max_id;
+    if next_id_too_low {
+        issue(
+            IssueKind::NextIdTooLow,
+            format!("next_id is {} but document {} exists", next_id, max_id),
+        );
+    }
+
+    // The index must match a fresh build from the documents
+    let meta = rack.meta();
+    let expected = Index::with_fields(meta.indexed_fields()).with_compound(meta.compound_indexes());
+    rack.for_each_document(|document| {
+        expected.index_document(&document.id, &document.data);
+        Ok(())
+    })?;
+    let index_mismatch = expected.entries() != rack.index.entries();
+    if index_mismatch {
+        issue(
+            IssueKind::IndexMismatch,
+            "index entries differ from the stored documents".to_string(),
+        );
+    }
+
+    report.racks_checked += 1;
+    report.documents_checked += rack.document_count();
+
+    if repair && !issues.is_empty() {
+        if index_mismatch {
+            rack.rebuild_index()?;
+        }
+
+        if next_id_too_low {
+            rack.next_id.fetch_max(max_id + 1, Ordering::SeqCst);
+        }
+
+        for doc_path in unreadable {
+            quarantine.move_file(database, &rack.name, &doc_path, "unreadable during verify");
+        }
+
+        // Persist the loaded version of mismatched documents before dropping the files
+        for (_, id) in &mismatched {
+            if let Some(doc) = rack.get(id)? {
+                rack.put(doc)?;
+            }
+        }
+        rack.sync()?;
+        for (doc_path, _) in mismatched {
+            fs::remove_file(doc_path)?;
+        }
+
+        for path in superseded.into_iter().chain(temp_files) {
+            fs::remove_file(path)?;
+        }
+        sync_dir(&rack.path)?;
+
+        // Rewriting from memory drops damaged and superseded segments
+        if damaged_segments || base.is_some_and(|base| base > 0) {
+            rack.compact()?;
+        }
+
+        for issue in &mut issues {
+            issue.repaired = true;
+        }
+    }
+
+    report.issues.extend(issues);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::META_FILE;
+    use crate::storage::{EngineOptions, StorageEngine};
+
+    #[test]
+    fn test_verify_detects_and_repairs_drift() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let rack_path = dir.path().join("app").join("users");
+        fs::create_dir_all(&rack_path).unwrap();
+        fs::write(rack_path.join(META_FILE), r#"{"version":1,"indexes":["name"]}"#).unwrap();
+        fs::write(
+            rack_path.join("3.dbs"),
+            r#"{"id":"9","data":{"name":"Misnamed"},"created_at":1,"updated_at":1}"#,
+        )
+        .unwrap();
+
+        let mut engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        engine.insert("app", "users", r#"{"name":"Alice"}"#, None).unwrap();
+        {
+            let db = engine.databases.get("app").unwrap();
+            let rack = db.racks.get("users").unwrap();
+            rack.next_id.store(2, Ordering::SeqCst);
+            rack.index.clear();
+        }
+
+        let report = engine.verify(Some("app"), false).unwrap();
+        let kinds: Vec<_> = report.issues.iter().map(|issue| issue.kind).collect();
+        assert!(kinds.contains(&IssueKind::IdMismatch));
+        assert!(kinds.contains(&IssueKind::NextIdTooLow));
+        assert!(kinds.contains(&IssueKind::IndexMismatch));
+        assert!(report.issues.iter().all(|issue| !issue.repaired));
+
+        let report = engine.verify(None, true).unwrap();
+        assert!(report.issues.iter().all(|issue| issue.repaired));
+        assert!(engine.verify(None, false).unwrap().issues.is_empty());
+        assert!(!rack_path.join("3.dbs").exists());
+        drop(engine);
+
+        let engine = StorageEngine::new(root, EngineOptions::default()).unwrap();
+        assert_eq!(engine.find("app", "users", r#"{"name":"Misnamed"}"#, None, None).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::crypto::{self, Keyring};
+use crate::durability::Durability;
+use crate::error::{OpenDBSError, Result};
+use crate::segment::read_full;
+use crate::storage::Document;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// File name of the write-ahead log inside the engine root
+pub const WAL_FILE: &str = "opendbs.wal";
+
+/// Size of a record frame header: payload length (u32) + CRC32 (u32)
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Context encrypted frame payloads are bound to
+const FRAME_AAD: &[u8] = b"opendbs-log-frame";
+
+/// A single logged mutation
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WalOp {
+    Insert { document: Document },
+    Update { document: Document },
+    Delete { id: String },
+}
+
+/// A mutation together with the rack it targets
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WalRecord {
+    pub database: String,
+    pub rack: String,
+    #[serde(flatten)]
+    pub op: WalOp,
+}
+
+/// Append-only log of mutations that have not been checkpointed yet.
+///
+/// Every record is framed as `[len: u32][crc32: u32][json payload]` so that a
+/// torn tail left by a crash is detected and discarded on the next open.
+/// With encryption enabled the payload is sealed with the keyring.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: Mutex<File>,
+    cipher: Option<Arc<Keyring>>,
+    pending: AtomicUsize,
+    durability: Durability,
+    /// Records were appended since the last fsync
+    unsynced: AtomicBool,
+}
+
+impl WriteAheadLog {
+    /// Open (or create) the log in `root` and return the records it still holds
+    pub fn open(
+        root: &Path,
+        durability: Durability,
+        cipher: Option<Arc<Keyring>>,
+    ) -> Result<(Self, Vec<WalRecord>)> {
+        let path = root.join(WAL_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let (records, valid_len) = read_frames::<WalRecord>(&mut file, cipher.as_deref())?;
+
+        // Drop a torn tail so new records are appended after the last good one
+        if valid_len < file.metadata()?.len() {
+            tracing::warn!(
+                "Discarding torn tail of write-ahead log {} at offset {}",
+                path.display(),
+                valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let wal = Self {
+            file: Mutex::new(file),
+            cipher,
+            pending: AtomicUsize::new(records.len()),
+            durability,
+            unsynced: AtomicBool::new(false),
+        };
+
+        Ok((wal, records))
+    }
+
+    /// Records in the log of `root` without opening it for writing or
+    /// repairing it, for read-only engines
+    pub fn read_records(root: &Path, cipher: Option<&Keyring>) -> Result<Vec<WalRecord>> {
+        let path = root.join(WAL_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let (records, _) = read_frames::<WalRecord>(&mut File::open(path)?, cipher)?;
+        Ok(records)
+    }
+
+    /// Append a record. With `Durability::Always` this returns only once the
+    /// record is on stable storage.
+    pub fn append(&self, record: &WalRecord) -> Result<()> {
+        let frame = encode_frame(record, self.cipher.as_deref())?;
+
+        let mut file = self.file.lock();
+        file.write_all(&frame)?;
+        match self.durability {
+            Durability::Always => file.sync_data()?,
+            Durability::BatchedMs(_) => self.unsynced.store(true, Ordering::SeqCst),
+            Durability::Never => {}
+        }
+
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Flush records appended since the last fsync
+    pub fn sync(&self) -> Result<()> {
+        if self.unsynced.swap(false, Ordering::SeqCst) {
+            self.file.lock().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Number of records written since the last checkpoint
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Discard all records. Callers must have made their effects durable first.
+    pub fn truncate(&self) -> Result<()> {
+        let mut file = self.file.lock();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        if self.durability.syncs() {
+            file.sync_all()?;
+        }
+
+        self.pending.store(0, Ordering::SeqCst);
+        self.unsynced.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Frame a record as `[len: u32][crc32: u32][json payload]`, sealing the
+/// payload when a cipher is given
+pub(crate) fn encode_frame<T: Serialize>(record: &T, cipher: Option<&Keyring>) -> Result<Vec<u8>> {
+    let mut payload = serde_json::to_vec(record)?;
+    if let Some(cipher) = cipher {
+        payload = cipher.seal(&payload, FRAME_AAD)?;
+    }
+    let len = u32::try_from(payload.len())
+        .map_err(|_| OpenDBSError::Internal("Log record too large".into()))?;
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Read every intact framed record, returning them with the length of the
+/// valid prefix. A frame that passes its CRC but fails decryption is an
+/// error rather than a torn tail.
+pub(crate) fn read_frames<T: DeserializeOwned>(file: &mut File, cipher: Option<&Keyring>) -> Result<(Vec<T>, u64)> {
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut records = Vec::new();
+    let mut valid_len = 0u64;
+
+    loop {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        if !read_full(&mut reader, &mut header)? {
+            break;
+        }
+
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        // A length running past the end of the file is a torn or corrupt header
+        if valid_len + (FRAME_HEADER_LEN + len) as u64 > file_len {
+            break;
+        }
+        let mut payload = vec![0u8; len];
+        if !read_full(&mut reader, &mut payload)? || crc32fast::hash(&payload) != crc {
+            break;
+        }
+
+        if crypto::is_sealed(&payload) {
+            let cipher = cipher.ok_or_else(|| {
+                OpenDBSError::Encryption("Log records are encrypted but no key is configured".into())
+            })?;
+            payload = cipher.open(&payload, FRAME_AAD)?;
+        }
+
+        match serde_json::from_slice::<T>(&payload) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+
+        valid_len += (FRAME_HEADER_LEN + len) as u64;
+    }
+
+    Ok((records, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record(id: &str) -> WalRecord {
+        WalRecord {
+            database: "db".into(),
+            rack: "users".into(),
+            op: WalOp::Insert {
+                document: Document {
+                    id: id.into(),
+                    data: json!({ "name": "Alice" }),
+                    created_at: 1,
+                    updated_at: 1,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_torn_tail_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let (wal, records) = WriteAheadLog::open(dir.path(), Durability::Always, None).unwrap();
+            assert!(records.is_empty());
+            wal.append(&record("1")).unwrap();
+            wal.append(&record("2")).unwrap();
+        }
+
+        // Simulate a crash in the middle of writing a third record
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL_FILE))
+            .unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let (wal, records) = WriteAheadLog::open(dir.path(), Durability::Always, None).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(wal.pending(), 2);
+
+        wal.append(&record("3")).unwrap();
+        drop(wal);
+
+        let (wal, records) = WriteAheadLog::open(dir.path(), Durability::Always, None).unwrap();
+        assert_eq!(records.len(), 3);
+        drop(wal);
+
+        // A corrupt length is not trusted to size a buffer
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL_FILE))
+            .unwrap();
+        file.write_all(&[0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]).unwrap();
+        drop(file);
+
+        let (wal, records) = WriteAheadLog::open(dir.path(), Durability::Always, None).unwrap();
+        assert_eq!(records.len(), 3);
+
+        wal.truncate().unwrap();
+        drop(wal);
+
+        let (_, records) = WriteAheadLog::open(dir.path(), Durability::Always, None).unwrap();
+        assert!(records.is_empty());
+    }
+}